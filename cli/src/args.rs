@@ -63,4 +63,27 @@ pub enum SubCommand {
         #[structopt(short, long, help = "Language to syntax highlight as")]
         language: Option<String>,
     },
+
+    #[structopt(about = "Convert the qcow, including its backing chain, to a sparse raw image")]
+    Convert {
+        #[structopt(help = "Path to write the raw image to")]
+        output: PathBuf,
+    },
+
+    #[structopt(about = "Rewrite the qcow in place so it contains no unused space")]
+    Compact,
+
+    #[structopt(
+        about = "Discard clusters of blocks which ext4 filesystems in the qcow consider free"
+    )]
+    Sparsify {
+        #[structopt(long, help = "Write zeroes over free blocks instead of discarding them")]
+        zero: bool,
+    },
+
+    #[structopt(about = "Display where metadata and guest data are laid out within the qcow file")]
+    Layout {
+        #[structopt(long, help = "Output the layout as JSON")]
+        json: bool,
+    },
 }
@@ -3,7 +3,13 @@ pub use args::{Args, SubCommand};
 
 mod output;
 pub use output::ReadAtAdapter;
-pub use output::{output_file, output_info, output_partitions, output_tree, FileCfg, TreeLimits};
+pub use output::{
+    output_compact, output_convert, output_file, output_info, output_layout, output_partitions,
+    output_sparsify, output_tree, FileCfg, TreeLimits,
+};
+
+mod sparsify;
+pub use sparsify::{sparsify, Sparsified};
 
 pub use {bootsector, ext4, gpt_partition_type, humansize, positioned_io, qcow};
 
@@ -11,14 +17,19 @@ use std::fs::File;
 use std::io::{BufReader, Seek, SeekFrom};
 
 pub fn main(args: Args) {
-    let mut file = BufReader::new(File::open(&args.qcow).unwrap());
-    let qcow = qcow::load(&mut file)
-        .expect("Failed to parse qcow")
-        .unwrap_qcow2();
-    let mut reader = qcow.reader(&mut file);
+    macro_rules! open_qcow {
+        ($file:ident, $qcow:ident) => {
+            let mut $file = BufReader::new(File::open(&args.qcow).unwrap());
+            let $qcow = qcow::load(&mut $file)
+                .expect("Failed to parse qcow")
+                .unwrap_qcow2();
+        };
+    }
 
     macro_rules! get_superblock {
         ($superblock:ident) => {
+            open_qcow!(file, qcow);
+            let mut reader = qcow.reader(&mut file);
             let partitons = bootsector::list_partitions(&mut reader, &Default::default()).unwrap();
             reader.seek(SeekFrom::Start(0)).unwrap();
 
@@ -40,8 +51,14 @@ pub fn main(args: Args) {
     }
 
     match args.command {
-        SubCommand::Info => output_info(&qcow),
-        SubCommand::Partitions => output_partitions(&mut reader),
+        SubCommand::Info => {
+            open_qcow!(file, qcow);
+            output_info(&qcow)
+        }
+        SubCommand::Partitions => {
+            open_qcow!(file, qcow);
+            output_partitions(&mut qcow.reader(&mut file))
+        }
         SubCommand::Tree {
             dir,
             file_limit,
@@ -84,5 +101,9 @@ pub fn main(args: Args) {
                 },
             )
         }
+        SubCommand::Convert { output } => output_convert(&args.qcow, &output),
+        SubCommand::Compact => output_compact(&args.qcow),
+        SubCommand::Sparsify { zero } => output_sparsify(&args.qcow, zero),
+        SubCommand::Layout { json } => output_layout(&args.qcow, json),
     }
 }
@@ -0,0 +1,16 @@
+use super::*;
+
+use std::path::Path;
+
+pub fn output_compact(qcow: &Path) {
+    let compaction = qcow::compact(qcow).expect("Failed to compact qcow");
+    let size = |bytes: u64| bytes.file_size(opts::BINARY).unwrap_or_else(|x| x);
+
+    println!(
+        "Reclaimed {} from {} ({} -> {})",
+        size(compaction.reclaimed()).bold(),
+        qcow.display(),
+        size(compaction.old_len),
+        size(compaction.new_len),
+    );
+}
@@ -0,0 +1,13 @@
+use super::*;
+
+use std::path::Path;
+
+pub fn output_convert(qcow: &Path, output: &Path) {
+    let written = qcow::convert_to_raw(qcow, output).expect("Failed to convert qcow");
+
+    println!(
+        "Wrote {} of data to {}",
+        written.file_size(opts::BINARY).unwrap_or_else(|x| x).bold(),
+        output.display()
+    );
+}
@@ -18,6 +18,78 @@ struct SnapshotTableEntry<'a> {
     time: NaiveDateTime,
 }
 
+#[derive(Tabled)]
+struct ChainTableEntry {
+    #[header("Layer")]
+    layer: usize,
+
+    #[header("Path")]
+    path: String,
+
+    #[header("Format")]
+    format: String,
+
+    #[header("Size")]
+    size: String,
+
+    #[header("Cluster Size")]
+    cluster_size: String,
+
+    #[header("Present")]
+    present: &'static str,
+}
+
+fn chain_table(qcow: &qcow::Qcow2) -> Option<String> {
+    let chain = qcow.backing_chain();
+    if chain.len() <= 1 {
+        return None;
+    }
+
+    let table: Vec<_> = chain
+        .iter()
+        .enumerate()
+        .map(|(i, layer)| ChainTableEntry {
+            layer: i,
+            path: layer.path.clone().unwrap_or_else(|| "(this image)".into()),
+            format: match (&layer.format, &layer.declared_format) {
+                (Some(format), Some(declared)) if format != declared => {
+                    format!("{} (declared {})", format, declared)
+                }
+                (Some(format), _) => format.to_string(),
+                (None, Some(declared)) => declared.to_string(),
+                (None, None) => "Unknown".into(),
+            },
+            size: layer
+                .virtual_size
+                .map(|size| size.file_size(opts::BINARY).unwrap_or_else(|x| x))
+                .unwrap_or_else(|| "-".into()),
+            cluster_size: layer
+                .cluster_size
+                .map(|size| format!("0x{:x}", size))
+                .unwrap_or_else(|| "-".into()),
+            present: match (layer.present, &layer.error) {
+                (true, None) => "Yes",
+                (true, Some(_)) => "Unreadable",
+                (false, _) => "No",
+            },
+        })
+        .collect();
+
+    Some(
+        Table::new(&table)
+            .with(Header("Backing Chain"))
+            .with(Modify::new(Head).with(Alignment::center_horizontal()))
+            .with(Modify::new(Row(..=1)).with(Format(|text| text.bold().to_string())))
+            .with(Style::pseudo())
+            .with(
+                Modify::new(Row(2..))
+                    .with(Alignment::left())
+                    .with(Indent::new(1, 1, 0, 0)),
+            )
+            .to_string(),
+    )
+}
+
 pub fn output_info(qcow: &qcow::Qcow2) {
     macro_rules! bold {
         () => { Format(|text| text.bold().to_string()) }
@@ -46,22 +118,27 @@ pub fn output_info(qcow: &qcow::Qcow2) {
     .with(Modify::new(Full).with(Alignment::left()))
     .with(Disable::Row(..1));
 
+    let chain_table = chain_table(qcow);
+
     if qcow.snapshots.is_empty() {
+        let mut sections = vec![
+            qcow_table.to_string(),
+            Table::new(["No snapshots present"])
+                .with(Header("QCOW Snapshots"))
+                .with(Disable::Row(1..=1))
+                .with(Style::pseudo())
+                .with(
+                    Modify::new(Head)
+                        .with(Alignment::center_horizontal())
+                        .with(bold!())
+                )
+                .to_string()
+        ];
+        sections.extend(chain_table);
+
         println!(
             "{}",
-            Table::new([
-                qcow_table.to_string(),
-                Table::new(["No snapshots present"])
-                    .with(Header("QCOW Snapshots"))
-                    .with(Disable::Row(1..=1))
-                    .with(Style::pseudo())
-                    .with(
-                        Modify::new(Head)
-                            .with(Alignment::center_horizontal())
-                            .with(bold!())
-                    )
-                    .to_string()
-            ])
+            Table::new(sections)
             .with(Disable::Row(0..=0))
             .with(Modify::new(Full).with(Indent::new(3, 3, 1, 0,)))
             .with(Style::noborder())
@@ -95,9 +172,12 @@ pub fn output_info(qcow: &qcow::Qcow2) {
                     .with(Indent::new(1, 1, 0, 0)),
             );
 
+        let mut sections = vec![qcow_table.to_string(), table.to_string()];
+        sections.extend(chain_table);
+
         println!(
             "\n{}",
-            Table::new([qcow_table.to_string(), table.to_string()])
+            Table::new(sections)
                 .with(Disable::Row(0..=0))
                 .with(Modify::new(Full).with(Indent::new(3, 3, 0, 0,)))
                 .with(Style::noborder())
@@ -0,0 +1,136 @@
+use super::*;
+
+use qcow::{HostRegion, HostRegionKind};
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+#[derive(Tabled)]
+struct TableEntry {
+    #[header("Start")]
+    start: String,
+
+    #[header("End")]
+    end: String,
+
+    #[header("Size")]
+    size: String,
+
+    #[header("Contents")]
+    kind: String,
+
+    #[header("Problems")]
+    problems: String,
+}
+
+pub fn output_layout(qcow: &Path, json: bool) {
+    let mut file = BufReader::new(File::open(qcow).unwrap());
+    let file_len = file.seek(SeekFrom::End(0)).unwrap();
+    let image = qcow::load(&mut file)
+        .expect("Failed to parse qcow")
+        .unwrap_qcow2();
+    let layout = image
+        .host_layout(&mut file)
+        .expect("Failed to read qcow metadata");
+
+    if json {
+        print_json(&layout, file_len);
+        return;
+    }
+
+    let mut prev_end = 0;
+    let table: Vec<_> = layout
+        .iter()
+        .map(|region| {
+            let mut problems = Vec::new();
+            if region.range.start < prev_end {
+                problems.push("overlaps previous region");
+            }
+            if region.range.end > file_len {
+                problems.push("past end of file");
+            }
+            prev_end = prev_end.max(region.range.end);
+
+            TableEntry {
+                start: format!("0x{:x}", region.range.start),
+                end: format!("0x{:x}", region.range.end),
+                size: (region.range.end - region.range.start)
+                    .file_size(opts::BINARY)
+                    .unwrap_or_else(|x| x),
+                kind: match region.kind {
+                    HostRegionKind::SnapshotL1Table(index) => {
+                        format!("L1 table (snapshot {})", index)
+                    }
+                    kind => describe(kind).to_owned(),
+                },
+                problems: problems.join(", "),
+            }
+        })
+        .collect();
+
+    let table = Table::new(&table)
+        .with(Header(format!(
+            "QCOW Layout ({})",
+            file_len.file_size(opts::BINARY).unwrap_or_else(|x| x)
+        )))
+        .with(Modify::new(Head).with(Alignment::center_horizontal()))
+        .with(Modify::new(Row(..=1)).with(Format(|text| text.bold().to_string())))
+        .with(Style::pseudo())
+        .with(
+            Modify::new(Row(2..))
+                .with(Alignment::left())
+                .with(Indent::new(1, 1, 0, 0)),
+        );
+
+    println!(
+        "\n{}",
+        Table::new([table.to_string()])
+            .with(Disable::Row(0..=0))
+            .with(Modify::new(Full).with(Indent::new(3, 3, 1, 0,)))
+            .with(Style::noborder())
+    );
+}
+
+fn describe(kind: HostRegionKind) -> &'static str {
+    match kind {
+        HostRegionKind::Header => "Header",
+        HostRegionKind::HeaderExtensions => "Header extensions",
+        HostRegionKind::BackingFileName => "Backing file name",
+        HostRegionKind::L1Table | HostRegionKind::SnapshotL1Table(_) => "L1 table",
+        HostRegionKind::L2Table => "L2 tables",
+        HostRegionKind::RefcountTable => "Refcount table",
+        HostRegionKind::RefcountBlock => "Refcount blocks",
+        HostRegionKind::SnapshotTable => "Snapshot table",
+        HostRegionKind::BitmapDirectory => "Bitmap directory",
+        HostRegionKind::Data => "Data",
+        HostRegionKind::CompressedData => "Compressed data",
+    }
+}
+
+fn print_json(layout: &[HostRegion], file_len: u64) {
+    let regions: Vec<String> = layout
+        .iter()
+        .map(|region| {
+            let snapshot = match region.kind {
+                HostRegionKind::SnapshotL1Table(index) => format!(", \"snapshot\": {}", index),
+                _ => String::new(),
+            };
+
+            format!(
+                "    {{\"start\": {}, \"end\": {}, \"kind\": \"{}\"{}}}",
+                region.range.start,
+                region.range.end,
+                region.kind.name(),
+                snapshot,
+            )
+        })
+        .collect();
+
+    println!("{{");
+    println!("  \"file_len\": {},", file_len);
+    println!("  \"regions\": [");
+    println!("{}", regions.join(",\n"));
+    println!("  ]");
+    println!("}}");
+}
@@ -2,12 +2,20 @@ mod tree;
 mod file;
 mod info;
 mod partitions;
+mod convert;
+mod compact;
+mod sparsify;
+mod layout;
 
 pub use {
     info::output_info,
     tree::{output_tree, TreeLimits},
     file::{output_file, FileCfg},
     partitions::output_partitions,
+    convert::output_convert,
+    compact::output_compact,
+    sparsify::output_sparsify,
+    layout::output_layout,
 };
 
 use std::io::{Read, Seek, SeekFrom};
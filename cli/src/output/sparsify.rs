@@ -0,0 +1,23 @@
+use super::*;
+
+use std::path::Path;
+
+pub fn output_sparsify(qcow: &Path, zero: bool) {
+    let sparsified = crate::sparsify(qcow, zero).expect("Failed to sparsify qcow");
+    for (partition, err) in &sparsified.skipped {
+        eprintln!("Skipping partition {}: {}", partition, err);
+    }
+
+    println!(
+        "{} {} of free space in {} ext4 filesystem{} of {}",
+        if zero { "Zeroed" } else { "Discarded" },
+        sparsified
+            .free_bytes
+            .file_size(opts::BINARY)
+            .unwrap_or_else(|x| x)
+            .bold(),
+        sparsified.filesystems,
+        if sparsified.filesystems == 1 { "" } else { "s" },
+        qcow.display(),
+    );
+}
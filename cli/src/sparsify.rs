@@ -0,0 +1,391 @@
+//! Discarding the blocks which ext4 filesystems within a qcow consider free.
+use crate::ReadAtAdapter;
+
+use binread::{derive_binread, BinReaderExt};
+use positioned_io::ReadAt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Cursor, SeekFrom};
+use std::ops::Range;
+use std::path::Path;
+
+/// Magic number identifying an ext4 superblock
+const EXT4_SUPER_MAGIC: u16 = 0xef53;
+
+/// Offset of the superblock within the filesystem
+const SUPERBLOCK_OFFSET: u64 = 1024;
+
+/// Filesystem state flag set while the filesystem is cleanly unmounted
+const EXT4_VALID_FS: u16 = 0x1;
+
+/// Incompatible feature flag set while the journal needs to be replayed
+const EXT4_FEATURE_INCOMPAT_RECOVER: u32 = 0x4;
+
+/// Incompatible feature flag for 64-bit block numbers and larger group descriptors
+const EXT4_FEATURE_INCOMPAT_64BIT: u32 = 0x80;
+
+/// Size of a group descriptor when the 64-bit feature is not in use
+const EXT4_MIN_DESC_SIZE: u16 = 32;
+
+/// Size of a group descriptor including the high halves of its fields
+const EXT4_MIN_DESC_SIZE_64BIT: u16 = 64;
+
+/// Block group flag set when the block bitmap of the group has never been initialized
+const EXT4_BG_BLOCK_UNINIT: u16 = 0x2;
+
+/// Outcome of sparsifying a qcow, as returned by [`sparsify`]
+#[derive(Debug, Default)]
+pub struct Sparsified {
+    /// Number of bytes of the guest which were discarded (or zeroed)
+    pub free_bytes: u64,
+
+    /// Number of ext4 filesystems whose free blocks were discarded (or zeroed)
+    pub filesystems: usize,
+
+    /// Partitions containing an ext4 filesystem which was skipped, along with the reason it
+    /// was skipped
+    pub skipped: Vec<(usize, io::Error)>,
+}
+
+/// Discard every cluster of the qcow at `path` which only contains blocks considered free by
+/// the ext4 filesystems on its partitions, or write zeroes over them if `zero` is set.
+///
+/// Filesystems which were not cleanly unmounted are skipped, as their block bitmaps can't be
+/// trusted until the journal has been replayed.
+pub fn sparsify(path: &Path, zero: bool) -> Result<Sparsified, qcow::Error> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .map_err(qcow::Error::FileNotFound)?;
+    let mut qcow = qcow::load(&mut BufReader::new(&mut file))?.unwrap_qcow2();
+
+    let mut sparsified = Sparsified::default();
+    let free_ranges = {
+        let mut reader = BufReader::new(File::open(path).map_err(qcow::Error::FileNotFound)?);
+        let mut reader = qcow.reader(&mut reader);
+        find_free_ranges(&mut reader, &mut sparsified)?
+    };
+
+    let mut writer = qcow.writer(&mut file)?;
+    for range in free_ranges {
+        let len = range.end - range.start;
+        if zero {
+            writer.write_zeroes(range.start, len)?;
+        } else {
+            writer.discard(range.start, len)?;
+        }
+
+        sparsified.free_bytes += len;
+    }
+    writer.sync()?;
+
+    Ok(sparsified)
+}
+
+/// Find the guest byte ranges of every block considered free by an ext4 filesystem within the
+/// guest, recording the filesystems found and skipped in `sparsified`
+fn find_free_ranges<R>(
+    reader: &mut R,
+    sparsified: &mut Sparsified,
+) -> Result<Vec<Range<u64>>, qcow::Error>
+where
+    R: io::Read + io::Seek,
+{
+    let partitions = bootsector::list_partitions(&mut *reader, &Default::default())?;
+
+    let mut ranges = Vec::new();
+    for partition in &partitions {
+        let partition_reader = bootsector::open_partition(&mut *reader, partition)?;
+        let mut fs = ReadAtAdapter::new(partition_reader);
+
+        // skip partitions which don't contain an ext4 filesystem
+        let options = ext4::Options {
+            checksums: ext4::Checksums::Enabled,
+        };
+        if ext4::SuperBlock::new_with_options(&mut fs, &options).is_err() {
+            continue;
+        }
+
+        match free_blocks(&fs) {
+            Ok(free) => {
+                let start = partition.first_byte;
+                ranges.extend(
+                    free.into_iter()
+                        .map(|range| start + range.start..start + range.end),
+                );
+                sparsified.filesystems += 1;
+            }
+            Err(err) => sparsified.skipped.push((partition.id, err)),
+        }
+    }
+
+    Ok(ranges)
+}
+
+/// Read the block bitmaps of an ext4 filesystem to find the byte ranges of the blocks it
+/// considers free. Block groups whose bitmap was never initialized are skipped, as their blocks
+/// have never been written to. With bigalloc, each bit of a bitmap covers a whole cluster of
+/// blocks.
+fn free_blocks(fs: &impl ReadAt) -> io::Result<Vec<Range<u64>>> {
+    let mut bytes = [0; 1024];
+    fs.read_exact_at(SUPERBLOCK_OFFSET, &mut bytes)?;
+    let superblock: SuperBlock = Cursor::new(&bytes[..])
+        .read_le()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+
+    // the bitmaps can't be trusted until the journal has been replayed
+    if superblock.state & EXT4_VALID_FS == 0
+        || superblock.feature_incompat & EXT4_FEATURE_INCOMPAT_RECOVER != 0
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "filesystem was not cleanly unmounted",
+        ));
+    }
+
+    // block and cluster sizes are limited to 64 KiB and 1 GiB respectively
+    if superblock.log_block_size > 6 || superblock.log_cluster_size > 20 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "superblock describes an invalid block or cluster size",
+        ));
+    }
+
+    let block_size = superblock.block_size();
+    let first_data_block = superblock.first_data_block as u64;
+    let blocks_count = superblock.blocks_count();
+    let desc_size = superblock.desc_size();
+
+    if superblock.log_cluster_size < superblock.log_block_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "cluster size is smaller than the block size",
+        ));
+    }
+    let cluster_blocks = 1u64 << (superblock.log_cluster_size - superblock.log_block_size);
+    let clusters_per_group = superblock.clusters_per_group as u64;
+    let blocks_per_group = clusters_per_group * cluster_blocks;
+
+    // the bitmap of each group must fit in a single block
+    if clusters_per_group == 0
+        || clusters_per_group > block_size * 8
+        || blocks_count <= first_data_block
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "superblock describes an invalid block group layout",
+        ));
+    }
+
+    // the group descriptor table starts in the block following the superblock, which isn't
+    // necessarily the first data block with bigalloc
+    let group_count = (blocks_count - first_data_block).div_ceil(blocks_per_group);
+    let mut descriptors = vec![0; group_count as usize * desc_size as usize];
+    let descriptors_block = (SUPERBLOCK_OFFSET / block_size) + 1;
+    fs.read_exact_at(descriptors_block * block_size, &mut descriptors)?;
+
+    let mut ranges: Vec<Range<u64>> = Vec::new();
+    let mut bitmap = vec![0; block_size as usize];
+    for (group, descriptor) in descriptors.chunks_exact(desc_size as usize).enumerate() {
+        let descriptor: GroupDescriptor = Cursor::new(descriptor)
+            .read_le_args((desc_size,))
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+        if descriptor.flags & EXT4_BG_BLOCK_UNINIT != 0 {
+            continue;
+        }
+
+        fs.read_exact_at(descriptor.block_bitmap() * block_size, &mut bitmap)?;
+
+        let first_block = first_data_block + (group as u64 * blocks_per_group);
+        let blocks = u64::min(blocks_per_group, blocks_count - first_block);
+        for i in 0..blocks.div_ceil(cluster_blocks) {
+            if bitmap[(i / 8) as usize] & (1 << (i % 8)) != 0 {
+                continue;
+            }
+
+            let start = (first_block + (i * cluster_blocks)) * block_size;
+            let end = (first_block + u64::min((i + 1) * cluster_blocks, blocks)) * block_size;
+            match ranges.last_mut() {
+                Some(last) if last.end == start => last.end = end,
+                _ => ranges.push(start..end),
+            }
+        }
+    }
+
+    Ok(ranges)
+}
+
+/// The fields of an ext4 superblock needed to locate its block bitmaps
+#[derive_binread]
+#[br(little)]
+struct SuperBlock {
+    #[br(temp)]
+    inodes_count: u32,
+
+    blocks_count_lo: u32,
+
+    #[br(temp)]
+    r_blocks_count_lo: u32,
+
+    #[br(temp)]
+    free_blocks_count_lo: u32,
+
+    #[br(temp)]
+    free_inodes_count: u32,
+
+    /// First block of the first block group, which is 1 for filesystems with 1 KiB blocks
+    first_data_block: u32,
+
+    /// Block size is 1024 << log_block_size
+    log_block_size: u32,
+
+    /// Cluster size is 1024 << log_cluster_size, which is larger than the block size with
+    /// bigalloc
+    log_cluster_size: u32,
+
+    #[br(temp)]
+    blocks_per_group: u32,
+
+    /// Number of clusters in each block group, and so the number of bits in its block bitmap
+    clusters_per_group: u32,
+
+    #[br(temp)]
+    inodes_per_group: u32,
+
+    #[br(temp)]
+    mtime: u32,
+
+    #[br(temp)]
+    wtime: u32,
+
+    #[br(temp)]
+    mnt_count: u16,
+
+    #[br(temp)]
+    max_mnt_count: u16,
+
+    #[br(temp, assert(magic == EXT4_SUPER_MAGIC))]
+    magic: u16,
+
+    state: u16,
+
+    #[br(temp)]
+    errors: u16,
+
+    #[br(temp)]
+    minor_rev_level: u16,
+
+    #[br(temp)]
+    lastcheck: u32,
+
+    #[br(temp)]
+    checkinterval: u32,
+
+    #[br(temp)]
+    creator_os: u32,
+
+    #[br(temp)]
+    rev_level: u32,
+
+    #[br(temp)]
+    def_resuid: u16,
+
+    #[br(temp)]
+    def_resgid: u16,
+
+    #[br(temp)]
+    first_ino: u32,
+
+    #[br(temp)]
+    inode_size: u16,
+
+    #[br(temp)]
+    block_group_nr: u16,
+
+    #[br(temp)]
+    feature_compat: u32,
+
+    feature_incompat: u32,
+
+    /// Size of each group descriptor, only used with the 64-bit feature
+    #[br(seek_before = SeekFrom::Start(0xfe))]
+    desc_size: u16,
+
+    /// High half of the number of blocks, only used with the 64-bit feature
+    #[br(seek_before = SeekFrom::Start(0x150))]
+    blocks_count_hi: u32,
+}
+
+impl SuperBlock {
+    fn is_64bit(&self) -> bool {
+        self.feature_incompat & EXT4_FEATURE_INCOMPAT_64BIT != 0
+    }
+
+    fn block_size(&self) -> u64 {
+        1024 << self.log_block_size
+    }
+
+    fn blocks_count(&self) -> u64 {
+        if self.is_64bit() {
+            ((self.blocks_count_hi as u64) << 32) | self.blocks_count_lo as u64
+        } else {
+            self.blocks_count_lo as u64
+        }
+    }
+
+    fn desc_size(&self) -> u16 {
+        if self.is_64bit() {
+            self.desc_size.max(EXT4_MIN_DESC_SIZE)
+        } else {
+            EXT4_MIN_DESC_SIZE
+        }
+    }
+}
+
+/// The fields of an ext4 block group descriptor needed to locate its block bitmap
+#[derive_binread]
+#[br(little, import(desc_size: u16))]
+struct GroupDescriptor {
+    block_bitmap_lo: u32,
+
+    #[br(temp)]
+    inode_bitmap_lo: u32,
+
+    #[br(temp)]
+    inode_table_lo: u32,
+
+    #[br(temp)]
+    free_blocks_count_lo: u16,
+
+    #[br(temp)]
+    free_inodes_count_lo: u16,
+
+    #[br(temp)]
+    used_dirs_count_lo: u16,
+
+    flags: u16,
+
+    #[br(temp)]
+    exclude_bitmap_lo: u32,
+
+    #[br(temp)]
+    block_bitmap_csum_lo: u16,
+
+    #[br(temp)]
+    inode_bitmap_csum_lo: u16,
+
+    #[br(temp)]
+    itable_unused_lo: u16,
+
+    #[br(temp)]
+    checksum: u16,
+
+    #[br(if(desc_size >= EXT4_MIN_DESC_SIZE_64BIT))]
+    block_bitmap_hi: u32,
+}
+
+impl GroupDescriptor {
+    fn block_bitmap(&self) -> u64 {
+        ((self.block_bitmap_hi as u64) << 32) | self.block_bitmap_lo as u64
+    }
+}
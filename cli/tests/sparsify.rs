@@ -0,0 +1,120 @@
+use std::fs;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use qcow_cli::ext4;
+use qcow_cli::qcow::{self, ExtentKind};
+
+/// A 2 MiB disk with an MBR partition table holding a single 1 MiB ext4 partition (with 1 KiB
+/// blocks and no journal), from which a 64 KiB file has been deleted, leaving its blocks free
+/// but still allocated in the qcow. 997 of its blocks are free.
+const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/ext4.qcow2");
+
+/// The same as [`FIXTURE`] but with a 2 MiB ext4 partition using bigalloc, with 4 KiB clusters of
+/// 1 KiB blocks. 2008 of its blocks are free.
+const BIGALLOC_FIXTURE: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/tests/data/ext4-bigalloc.qcow2"
+);
+
+/// Start of the ext4 partition within the guest
+const PARTITION_START: u64 = 1 << 20;
+
+fn copy_fixture(test: &str, fixture: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("qcow-cli-{}-{}", test, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let path = dir.join("ext4.qcow2");
+    fs::copy(fixture, &path).unwrap();
+
+    path
+}
+
+/// Get the number of bytes of the ext4 partition stored within the qcow itself
+fn allocated_in_partition(path: &Path) -> u64 {
+    let mut file = BufReader::new(fs::File::open(path).unwrap());
+    let qcow = qcow::load(&mut file).unwrap().unwrap_qcow2();
+    let mut reader = qcow.reader(&mut file);
+
+    reader
+        .allocation_map()
+        .map(Result::unwrap)
+        .filter(|extent| extent.is_local() && extent.kind != ExtentKind::Zero)
+        .map(|extent| {
+            extent
+                .end()
+                .saturating_sub(extent.start.max(PARTITION_START))
+        })
+        .sum()
+}
+
+fn read_guest(path: &Path) -> Vec<u8> {
+    let mut file = BufReader::new(fs::File::open(path).unwrap());
+    let qcow = qcow::load(&mut file).unwrap().unwrap_qcow2();
+    let mut guest = Vec::new();
+    qcow.reader(&mut file).read_to_end(&mut guest).unwrap();
+
+    guest
+}
+
+fn check_sparsify(test: &str, fixture: &str, zero: bool, free_blocks: u64) {
+    let path = copy_fixture(test, fixture);
+    let before = read_guest(&path);
+    let allocated = allocated_in_partition(&path);
+
+    let sparsified = qcow_cli::sparsify(&path, zero).unwrap();
+    assert_eq!(sparsified.filesystems, 1);
+    assert!(sparsified.skipped.is_empty());
+    assert_eq!(sparsified.free_bytes, free_blocks * 1024);
+
+    // the deleted file's blocks no longer take up space in the qcow. The file's 64 KiB covers at
+    // least 14 whole clusters of the qcow.
+    assert!(allocated_in_partition(&path) <= allocated - (14 << 12));
+
+    // discarding leaves clusters only partly covered by free blocks alone, while zeroing also
+    // writes zeroes over the free blocks within them
+    let after = read_guest(&path);
+    assert_eq!(after.len(), before.len());
+    let stale = |guest: &[u8]| {
+        guest
+            .windows(9)
+            .filter(|&window| window == b"discarded")
+            .count()
+    };
+    if zero {
+        assert_eq!(stale(&after), 0);
+    } else {
+        assert!(stale(&after) < stale(&before));
+    }
+
+    // everything still in use by the filesystem is left untouched, including all the metadata
+    // covered by checksums
+    let superblock = ext4::SuperBlock::new(&after[PARTITION_START as usize..]).unwrap();
+    let kept = superblock.resolve_path("/kept.txt").unwrap();
+    let inode = superblock.load_inode(kept.inode).unwrap();
+    let mut contents = Vec::new();
+    superblock
+        .open(&inode)
+        .unwrap()
+        .read_to_end(&mut contents)
+        .unwrap();
+    assert_eq!(contents, b"kept\n");
+
+    fs::remove_dir_all(path.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn sparsify_discard() {
+    check_sparsify("sparsify_discard", FIXTURE, false, 997);
+}
+
+#[test]
+fn sparsify_zero() {
+    check_sparsify("sparsify_zero", FIXTURE, true, 997);
+}
+
+#[test]
+fn sparsify_bigalloc() {
+    check_sparsify("sparsify_bigalloc", BIGALLOC_FIXTURE, false, 2008);
+}
@@ -0,0 +1,271 @@
+use crate::refcount::Refcounts;
+use crate::*;
+
+use std::fs::OpenOptions;
+use std::io::{self, Read, Seek, Write};
+
+/// Changes to make to the format of an existing qcow2 image in place, for use with
+/// [`Writer::amend`] or [`amend`]. Any option left as `None` is kept as it is.
+///
+/// ## Example
+///
+/// ```rust
+/// use qcow::AmendOptions;
+///
+/// // make the image readable by versions of qemu predating qcow2 version 3
+/// let options = AmendOptions {
+///     version: Some(2),
+///     ..AmendOptions::default()
+/// };
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct AmendOptions {
+    /// Version of the qcow2 format, either 2 or 3
+    pub version: Option<u32>,
+
+    /// Whether the lazy refcounts compatible feature bit is set. Requires a version 3 image, and
+    /// is cleared when downgrading to version 2 unless explicitly enabled.
+    pub lazy_refcounts: Option<bool>,
+
+    /// Width of each refcount entry as a power of two number of bits. Must be 4 for version 2
+    /// images.
+    pub refcount_order: Option<u32>,
+}
+
+impl<'qcow, 'file, F> Writer<'qcow, 'file, F>
+where
+    F: Read + Write + Seek,
+{
+    /// Change the version, lazy refcounts bit or refcount width of the image in place.
+    ///
+    /// Upgrading a version 2 image adds a version 3 header with no features set. Downgrading to
+    /// version 2 fails with [`Error::UnsupportedFeature`] if the image uses anything version 2
+    /// can't represent, such as zero clusters or zstd compression. Changing the refcount width
+    /// rebuilds the refcount table and every refcount block, failing with
+    /// [`Error::RefcountOverflow`] if a refcount doesn't fit in the new width.
+    ///
+    /// ## Example
+    ///
+    /// ```rust
+    /// use std::io::Cursor;
+    /// use qcow::{AmendOptions, CreateOptions};
+    ///
+    /// let mut file = Cursor::new(Vec::new());
+    /// let options = CreateOptions {
+    ///     version: 2,
+    ///     ..CreateOptions::new(16 << 20)
+    /// };
+    /// let mut qcow = qcow::create(&mut file, &options)?;
+    ///
+    /// let mut writer = qcow.writer(&mut file)?;
+    /// writer.amend(&AmendOptions {
+    ///     version: Some(3),
+    ///     refcount_order: Some(6),
+    ///     ..AmendOptions::default()
+    /// })?;
+    /// writer.sync()?;
+    /// drop(writer);
+    ///
+    /// assert_eq!(qcow.header.version, 3);
+    /// assert_eq!(qcow.header.refcount_order(), 6);
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn amend(&mut self, options: &AmendOptions) -> Result<(), Error> {
+        let header = &self.qcow.header;
+        let version = options.version.unwrap_or(header.version);
+        let refcount_order = options.refcount_order.unwrap_or(header.refcount_order());
+        let lazy_refcounts = options.lazy_refcounts.unwrap_or_else(|| {
+            version == 3
+                && header
+                    .v3_header
+                    .as_ref()
+                    .is_some_and(|v3_header| v3_header.compatible_features.lazy_refcount())
+        });
+
+        validate(version, refcount_order, lazy_refcounts)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+        if version == 2 && header.version == 3 {
+            self.check_downgradable()?;
+        }
+
+        self.sync()?;
+
+        // copy the refcounts before changing anything, as they may not fit in the new width
+        let rebuilt_refcounts = if refcount_order != self.qcow.header.refcount_order() {
+            Some(self.refcounts.with_order(refcount_order)?)
+        } else {
+            None
+        };
+
+        let upgrading = version == 3 && self.qcow.header.v3_header.is_none();
+        if upgrading {
+            self.qcow.header.v3_header = Some(Version3Header {
+                incompatible_features: IncompatibleFeatures::new(),
+                compatible_features: CompatibleFeatures::new(),
+                autoclear_features: AutoClearFeatures::new(),
+                refcount_order: 4,
+                header_len: V3_HEADER_LEN,
+                compression_type: CompressionType::Zlib,
+            });
+            self.qcow.header.version = 3;
+        }
+
+        if let Some(refcounts) = rebuilt_refcounts {
+            self.replace_refcounts(refcounts)?;
+        }
+
+        if upgrading {
+            self.upgrade_snapshots()?;
+        }
+
+        if version == 3 {
+            let v3_header = self.qcow.header.v3_header.as_mut().unwrap();
+            v3_header
+                .compatible_features
+                .set_lazy_refcount(lazy_refcounts);
+        } else {
+            self.qcow.header.v3_header = None;
+        }
+
+        self.qcow.header.version = version;
+        self.header_dirty = true;
+
+        self.sync()
+    }
+
+    /// Extend the extra data of every snapshot to include the VM state and virtual disk sizes,
+    /// as required by version 3
+    fn upgrade_snapshots(&mut self) -> Result<(), Error> {
+        if self
+            .qcow
+            .snapshots
+            .iter()
+            .all(|snapshot| snapshot.extra_data_size >= 16)
+        {
+            return Ok(());
+        }
+
+        self.release_snapshot_table()?;
+
+        // version 2 images can't be resized while they have snapshots, so every snapshot has the
+        // current size
+        let disk_size = self.guest_size();
+        for snapshot in self.qcow.snapshots.iter_mut() {
+            snapshot.extra_data.vm_state_size = snapshot.vm_state_len();
+            snapshot
+                .extra_data
+                .virtual_disk_size
+                .get_or_insert(disk_size);
+            snapshot.extra_data_size = snapshot.extra_data_size.max(16);
+        }
+        self.snapshots_dirty = true;
+
+        Ok(())
+    }
+
+    /// Ensure the image doesn't use any features which can't be represented in version 2
+    fn check_downgradable(&mut self) -> Result<(), Error> {
+        if self.qcow.header.compression_type() != CompressionType::Zlib {
+            return Err(Error::UnsupportedFeature(
+                "compression types other than zlib when downgrading to version 2",
+            ));
+        }
+
+        let is_zero = |entry: &L2Entry| {
+            matches!(
+                &entry.cluster_descriptor,
+                ClusterDescriptor::Standard(cluster) if cluster.all_zeroes
+            )
+        };
+        if self.any_l2_entry(is_zero)? {
+            return Err(Error::UnsupportedFeature(
+                "zero clusters when downgrading to version 2",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Switch to the given refcounts, written to a new refcount table and refcount blocks, then
+    /// free the old table and blocks
+    fn replace_refcounts(&mut self, mut refcounts: Refcounts) -> Result<(), Error> {
+        let old_clusters = self.refcounts.metadata_clusters();
+
+        // the old table and blocks remain allocated until the header points to the new ones, so
+        // the image stays consistent if interrupted
+        refcounts.flush(self.file)?;
+        let (offset, clusters) = refcounts.table_location();
+
+        let header = &mut self.qcow.header;
+        header.refcount_table_offset = offset;
+        header.refcount_table_clusters = clusters;
+        header.v3_header.as_mut().unwrap().refcount_order = refcounts.refcount_order();
+        write_header(header, self.file)?;
+        self.file.flush()?;
+
+        for cluster in old_clusters {
+            refcounts.decrement(cluster)?;
+        }
+        refcounts.flush_frees(self.file)?;
+        self.refcounts = refcounts;
+
+        Ok(())
+    }
+}
+
+fn validate(version: u32, refcount_order: u32, lazy_refcounts: bool) -> Result<(), &'static str> {
+    if version != 2 && version != 3 {
+        return Err("version must be either 2 or 3");
+    }
+
+    if refcount_order > 6 {
+        return Err("refcount_order must not exceed 6");
+    }
+
+    if version == 2 && refcount_order != 4 {
+        return Err("version 2 images only support a refcount_order of 4");
+    }
+
+    if version == 2 && lazy_refcounts {
+        return Err("version 2 images do not support lazy refcounts");
+    }
+
+    Ok(())
+}
+
+/// Change the version, lazy refcounts bit or refcount width of the qcow2 image at `path` in
+/// place.
+///
+/// See [`Writer::amend`] for more details.
+///
+/// ## Example
+///
+/// ```rust,no_run
+/// use qcow::AmendOptions;
+///
+/// // downgrade to version 2 for older qemu forks
+/// let options = AmendOptions {
+///     version: Some(2),
+///     ..AmendOptions::default()
+/// };
+/// qcow::amend("experiment.qcow2", &options)?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn amend(path: impl AsRef<Path>, options: &AmendOptions) -> Result<(), Error> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .map_err(Error::FileNotFound)?;
+
+    let mut qcow = match load(&mut BufReader::new(&mut file))? {
+        DynamicQcow::Qcow2(qcow) => qcow,
+        DynamicQcow::Qcow1(_) => return Err(Error::UnsupportedFeature("qcow version 1")),
+    };
+
+    let mut writer = qcow.writer(&mut file)?;
+    writer.amend(options)?;
+
+    Ok(())
+}
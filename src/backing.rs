@@ -0,0 +1,348 @@
+use crate::header_ext::HeaderExt;
+use crate::*;
+
+use std::convert::TryInto;
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::rc::Rc;
+
+/// A readable and seekable source of bytes, used for backing files which may be backed by
+/// anything from a file on disk to an in-memory buffer.
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// A resolver for mapping the backing file string stored in a qcow header
+/// ([`QcowHeader::backing_file`]) to the contents of the backing file.
+///
+/// By default backing files are opened from the filesystem using [`FileResolver`]. A custom
+/// resolver can be provided to [`Reader::with_backing_resolver`] in order to read backing files
+/// from a different location, from memory, or from a cache. Any closure of the form
+/// `Fn(&str) -> io::Result<Box<dyn ReadSeek>>` can be used as a resolver.
+///
+/// ## Example
+///
+/// ```rust,no_run
+/// use std::fs::File;
+/// use std::io::{self, BufReader};
+/// use std::path::Path;
+/// use qcow::ReadSeek;
+///
+/// # const PATH: &str = "/home/jamcleod/.panda/bionic-server-cloudimg-amd64-noaslr-nokaslr.qcow2";
+/// let qcow = qcow::open(PATH)?.unwrap_qcow2();
+/// let mut file = BufReader::new(File::open(PATH)?);
+///
+/// // look for backing files next to the overlay, ignoring the directory they were created in
+/// let reader = qcow.reader(&mut file).with_backing_resolver(|backing_file: &str| {
+///     let name = Path::new(backing_file).file_name().unwrap_or_default();
+///     let file = File::open(Path::new("/images").join(name))?;
+///
+///     Ok(Box::new(BufReader::new(file)) as Box<dyn ReadSeek>)
+/// });
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub trait BackingResolver {
+    /// Get the contents of the backing file referred to by `backing_file`
+    fn resolve(&self, backing_file: &str) -> io::Result<Box<dyn ReadSeek>>;
+}
+
+impl<F> BackingResolver for F
+where
+    F: Fn(&str) -> io::Result<Box<dyn ReadSeek>>,
+{
+    fn resolve(&self, backing_file: &str) -> io::Result<Box<dyn ReadSeek>> {
+        self(backing_file)
+    }
+}
+
+/// The default [`BackingResolver`], which opens backing files from the filesystem using the
+/// path stored in the header as-is.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FileResolver;
+
+impl BackingResolver for FileResolver {
+    fn resolve(&self, backing_file: &str) -> io::Result<Box<dyn ReadSeek>> {
+        Ok(Box::new(BufReader::new(File::open(backing_file)?)))
+    }
+}
+
+/// The format of a backing file, either as declared by the
+/// [`BackingFileFormat`](HeaderExt::BackingFileFormat) header extension or as detected from the
+/// contents of the backing file itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BackingFormat {
+    /// A legacy version 1 qcow image
+    Qcow,
+
+    /// A qcow image of version 2 or higher
+    Qcow2,
+
+    /// A raw disk image, in which guest offsets map directly to file offsets
+    Raw,
+
+    /// A format unknown to this crate, identified by its name
+    Other(String),
+}
+
+impl BackingFormat {
+    /// Parse a format from the name used by the backing file format header extension (such as
+    /// `"qcow2"` or `"raw"`)
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "qcow" => Self::Qcow,
+            "qcow2" => Self::Qcow2,
+            "raw" => Self::Raw,
+            other => Self::Other(other.to_owned()),
+        }
+    }
+
+    /// Get the name of the format as used by the backing file format header extension
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Qcow => "qcow",
+            Self::Qcow2 => "qcow2",
+            Self::Raw => "raw",
+            Self::Other(name) => name,
+        }
+    }
+
+    /// Detect the format of a backing file from its magic, leaving the position of `source`
+    /// at the start of the file.
+    pub fn detect(source: &mut (impl Read + Seek)) -> io::Result<Self> {
+        let mut magic = [0; 8];
+        source.seek(SeekFrom::Start(0))?;
+        let len = read_up_to(source, &mut magic)?;
+        source.seek(SeekFrom::Start(0))?;
+
+        Ok(if len == magic.len() && magic[..4] == *b"QFI\xfb" {
+            if u32::from_be_bytes(magic[4..].try_into().unwrap()) == 1 {
+                Self::Qcow
+            } else {
+                Self::Qcow2
+            }
+        } else {
+            Self::Raw
+        })
+    }
+
+    /// Check the format detected from the contents of a backing file against the format declared
+    /// in the header, returning the format the backing file should be read as.
+    pub(crate) fn check(declared: Option<Self>, detected: Self) -> Result<Self, Error> {
+        match declared {
+            Some(declared @ Self::Other(_)) => Err(Error::UnsupportedBackingFormat(declared)),
+            Some(declared) if declared != detected => {
+                Err(Error::BackingFormatMismatch { declared, detected })
+            }
+            _ => Ok(detected),
+        }
+    }
+}
+
+impl fmt::Display for BackingFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl QcowHeader {
+    /// Get the format of the backing file as declared by the
+    /// [`BackingFileFormat`](HeaderExt::BackingFileFormat) header extension, if present.
+    pub fn backing_file_format(&self) -> Option<BackingFormat> {
+        self.extensions.iter().find_map(|ext| match ext {
+            HeaderExt::BackingFileFormat(name) => Some(BackingFormat::from_name(name)),
+            _ => None,
+        })
+    }
+}
+
+/// A reader for the guest contents of a backing file, as used by [`Reader`] for any clusters
+/// not allocated in the image itself.
+///
+/// Reads past the end of the backing image return zeroes, matching how a backing file shorter
+/// than its overlay appears to the guest.
+pub enum BackingReader {
+    /// A qcow2 backing image, which may in turn have its own backing file
+    Qcow2(Reader<'static, 'static, Box<dyn ReadSeek>>),
+
+    /// A raw backing image, read as a plain file
+    Raw(RawReader),
+}
+
+impl BackingReader {
+    /// Open the backing file of the given qcow header using `resolver`, returning `None` if
+    /// the image has no backing file.
+    pub fn open(
+        header: &QcowHeader,
+        resolver: Rc<dyn BackingResolver>,
+    ) -> Result<Option<Self>, Error> {
+        let backing_file = match header.backing_file.as_deref() {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+
+        let source = resolver
+            .resolve(backing_file)
+            .map_err(Error::FileNotFound)?;
+
+        Self::from_source(source, header.backing_file_format(), resolver).map(Some)
+    }
+
+    /// Create a reader for a backing file from its contents, checking the detected format
+    /// against the declared format (if any). If the backing file itself has a backing file, it
+    /// will be located using `resolver`.
+    ///
+    /// A backing file declared as raw, or an undeclared backing file without a qcow magic, is
+    /// read as a plain file.
+    pub fn from_source(
+        mut source: Box<dyn ReadSeek>,
+        declared: Option<BackingFormat>,
+        resolver: Rc<dyn BackingResolver>,
+    ) -> Result<Self, Error> {
+        let format = BackingFormat::check(declared, BackingFormat::detect(&mut source)?)?;
+
+        match format {
+            BackingFormat::Raw => Ok(Self::Raw(RawReader::new(source)?)),
+            BackingFormat::Qcow2 => {
+                let qcow = crate::load(&mut source)?.unwrap_qcow2();
+
+                Ok(Self::Qcow2(
+                    qcow.into_reader(source).with_shared_resolver(resolver),
+                ))
+            }
+            format => Err(Error::UnsupportedBackingFormat(format)),
+        }
+    }
+
+    /// Get the format of the backing file being read from
+    pub fn format(&self) -> BackingFormat {
+        match self {
+            Self::Qcow2(_) => BackingFormat::Qcow2,
+            Self::Raw(_) => BackingFormat::Raw,
+        }
+    }
+
+    /// Get the size of the backing image as presented to the guest
+    pub fn guest_size(&self) -> u64 {
+        match self {
+            Self::Qcow2(reader) => reader.guest_size(),
+            Self::Raw(reader) => reader.len(),
+        }
+    }
+}
+
+impl Read for BackingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Qcow2(reader) => {
+                if reader.guest_pos() >= reader.guest_size() {
+                    buf.fill(0);
+                    reader.seek(SeekFrom::Current(buf.len() as i64))?;
+
+                    Ok(buf.len())
+                } else {
+                    reader.read(buf)
+                }
+            }
+            Self::Raw(reader) => reader.read(buf),
+        }
+    }
+}
+
+impl Seek for BackingReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            Self::Qcow2(reader) => reader.seek(pos),
+            Self::Raw(reader) => reader.seek(pos),
+        }
+    }
+}
+
+/// A reader for a raw backing image, which reads as zeroes past the end of the file.
+pub struct RawReader {
+    source: Box<dyn ReadSeek>,
+    len: u64,
+    pos: u64,
+}
+
+impl RawReader {
+    /// Create a reader from the contents of a raw image
+    pub fn new(mut source: Box<dyn ReadSeek>) -> Result<Self, Error> {
+        let len = source.seek(SeekFrom::End(0))?;
+
+        Ok(Self {
+            source,
+            len,
+            pos: 0,
+        })
+    }
+
+    /// Get the length of the raw image in bytes
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns true if the raw image is empty
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Read for RawReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len {
+            buf.fill(0);
+            self.pos += buf.len() as u64;
+
+            return Ok(buf.len());
+        }
+
+        let read_len = u64::min(self.len - self.pos, buf.len() as u64) as usize;
+        self.source.seek(SeekFrom::Start(self.pos))?;
+        let bytes_read = self.source.read(&mut buf[..read_len])?;
+        self.pos += bytes_read as u64;
+
+        Ok(bytes_read)
+    }
+}
+
+impl Seek for RawReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::Current(offset) => offset_pos(self.pos, offset),
+            SeekFrom::End(offset) => offset_pos(self.len, offset),
+        };
+
+        self.pos = new_pos.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek out of range of 64-bit position",
+            )
+        })?;
+
+        Ok(self.pos)
+    }
+}
+
+pub(crate) fn offset_pos(pos: u64, offset: i64) -> Option<u64> {
+    if offset < 0 {
+        pos.checked_sub(offset.unsigned_abs())
+    } else {
+        pos.checked_add(offset as u64)
+    }
+}
+
+/// Read into `buf` until it is full or the end of the reader has been reached
+fn read_up_to(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(len)
+}
@@ -0,0 +1,197 @@
+use crate::*;
+
+use std::collections::HashSet;
+
+/// A single image within a backing chain, as returned by [`Qcow2::backing_chain`].
+#[derive(Debug, Clone)]
+pub struct ChainLayer {
+    /// Path of the layer as stored in the header of the layer above it. `None` for the top layer,
+    /// whose path is not known to the parsed qcow.
+    pub path: Option<String>,
+
+    /// Format of the layer as detected from its contents, or `None` if the layer is not present
+    pub format: Option<BackingFormat>,
+
+    /// Format of the layer as declared by the header of the layer above it, if any
+    pub declared_format: Option<BackingFormat>,
+
+    /// Size of the virtual disk provided by this layer in bytes, if known
+    pub virtual_size: Option<u64>,
+
+    /// Size of a cluster within this layer in bytes, if the layer is a qcow image
+    pub cluster_size: Option<u64>,
+
+    /// Whether or not the layer could be located using the resolver
+    pub present: bool,
+
+    /// The error encountered while reading a layer which could be located but not read, which
+    /// ends the chain
+    pub error: Option<String>,
+}
+
+impl ChainLayer {
+    fn missing(path: String, declared_format: Option<BackingFormat>) -> Self {
+        Self {
+            path: Some(path),
+            format: None,
+            declared_format,
+            virtual_size: None,
+            cluster_size: None,
+            present: false,
+            error: None,
+        }
+    }
+}
+
+impl Qcow2 {
+    /// Walk the full backing chain of this qcow, opening backing files from the filesystem.
+    ///
+    /// The first layer is the qcow itself, followed by its backing file, the backing file of
+    /// that, and so on. The chain ends at the first layer without a backing file, at the first
+    /// layer which is not present or can't be read, or at a layer which has already been
+    /// visited.
+    ///
+    /// To find out which layer provides the contents of a given part of the guest, see
+    /// [`Reader::supplying_layer`].
+    ///
+    /// ## Example
+    ///
+    /// ```rust,no_run
+    /// # const PATH: &str = "/home/jamcleod/.panda/bionic-server-cloudimg-amd64-noaslr-nokaslr.qcow2";
+    /// let qcow = qcow::open(PATH)?.unwrap_qcow2();
+    ///
+    /// for (i, layer) in qcow.backing_chain().iter().enumerate() {
+    ///     println!("{}: {:?} (present = {})", i, layer.path, layer.present);
+    /// }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn backing_chain(&self) -> Vec<ChainLayer> {
+        self.backing_chain_with_resolver(&FileResolver)
+    }
+
+    /// Walk the full backing chain of this qcow, using `resolver` to locate backing files.
+    ///
+    /// See [`Qcow2::backing_chain`] for more info.
+    pub fn backing_chain_with_resolver(&self, resolver: &dyn BackingResolver) -> Vec<ChainLayer> {
+        let top = ChainLayer {
+            path: None,
+            format: Some(BackingFormat::Qcow2),
+            declared_format: None,
+            virtual_size: Some(self.header.size),
+            cluster_size: Some(self.cluster_size()),
+            present: true,
+            error: None,
+        };
+
+        walk_chain(
+            top,
+            self.header.backing_file.clone(),
+            self.header.backing_file_format(),
+            resolver,
+        )
+    }
+}
+
+impl DynamicQcow {
+    /// Walk the full backing chain of this qcow, opening backing files from the filesystem.
+    ///
+    /// See [`Qcow2::backing_chain`] for more info.
+    pub fn backing_chain(&self) -> Vec<ChainLayer> {
+        self.backing_chain_with_resolver(&FileResolver)
+    }
+
+    /// Walk the full backing chain of this qcow, using `resolver` to locate backing files.
+    ///
+    /// See [`Qcow2::backing_chain`] for more info.
+    pub fn backing_chain_with_resolver(&self, resolver: &dyn BackingResolver) -> Vec<ChainLayer> {
+        match self {
+            Self::Qcow2(qcow) => qcow.backing_chain_with_resolver(resolver),
+            Self::Qcow1(qcow) => {
+                let top = ChainLayer {
+                    path: None,
+                    format: Some(BackingFormat::Qcow),
+                    declared_format: None,
+                    virtual_size: Some(qcow.header.size),
+                    cluster_size: Some(qcow.cluster_size()),
+                    present: true,
+                    error: None,
+                };
+
+                walk_chain(top, qcow.header.backing_file.clone(), None, resolver)
+            }
+        }
+    }
+}
+
+fn walk_chain(
+    top: ChainLayer,
+    mut backing_file: Option<String>,
+    mut declared_format: Option<BackingFormat>,
+    resolver: &dyn BackingResolver,
+) -> Vec<ChainLayer> {
+    let mut layers = vec![top];
+    let mut visited = HashSet::new();
+
+    while let Some(path) = backing_file.take() {
+        if !visited.insert(path.clone()) {
+            break;
+        }
+
+        let declared = declared_format.take();
+        let mut source = match resolver.resolve(&path) {
+            Ok(source) => source,
+            Err(_) => {
+                layers.push(ChainLayer::missing(path, declared));
+                break;
+            }
+        };
+
+        let mut layer = ChainLayer {
+            path: Some(path),
+            format: None,
+            declared_format: declared,
+            virtual_size: None,
+            cluster_size: None,
+            present: true,
+            error: None,
+        };
+
+        let format = match BackingFormat::detect(&mut source) {
+            Ok(format) => format,
+            Err(err) => {
+                layer.error = Some(err.to_string());
+                layers.push(layer);
+                break;
+            }
+        };
+        layer.format = Some(format.clone());
+
+        match format {
+            BackingFormat::Raw => {
+                layer.virtual_size = source.seek(SeekFrom::End(0)).ok();
+            }
+            _ => match crate::load(&mut source) {
+                Ok(DynamicQcow::Qcow2(qcow)) => {
+                    layer.virtual_size = Some(qcow.header.size);
+                    layer.cluster_size = Some(qcow.cluster_size());
+                    backing_file = qcow.header.backing_file.clone();
+                    declared_format = qcow.header.backing_file_format();
+                }
+                Ok(DynamicQcow::Qcow1(qcow)) => {
+                    layer.virtual_size = Some(qcow.header.size);
+                    layer.cluster_size = Some(qcow.cluster_size());
+                    backing_file = qcow.header.backing_file.clone();
+                }
+                Err(err) => {
+                    layer.error = Some(err.to_string());
+                    layers.push(layer);
+                    break;
+                }
+            },
+        }
+
+        layers.push(layer);
+    }
+
+    layers
+}
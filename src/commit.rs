@@ -0,0 +1,145 @@
+use crate::*;
+
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+
+/// What to do with an overlay once its contents have been committed into its backing file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommitAction {
+    /// Leave the overlay unchanged
+    Keep,
+
+    /// Deallocate every cluster of the overlay, so that it reads entirely from its backing file
+    Empty,
+
+    /// Delete the overlay file
+    Drop,
+}
+
+impl Qcow2 {
+    /// Copy every cluster allocated in this qcow into its backing image, returning the number of
+    /// guest bytes committed.
+    ///
+    /// `reader` must be the source file of the qcow and `backing` the backing file, opened for
+    /// writing. The backing file may either be a qcow2 image or a raw image, and its format is
+    /// checked against the format declared in the header (if any).
+    ///
+    /// See [`commit`] for committing an overlay on the filesystem.
+    pub fn commit<R, W>(&self, reader: &mut R, backing: &mut W) -> Result<u64, Error>
+    where
+        R: Read + Seek,
+        W: Read + Write + Seek,
+    {
+        let declared = self.header.backing_file_format();
+        match BackingFormat::check(declared, BackingFormat::detect(backing)?)? {
+            BackingFormat::Raw => self.commit_to(reader, backing),
+            BackingFormat::Qcow2 => {
+                let mut backing_qcow = crate::load(backing)?.unwrap_qcow2();
+                let mut writer = backing_qcow.writer(backing)?;
+
+                let committed = self.commit_to(reader, &mut writer)?;
+                writer.sync()?;
+
+                Ok(committed)
+            }
+            format => Err(Error::UnsupportedBackingFormat(format)),
+        }
+    }
+
+    /// Copy every allocated cluster into `target` at its guest offset
+    fn commit_to<R, W>(&self, reader: &mut R, target: &mut W) -> Result<u64, Error>
+    where
+        R: Read + Seek,
+        W: Write + Seek,
+    {
+        let cluster_size = self.cluster_size();
+        let cluster_bits = self.header.cluster_bits;
+        let compression_type = self.header.compression_type();
+        let mut cluster = vec![0; cluster_size as usize];
+        let mut committed = 0;
+
+        for (l1_index, l1_entry) in self.l1_table.iter().enumerate() {
+            if l1_entry.l2_offset == 0 {
+                continue;
+            }
+
+            let l2_table = l1_entry.read_l2(reader, cluster_bits).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::UnexpectedEof, "L2 table could not be read")
+            })?;
+
+            for (l2_index, l2_entry) in l2_table.iter().enumerate() {
+                let guest_offset =
+                    ((l1_index as u64 * self.l2_entries()) + l2_index as u64) << cluster_bits;
+                if l2_entry.is_unallocated() || guest_offset >= self.header.size {
+                    continue;
+                }
+
+                let len = u64::min(cluster_size, self.header.size - guest_offset);
+                l2_entry.read_contents(reader, &mut cluster, compression_type)?;
+
+                target.seek(SeekFrom::Start(guest_offset))?;
+                target.write_all(&cluster[..len as usize])?;
+                committed += len;
+            }
+        }
+
+        Ok(committed)
+    }
+}
+
+/// Commit the contents of the overlay at `path` into its backing file, then apply `action` to
+/// the overlay. Returns the number of guest bytes committed.
+///
+/// The backing file is opened using the path stored in the overlay's header.
+///
+/// ## Example
+///
+/// ```rust,no_run
+/// use qcow::CommitAction;
+///
+/// // fold the experiment's changes into the base image and start over with an empty overlay
+/// let committed = qcow::commit("experiment.qcow2", CommitAction::Empty)?;
+/// println!("Committed {} bytes", committed);
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn commit(path: impl AsRef<Path>, action: CommitAction) -> Result<u64, Error> {
+    let path = path.as_ref();
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(action == CommitAction::Empty)
+        .open(path)
+        .map_err(Error::FileNotFound)?;
+
+    let mut qcow = match load(&mut BufReader::new(&mut file))? {
+        DynamicQcow::Qcow2(qcow) => qcow,
+        DynamicQcow::Qcow1(_) => return Err(Error::UnsupportedFeature("qcow version 1")),
+    };
+
+    if action == CommitAction::Empty {
+        qcow.check_writable()?;
+    }
+
+    let backing_file = qcow.header.backing_file.clone().ok_or(Error::NoBackingFile)?;
+    let mut backing = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(backing_file)
+        .map_err(Error::FileNotFound)?;
+
+    let committed = qcow.commit(&mut BufReader::new(&mut file), &mut backing)?;
+
+    match action {
+        CommitAction::Keep => (),
+        CommitAction::Empty => {
+            let mut writer = qcow.writer(&mut file)?;
+            writer.make_empty()?;
+            writer.sync()?;
+        }
+        CommitAction::Drop => {
+            drop(file);
+            std::fs::remove_file(path)?;
+        }
+    }
+
+    Ok(committed)
+}
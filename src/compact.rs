@@ -0,0 +1,397 @@
+use crate::compressed::allocate_compressed;
+use crate::levels::{ClusterDescriptor, L2Entry};
+use crate::refcount::Refcounts;
+use crate::*;
+
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+
+/// Size of the image file before and after compacting it, as returned by [`Qcow2::compact`]
+/// and [`compact`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compaction {
+    /// Length of the image file in bytes before compacting
+    pub old_len: u64,
+
+    /// Length of the image file in bytes after compacting
+    pub new_len: u64,
+}
+
+impl Compaction {
+    /// Number of bytes freed by compacting the image
+    pub fn reclaimed(&self) -> u64 {
+        self.old_len.saturating_sub(self.new_len)
+    }
+}
+
+impl Qcow2 {
+    /// Write a copy of the image in `source` to `output` in which the placement of every host
+    /// cluster is rewritten so that the image contains no unused space, returning the length of
+    /// the image before and after. `source` must be the source file of the qcow, and `output`
+    /// should be empty. On success the qcow is updated to describe the image in `output`.
+    ///
+    /// Metadata (the L1 tables and the snapshot table) is placed first, followed by each L2
+    /// table and the data clusters it references, in guest order of the active image and then
+    /// of each snapshot. Clusters which aren't referenced by anything are dropped and the
+    /// refcounts are rebuilt from scratch. Compressed clusters are repacked, and zero clusters
+    /// lose any preallocated host cluster.
+    ///
+    /// `source` is never written to. The header is written to `output` last, after everything
+    /// else has been flushed, so `output` is not a valid image until compaction has completed.
+    /// See [`compact`] for compacting a qcow on the filesystem.
+    pub fn compact<R, W>(&mut self, source: &mut R, output: &mut W) -> Result<Compaction, Error>
+    where
+        R: Read + Seek,
+        W: Write + Seek,
+    {
+        self.check_writable()?;
+
+        let old_len = source.seek(SeekFrom::End(0))?;
+        let cluster_size = self.cluster_size();
+
+        let mut compactor = Compactor {
+            source,
+            output,
+            cluster_bits: self.header.cluster_bits,
+            refcounts: Refcounts::empty(self.header.cluster_bits, self.header.refcount_order()),
+            l2_tables: HashMap::new(),
+            data_clusters: HashMap::new(),
+            compressed_clusters: HashMap::new(),
+            compressed_offset: 0,
+        };
+
+        let clusters_for = |len: u64| len.div_ceil(cluster_size).max(1);
+        compactor.refcounts.allocate(1)?;
+        let l1_table_offset = compactor
+            .refcounts
+            .allocate(clusters_for(self.l1_table.len() as u64 * 8))?;
+        let snapshot_l1_offsets = self
+            .snapshots
+            .iter()
+            .map(|snapshot| {
+                let l1_len = snapshot.l1_table.len() as u64 * 8;
+                compactor.refcounts.allocate(clusters_for(l1_len))
+            })
+            .collect::<Result<Vec<u64>, Error>>()?;
+        let snapshot_table_len: u64 = self
+            .snapshots
+            .iter()
+            .map(|snapshot| snapshot.to_bytes().len() as u64)
+            .sum();
+        let snapshots_offset = if self.snapshots.is_empty() {
+            0
+        } else {
+            compactor
+                .refcounts
+                .allocate(clusters_for(snapshot_table_len))?
+        };
+
+        for l1_entry in self.l1_table.iter_mut() {
+            l1_entry.l2_offset = compactor.move_l2_table(l1_entry.l2_offset)?;
+        }
+
+        for snapshot in self.snapshots.iter_mut() {
+            for l1_entry in snapshot.l1_table.iter_mut() {
+                l1_entry.l2_offset = compactor.move_l2_table(l1_entry.l2_offset)?;
+            }
+        }
+
+        // copied flags depend on the final refcounts, so the tables are written last
+        compactor.write_l2_tables()?;
+
+        let refcounts = &compactor.refcounts;
+        for l1_entry in self.l1_table.iter_mut() {
+            l1_entry.is_used = l1_entry.l2_offset != 0 && refcounts.get(l1_entry.l2_offset) == 1;
+        }
+        compactor.write_l1_table(l1_table_offset, &self.l1_table)?;
+
+        for (snapshot, l1_table_offset) in self.snapshots.iter_mut().zip(snapshot_l1_offsets) {
+            snapshot.l1_table_offset = l1_table_offset;
+            compactor.write_l1_table(l1_table_offset, &snapshot.l1_table)?;
+        }
+
+        let snapshot_table: Vec<u8> = self.snapshots.iter().flat_map(Snapshot::to_bytes).collect();
+        compactor.write_at(snapshots_offset, &snapshot_table)?;
+
+        compactor.refcounts.flush(&mut *compactor.output)?;
+        let (refcount_table_offset, refcount_table_clusters) = compactor.refcounts.table_location();
+
+        // parts of the new layout which were never written should read as zeroes, so the
+        // output is extended to cover every allocated cluster
+        let new_len = compactor.refcounts.len() * cluster_size;
+        let output = compactor.output;
+        if output.seek(SeekFrom::End(0))? < new_len {
+            output.seek(SeekFrom::Start(new_len - 1))?;
+            output.write_all(&[0])?;
+        }
+        output.flush()?;
+
+        let header = &mut self.header;
+        header.l1_table_offset = l1_table_offset;
+        header.refcount_table_offset = refcount_table_offset;
+        header.refcount_table_clusters = refcount_table_clusters;
+        header.snapshots_offset = snapshots_offset;
+        if let Some(v3_header) = &mut header.v3_header {
+            v3_header.autoclear_features = AutoClearFeatures::new();
+        }
+
+        output.seek(SeekFrom::Start(0))?;
+        output.write_all(&vec![0; cluster_size as usize])?;
+        crate::writer::write_header(header, &mut *output)?;
+        output.flush()?;
+
+        Ok(Compaction { old_len, new_len })
+    }
+}
+
+/// State for building the compacted layout of an image in a separate output
+struct Compactor<'a, R, W> {
+    /// source file of the image being compacted
+    source: &'a mut R,
+
+    /// output the new layout is written to
+    output: &'a mut W,
+
+    cluster_bits: u32,
+
+    /// refcounts of the new layout
+    refcounts: Refcounts,
+
+    /// new offset and remapped entries of each L2 table, keyed by its original offset
+    l2_tables: HashMap<u64, (u64, Vec<L2Entry>)>,
+
+    /// new offset of each data cluster, keyed by its original offset
+    data_clusters: HashMap<u64, u64>,
+
+    /// new offset of each compressed cluster, keyed by its original offset
+    compressed_clusters: HashMap<u64, u64>,
+
+    /// offset following the most recently packed compressed cluster, or 0 if it ended on a
+    /// cluster boundary
+    compressed_offset: u64,
+}
+
+impl<'a, R, W> Compactor<'a, R, W>
+where
+    R: Read + Seek,
+    W: Write + Seek,
+{
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    /// Move the L2 table at `l2_offset` and all the data it references into the new layout,
+    /// returning its new offset. Every data cluster gains a reference for each L1 table
+    /// referencing the L2 table, even if it was already moved.
+    fn move_l2_table(&mut self, l2_offset: u64) -> Result<u64, Error> {
+        if l2_offset == 0 {
+            return Ok(0);
+        }
+
+        if let Some((new_offset, entries)) = self.l2_tables.get(&l2_offset) {
+            let new_offset = *new_offset;
+            let host_clusters: Vec<u64> = entries
+                .iter()
+                .flat_map(|entry| self.host_clusters(entry))
+                .collect();
+
+            self.refcounts.increment(new_offset)?;
+            for host_offset in host_clusters {
+                self.refcounts.increment(host_offset)?;
+            }
+
+            return Ok(new_offset);
+        }
+
+        let new_offset = self.refcounts.allocate(1)?;
+        let entries = L1Entry::from_u64(l2_offset)
+            .read_l2(&mut *self.source, self.cluster_bits)
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::UnexpectedEof, "L2 table could not be read")
+            })?
+            .iter()
+            .map(|entry| self.move_entry(entry))
+            .collect::<Result<Vec<L2Entry>, Error>>()?;
+
+        self.l2_tables.insert(l2_offset, (new_offset, entries));
+
+        Ok(new_offset)
+    }
+
+    /// Move the data referenced by an L2 entry into the new layout, returning the entry
+    /// describing its new location
+    fn move_entry(&mut self, entry: &L2Entry) -> Result<L2Entry, Error> {
+        let cluster_size = self.cluster_size();
+
+        match &entry.cluster_descriptor {
+            ClusterDescriptor::Standard(cluster) if cluster.all_zeroes => Ok(L2Entry::zero()),
+            ClusterDescriptor::Standard(cluster) if cluster.host_cluster_offset == 0 => {
+                Ok(L2Entry::unallocated())
+            }
+            ClusterDescriptor::Standard(cluster) => {
+                let old_offset = cluster.host_cluster_offset;
+                let new_offset = match self.data_clusters.get(&old_offset) {
+                    Some(&new_offset) => {
+                        self.refcounts.increment(new_offset)?;
+                        new_offset
+                    }
+                    None => {
+                        let new_offset = self.refcounts.allocate(1)?;
+                        self.copy(old_offset, new_offset, cluster_size)?;
+                        self.data_clusters.insert(old_offset, new_offset);
+                        new_offset
+                    }
+                };
+
+                Ok(L2Entry::standard(new_offset))
+            }
+            ClusterDescriptor::Compressed(cluster) => {
+                let old_offset = cluster.host_cluster_offset;
+                let sectors = cluster.additional_sector_count;
+                let new_offset = match self.compressed_clusters.get(&old_offset) {
+                    Some(&new_offset) => {
+                        let new_entry = L2Entry::compressed(new_offset, sectors);
+                        for host_offset in self.host_clusters(&new_entry) {
+                            self.refcounts.increment(host_offset)?;
+                        }
+
+                        new_offset
+                    }
+                    None => {
+                        // the data is packed at sector granularity, keeping its offset within
+                        // the first sector
+                        let range = cluster.host_range();
+                        let start_sector = range.start & !0x1ff;
+                        let len = range.end - start_sector;
+                        let new_sector = allocate_compressed(
+                            &mut self.refcounts,
+                            &mut self.compressed_offset,
+                            cluster_size,
+                            len,
+                        )?;
+                        self.copy(start_sector, new_sector, len)?;
+
+                        let new_offset = new_sector + (old_offset - start_sector);
+                        self.compressed_clusters.insert(old_offset, new_offset);
+                        new_offset
+                    }
+                };
+
+                Ok(L2Entry::compressed(new_offset, sectors))
+            }
+        }
+    }
+
+    /// Get the offset of every host cluster used by the given L2 entry
+    fn host_clusters(&self, entry: &L2Entry) -> Vec<u64> {
+        entry.host_clusters(self.cluster_bits)
+    }
+
+    /// Copy `len` bytes from `old_offset` in the source file to `new_offset` in the new
+    /// layout. Anything past the end of the source file is copied as zeroes.
+    fn copy(&mut self, old_offset: u64, new_offset: u64, len: u64) -> Result<(), Error> {
+        let mut data = Vec::with_capacity(len as usize);
+        self.source.seek(SeekFrom::Start(old_offset))?;
+        (&mut *self.source).take(len).read_to_end(&mut data)?;
+        data.resize(len as usize, 0);
+
+        self.write_at(new_offset, &data)
+    }
+
+    /// Write `bytes` to `offset` in the new layout
+    fn write_at(&mut self, offset: u64, bytes: &[u8]) -> Result<(), Error> {
+        self.output.seek(SeekFrom::Start(offset))?;
+        self.output.write_all(bytes)?;
+
+        Ok(())
+    }
+
+    fn write_l2_tables(&mut self) -> Result<(), Error> {
+        let mut tables = std::mem::take(&mut self.l2_tables);
+        for (new_offset, entries) in tables.values_mut() {
+            let mut bytes = Vec::with_capacity(entries.len() * 8);
+            for entry in entries.iter_mut() {
+                entry.is_used = entry
+                    .standard_host_offset()
+                    .is_some_and(|offset| self.refcounts.get(offset) == 1);
+                bytes.extend_from_slice(&entry.to_u64(self.cluster_bits).to_be_bytes());
+            }
+
+            self.write_at(*new_offset, &bytes)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_l1_table(&mut self, offset: u64, l1_table: &[L1Entry]) -> Result<(), Error> {
+        let bytes: Vec<u8> = l1_table
+            .iter()
+            .flat_map(|entry| entry.to_u64().to_be_bytes())
+            .collect();
+
+        self.write_at(offset, &bytes)
+    }
+}
+
+/// Compact the qcow2 image at `path`, so that its data is laid out in guest order and the file
+/// contains no unused space. Returns the length of the image before and after.
+///
+/// The compacted image is built in a temporary file alongside the original, which then
+/// replaces the original, so the image at `path` is left untouched if compaction fails or is
+/// interrupted. Enough free space for the compacted image is needed temporarily.
+///
+/// See [`Qcow2::compact`] for more details.
+///
+/// ## Example
+///
+/// ```rust,no_run
+/// let compaction = qcow::compact("experiment.qcow2")?;
+/// println!("Reclaimed {} bytes", compaction.reclaimed());
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn compact(path: impl AsRef<Path>) -> Result<Compaction, Error> {
+    let path = path.as_ref();
+    let mut file = BufReader::new(File::open(path).map_err(Error::FileNotFound)?);
+    let mut qcow = match load(&mut file)? {
+        DynamicQcow::Qcow2(qcow) => qcow,
+        DynamicQcow::Qcow1(_) => return Err(Error::UnsupportedFeature("qcow version 1")),
+    };
+
+    let mut temp_name = path.file_name().unwrap_or_default().to_owned();
+    temp_name.push(".compact");
+    let temp_path = path.with_file_name(temp_name);
+
+    let mut temp = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&temp_path)?;
+
+    let result = compact_into(&mut qcow, &mut file, &mut temp);
+    drop(temp);
+
+    match result {
+        Ok(compaction) => {
+            fs::rename(&temp_path, path)?;
+            Ok(compaction)
+        }
+        Err(err) => {
+            let _ = fs::remove_file(&temp_path);
+            Err(err)
+        }
+    }
+}
+
+/// Compact `qcow` from its source file into `temp`, syncing it to disk once complete
+fn compact_into(
+    qcow: &mut Qcow2,
+    file: &mut BufReader<File>,
+    temp: &mut File,
+) -> Result<Compaction, Error> {
+    temp.set_permissions(file.get_ref().metadata()?.permissions())?;
+
+    let compaction = qcow.compact(file, temp)?;
+    temp.sync_all()?;
+
+    Ok(compaction)
+}
@@ -0,0 +1,299 @@
+use crate::map::MapCursor;
+use crate::v1::Qcow1Reader;
+use crate::*;
+
+use std::io::{self, Read, Seek};
+use std::ops::Range;
+
+/// Options for comparing the guest contents of two images, for use with
+/// [`DynamicQcow::compare`] or [`compare`].
+///
+/// ## Example
+///
+/// ```rust
+/// use qcow::CompareOptions;
+///
+/// let options = CompareOptions {
+///     first_only: true,
+///     ..CompareOptions::default()
+/// };
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CompareOptions {
+    /// Stop once the first differing range has been found rather than finding every differing
+    /// range. Defaults to false.
+    pub first_only: bool,
+
+    /// Treat the smaller guest as if it were extended with zeroes to the size of the larger
+    /// one, so images of different sizes are identical if the extra part of the larger guest
+    /// reads as zeroes. Otherwise the extra part always differs. Defaults to false.
+    pub zero_extend: bool,
+}
+
+/// The result of comparing the guest contents of two images, as returned by
+/// [`DynamicQcow::compare`] and [`compare`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Comparison {
+    /// Ranges of the guest whose contents differ between the images, sorted by offset. Each
+    /// range is as short as possible, starting and ending at a differing byte.
+    pub differences: Vec<Range<u64>>,
+}
+
+impl Comparison {
+    /// Returns true if the guest contents of the images are identical
+    pub fn is_identical(&self) -> bool {
+        self.differences.is_empty()
+    }
+}
+
+/// A reader for the guest of either version of qcow
+pub(crate) enum GuestReader<'qcow, 'reader, R>
+where
+    R: Read + Seek,
+{
+    Qcow1(Qcow1Reader<'qcow, 'reader, R>),
+    Qcow2 {
+        reader: Reader<'qcow, 'reader, R>,
+
+        /// position within the allocation map of the extent following the one last returned
+        /// by [`GuestReader::extent_at`]
+        map: MapCursor,
+    },
+}
+
+impl<'qcow, 'reader, R> GuestReader<'qcow, 'reader, R>
+where
+    R: Read + Seek,
+{
+    pub(crate) fn guest_size(&self) -> u64 {
+        match self {
+            Self::Qcow1(reader) => reader.guest_size(),
+            Self::Qcow2 { reader, .. } => reader.guest_size(),
+        }
+    }
+
+    /// Get the end of the part of the guest starting at `pos` which is stored the same way, and
+    /// whether that part is known to read as zeroes without reading it. Version 1 images don't
+    /// provide any allocation information, so are read in full.
+    ///
+    /// The guest is looked up in order, so `pos` must be at or past the end returned by the
+    /// previous call.
+    pub(crate) fn extent_at(&mut self, pos: u64) -> Result<(u64, bool), Error> {
+        let guest_size = self.guest_size();
+        if pos >= guest_size {
+            return Ok((u64::MAX, true));
+        }
+
+        match self {
+            Self::Qcow1(_) => Ok((guest_size, false)),
+            Self::Qcow2 { reader, map } => loop {
+                let extent = match map.next_extent(reader) {
+                    Some(extent) => extent?,
+                    None => return Ok((u64::MAX, true)),
+                };
+                if extent.end() > pos {
+                    let is_zero = matches!(extent.kind, ExtentKind::Zero | ExtentKind::Unallocated);
+
+                    return Ok((extent.end(), is_zero));
+                }
+            },
+        }
+    }
+
+    pub(crate) fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> io::Result<()> {
+        match self {
+            Self::Qcow1(reader) => {
+                reader.seek(SeekFrom::Start(pos))?;
+                reader.read_exact(buf)
+            }
+            Self::Qcow2 { reader, .. } => {
+                reader.seek(SeekFrom::Start(pos))?;
+                reader.read_exact(buf)
+            }
+        }
+    }
+}
+
+impl DynamicQcow {
+    /// Create a reader for the guest of the qcow, whatever its version
+    pub(crate) fn guest_reader<'qcow, 'reader, R>(
+        &'qcow self,
+        reader: &'reader mut R,
+    ) -> GuestReader<'qcow, 'reader, R>
+    where
+        R: Read + Seek,
+    {
+        match self {
+            Self::Qcow1(qcow) => GuestReader::Qcow1(qcow.reader(reader)),
+            Self::Qcow2(qcow) => GuestReader::Qcow2 {
+                reader: qcow.reader(reader),
+                map: MapCursor::new(0),
+            },
+        }
+    }
+
+    /// Compare the contents of the guest virtual drive with that of `other`, including anything
+    /// supplied by either backing chain, returning the ranges of the guest which differ.
+    /// `reader` must be the source file of this qcow, and `other_reader` the source file of
+    /// `other`.
+    ///
+    /// Ranges which are unallocated or zeroed in both images (and their backing chains) are
+    /// skipped without being read. Everything else is read from both images and compared byte
+    /// by byte, so data which happens to be identical is not a difference even if it is stored
+    /// differently.
+    ///
+    /// See [`compare`] for comparing images on the filesystem.
+    ///
+    /// ## Example
+    ///
+    /// ```rust
+    /// use std::io::{Cursor, Seek, SeekFrom, Write};
+    /// use qcow::{CompareOptions, CreateOptions, DynamicQcow};
+    ///
+    /// let mut file = Cursor::new(Vec::new());
+    /// let qcow = qcow::create(&mut file, &CreateOptions::new(16 << 20))?;
+    ///
+    /// let mut other_file = Cursor::new(Vec::new());
+    /// let mut other = qcow::create(&mut other_file, &CreateOptions::new(16 << 20))?;
+    /// let mut writer = other.writer(&mut other_file)?;
+    /// writer.seek(SeekFrom::Start(0x12345))?;
+    /// writer.write_all(b"needle")?;
+    /// writer.flush()?;
+    /// drop(writer);
+    ///
+    /// let comparison = DynamicQcow::Qcow2(qcow).compare(
+    ///     &mut file,
+    ///     &DynamicQcow::Qcow2(other),
+    ///     &mut other_file,
+    ///     &CompareOptions::default(),
+    /// )?;
+    /// assert_eq!(comparison.differences, [0x12345..0x1234b]);
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn compare<R1, R2>(
+        &self,
+        reader: &mut R1,
+        other: &DynamicQcow,
+        other_reader: &mut R2,
+        options: &CompareOptions,
+    ) -> Result<Comparison, Error>
+    where
+        R1: Read + Seek,
+        R2: Read + Seek,
+    {
+        let mut reader = self.guest_reader(reader);
+        let mut other_reader = other.guest_reader(other_reader);
+
+        let size = reader.guest_size();
+        let other_size = other_reader.guest_size();
+        let end = if options.zero_extend {
+            u64::max(size, other_size)
+        } else {
+            u64::min(size, other_size)
+        };
+
+        let chunk_size = u64::max(self.cluster_size(), other.cluster_size());
+        let mut buf = vec![0; chunk_size as usize];
+        let mut other_buf = vec![0; chunk_size as usize];
+        let mut differences: Vec<Range<u64>> = Vec::new();
+
+        let mut extent = (0, false);
+        let mut other_extent = (0, false);
+        let mut pos = 0;
+        while pos < end {
+            // the first difference is complete once something identical follows it
+            if options.first_only && differences.last().is_some_and(|diff| diff.end < pos) {
+                break;
+            }
+
+            if pos >= extent.0 {
+                extent = reader.extent_at(pos)?;
+            }
+            if pos >= other_extent.0 {
+                other_extent = other_reader.extent_at(pos)?;
+            }
+
+            let extent_end = u64::min(extent.0, other_extent.0).min(end);
+            if extent.1 && other_extent.1 {
+                pos = extent_end;
+                continue;
+            }
+
+            let len = u64::min(extent_end, pos + chunk_size) - pos;
+            let buf = &mut buf[..len as usize];
+            let other_buf = &mut other_buf[..len as usize];
+            if extent.1 {
+                buf.fill(0);
+            } else {
+                reader.read_at(pos, buf)?;
+            }
+            if other_extent.1 {
+                other_buf.fill(0);
+            } else {
+                other_reader.read_at(pos, other_buf)?;
+            }
+
+            if buf != other_buf {
+                let differing = buf.iter().zip(other_buf.iter()).enumerate();
+                for (i, _) in differing.filter(|(_, (byte, other_byte))| byte != other_byte) {
+                    let offset = pos + i as u64;
+                    push_difference(&mut differences, offset..offset + 1);
+                }
+            }
+
+            pos += len;
+        }
+
+        // without zero extension, the part of the larger guest past the end of the smaller one
+        // always differs
+        let first_found = options.first_only && !differences.is_empty();
+        if size != other_size && !options.zero_extend && !first_found {
+            push_difference(&mut differences, end..u64::max(size, other_size));
+        }
+
+        if options.first_only {
+            differences.truncate(1);
+        }
+
+        Ok(Comparison { differences })
+    }
+}
+
+/// Add `range` to the sorted list of differing ranges, extending the last range if `range`
+/// directly follows it
+fn push_difference(differences: &mut Vec<Range<u64>>, range: Range<u64>) {
+    match differences.last_mut() {
+        Some(last) if last.end == range.start => last.end = range.end,
+        _ => differences.push(range),
+    }
+}
+
+/// Compare the guest contents of the images at `path` and `other_path`, including anything
+/// supplied by their backing chains, returning the ranges of the guest which differ.
+///
+/// See [`DynamicQcow::compare`] for more details.
+///
+/// ## Example
+///
+/// ```rust,no_run
+/// use qcow::CompareOptions;
+///
+/// let comparison = qcow::compare("before.qcow2", "after.qcow2", &CompareOptions::default())?;
+/// for range in &comparison.differences {
+///     println!("{:#x}-{:#x} differs", range.start, range.end);
+/// }
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn compare(
+    path: impl AsRef<Path>,
+    other_path: impl AsRef<Path>,
+    options: &CompareOptions,
+) -> Result<Comparison, Error> {
+    let mut file = BufReader::new(File::open(path).map_err(Error::FileNotFound)?);
+    let qcow = load(&mut file)?;
+
+    let mut other_file = BufReader::new(File::open(other_path).map_err(Error::FileNotFound)?);
+    let other = load(&mut other_file)?;
+
+    qcow.compare(&mut file, &other, &mut other_file, options)
+}
@@ -0,0 +1,185 @@
+//! Writing of compressed clusters, packed together at sector granularity.
+use crate::levels::L2Entry;
+use crate::refcount::Refcounts;
+use crate::*;
+
+use flate2::{Compress, Compression, FlushCompress, Status};
+use std::io::{self, Read, Seek, Write};
+
+/// Size of the sectors compressed clusters are packed into
+const SECTOR_SIZE: u64 = 512;
+
+/// Window size used for zlib compression, as qemu decompresses using a 4 KiB window
+const ZLIB_WINDOW_BITS: u8 = 12;
+
+impl CompressionType {
+    /// Compress the contents of a single cluster
+    pub(crate) fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Zlib => {
+                let mut compress =
+                    Compress::new_with_window_bits(Compression::default(), false, ZLIB_WINDOW_BITS);
+
+                // incompressible data can grow slightly, leave room for the worst case
+                let mut compressed = Vec::with_capacity(data.len() + (data.len() / 8) + 64);
+                match compress.compress_vec(data, &mut compressed, FlushCompress::Finish)? {
+                    Status::StreamEnd => Ok(compressed),
+                    _ => Err(io::Error::other(
+                        "compressed cluster exceeded the output buffer",
+                    )),
+                }
+            }
+            Self::Zstd => zstd::encode_all(data, 0),
+        }
+    }
+}
+
+impl<'qcow, 'file, F> Writer<'qcow, 'file, F>
+where
+    F: Read + Write + Seek,
+{
+    /// Compress clusters using `compression` when writing them, or write them uncompressed if
+    /// `None` (the default). Clusters which don't compress to less than a cluster are always
+    /// written uncompressed.
+    ///
+    /// As every compressed cluster in an image must use the compression type given by the
+    /// header, the compression type can only be changed for images without any compressed
+    /// clusters. Compression types other than zlib require a version 3 image, and set the
+    /// [`has_compression_type`](IncompatibleFeatures::has_compression_type) incompatible
+    /// feature bit.
+    ///
+    /// ## Example
+    ///
+    /// ```rust
+    /// use std::io::{Cursor, Write};
+    /// use qcow::{CompressionType, CreateOptions};
+    ///
+    /// let mut file = Cursor::new(Vec::new());
+    /// let mut qcow = qcow::create(&mut file, &CreateOptions::new(16 << 20))?;
+    ///
+    /// let mut writer = qcow.writer(&mut file)?;
+    /// writer.set_compression(Some(CompressionType::Zstd))?;
+    /// writer.write_all(&[0x55; 0x20000])?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn set_compression(&mut self, compression: Option<CompressionType>) -> Result<(), Error> {
+        let compression_type = match compression {
+            Some(compression_type) => compression_type,
+            None => {
+                self.compress = false;
+                return Ok(());
+            }
+        };
+
+        if compression_type != self.qcow.header.compression_type() {
+            // version 2 images always use zlib, so any other compression type is unsupported
+            if self.qcow.header.v3_header.is_none() {
+                return Err(Error::UnsupportedFeature(
+                    "compression types other than zlib in version 2 images",
+                ));
+            }
+
+            if self.any_l2_entry(|entry| entry.is_compressed)? {
+                return Err(Error::UnsupportedFeature(
+                    "changing the compression type of an image with compressed clusters",
+                ));
+            }
+
+            let v3_header = self.qcow.header.v3_header.as_mut().unwrap();
+            v3_header.compression_type = compression_type;
+            v3_header
+                .incompatible_features
+                .set_has_compression_type(compression_type != CompressionType::Zlib);
+            self.header_dirty = true;
+        }
+
+        self.compress = true;
+
+        Ok(())
+    }
+
+    /// Write the full contents of the given guest cluster in compressed form, falling back to
+    /// writing it uncompressed if it does not compress to less than a cluster.
+    pub(crate) fn write_compressed_cluster(
+        &mut self,
+        guest_cluster: u64,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        let cluster_size = self.cluster_size();
+        let compressed = self.qcow.header.compression_type().compress(data)?;
+        if compressed.len() as u64 >= cluster_size {
+            return self.write_cluster(guest_cluster, data);
+        }
+
+        // the L2 table is allocated first so it doesn't break up the packed data
+        let l2_entries = self.qcow.l2_entries();
+        let old_entry =
+            self.l2_table_mut(guest_cluster / l2_entries)?[(guest_cluster % l2_entries) as usize]
+                .clone();
+
+        let host_offset = allocate_compressed(
+            &mut self.refcounts,
+            &mut self.compressed_offset,
+            cluster_size,
+            compressed.len() as u64,
+        )?;
+        self.file.seek(SeekFrom::Start(host_offset))?;
+        self.file.write_all(&compressed)?;
+
+        self.release(&old_entry)?;
+
+        let additional_sector_count = (compressed.len() as u64).div_ceil(SECTOR_SIZE) - 1;
+        self.set_l2_entry(
+            guest_cluster,
+            L2Entry::compressed(host_offset, additional_sector_count),
+        )
+    }
+}
+
+/// Allocate sectors for `len` bytes of compressed data, following on from `compressed_offset`
+/// (the end of the previously packed compressed cluster, or 0 if there is none) if the space
+/// after it is free. Every host cluster the sectors overlap gains a reference, and
+/// `compressed_offset` is moved past the allocated sectors.
+pub(crate) fn allocate_compressed(
+    refcounts: &mut Refcounts,
+    compressed_offset: &mut u64,
+    cluster_size: u64,
+    len: u64,
+) -> Result<u64, Error> {
+    let len = len.div_ceil(SECTOR_SIZE) * SECTOR_SIZE;
+
+    let offset = *compressed_offset;
+    let offset = if offset != 0 && can_extend(refcounts, offset, cluster_size, len) {
+        let first_cluster = offset & !(cluster_size - 1);
+        let last_cluster = (offset + len - 1) & !(cluster_size - 1);
+        for cluster in (first_cluster..=last_cluster).step_by(cluster_size as usize) {
+            refcounts.increment(cluster)?;
+        }
+
+        offset
+    } else {
+        refcounts.allocate(len.div_ceil(cluster_size))?
+    };
+
+    // a cluster boundary means there is no partially filled cluster to continue from
+    let end = offset + len;
+    *compressed_offset = if end & (cluster_size - 1) == 0 {
+        0
+    } else {
+        end
+    };
+
+    Ok(offset)
+}
+
+/// Check whether `len` bytes of compressed data can be packed at `offset`, directly following
+/// the previous compressed cluster
+fn can_extend(refcounts: &Refcounts, offset: u64, cluster_size: u64, len: u64) -> bool {
+    let first_cluster = offset & !(cluster_size - 1);
+    let last_cluster = (offset + len - 1) & !(cluster_size - 1);
+
+    refcounts.get(first_cluster) < refcounts.max_refcount()
+        && ((first_cluster + cluster_size)..=last_cluster)
+            .step_by(cluster_size as usize)
+            .all(|cluster| refcounts.is_free(cluster))
+}
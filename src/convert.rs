@@ -0,0 +1,233 @@
+use crate::*;
+
+use std::fs::OpenOptions;
+use std::io::{self, Read, Seek, Write};
+use std::ops::Range;
+
+impl DynamicQcow {
+    /// Write the contents of the guest virtual drive, including anything supplied by the
+    /// backing chain, to `output` as a raw disk image. Returns the number of bytes written.
+    ///
+    /// Only the clusters allocated as data somewhere in the backing chain are read, as found
+    /// from the [allocation map](Reader::allocation_map). Unallocated and zero clusters (and
+    /// any data clusters which only contain zeroes) are skipped over by seeking rather than
+    /// written, so that `output` stays sparse when it is a file on a filesystem supporting
+    /// sparse files. Version 1 images have no allocation map, so every cluster is read. `reader`
+    /// must be the source file of the qcow.
+    ///
+    /// See [`convert_to_raw`] for converting a qcow on the filesystem.
+    pub fn convert_to_raw<R, W>(&self, reader: &mut R, output: &mut W) -> Result<u64, Error>
+    where
+        R: Read + Seek,
+        W: Write + Seek,
+    {
+        let chunk_size = self.cluster_size();
+        let written = match self {
+            Self::Qcow2(qcow) => {
+                let mut reader = qcow.reader(reader);
+                let mut data = Vec::new();
+                for extent in reader.allocation_map() {
+                    let extent = extent?;
+                    if matches!(extent.kind, ExtentKind::Data | ExtentKind::Compressed) {
+                        data.push(extent.start..extent.end());
+                    }
+                }
+
+                copy_sparse(&mut reader, output, data, qcow.header.size, chunk_size)?
+            }
+            Self::Qcow1(qcow) => {
+                let mut reader = qcow.reader(reader);
+                let size = qcow.header.size;
+                copy_sparse(&mut reader, output, Some(0..size), size, chunk_size)?
+            }
+        };
+
+        Ok(written)
+    }
+}
+
+/// Copy each of the `ranges` of `source` to the same offsets within `output` in chunks of
+/// `chunk_size`, seeking over any chunks which are entirely zero, then extend `output` to
+/// `size` bytes. Returns the number of bytes written.
+fn copy_sparse(
+    source: &mut (impl Read + Seek),
+    output: &mut (impl Write + Seek),
+    ranges: impl IntoIterator<Item = Range<u64>>,
+    size: u64,
+    chunk_size: u64,
+) -> io::Result<u64> {
+    let mut chunk = vec![0; chunk_size as usize];
+    let mut written = 0;
+    let mut output_pos = output.seek(SeekFrom::Start(0))?;
+
+    for range in ranges {
+        let mut pos = range.start;
+        source.seek(SeekFrom::Start(pos))?;
+
+        while pos < range.end {
+            let len = u64::min(chunk_size, range.end - pos) as usize;
+            let chunk = &mut chunk[..len];
+            source.read_exact(chunk)?;
+
+            if chunk.iter().any(|&byte| byte != 0) {
+                if output_pos != pos {
+                    output_pos = output.seek(SeekFrom::Start(pos))?;
+                }
+
+                output.write_all(chunk)?;
+                output_pos += len as u64;
+                written += len as u64;
+            }
+
+            pos += len as u64;
+        }
+    }
+
+    // extend the output to the full size of the guest if it ends with zeroes
+    if output_pos != size && size != 0 {
+        output.seek(SeekFrom::Start(size - 1))?;
+        output.write_all(&[0])?;
+    }
+
+    output.flush()?;
+
+    Ok(written)
+}
+
+/// Convert the qcow at `path` into a sparse raw disk image at `output`, including the contents
+/// of its backing chain. Returns the number of bytes written to `output`.
+///
+/// `output` is created if it does not exist, and truncated if it does.
+///
+/// ## Example
+///
+/// ```rust,no_run
+/// let written = qcow::convert_to_raw("experiment.qcow2", "experiment.img")?;
+/// println!("Wrote {} bytes of data", written);
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn convert_to_raw(path: impl AsRef<Path>, output: impl AsRef<Path>) -> Result<u64, Error> {
+    let mut file = BufReader::new(File::open(path).map_err(Error::FileNotFound)?);
+    let qcow = load(&mut file)?;
+
+    let mut output = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(output)?;
+
+    qcow.convert_to_raw(&mut file, &mut output)
+}
+
+/// Options for importing a raw disk image into a new qcow2 image, for use with
+/// [`Qcow2::import_raw`] or [`import_raw`].
+#[derive(Debug, Clone)]
+pub struct ImportOptions {
+    /// Number of bits used for addressing within a cluster (1 << cluster_bits is the cluster
+    /// size). Defaults to 16 (64 KiB clusters).
+    pub cluster_bits: u32,
+
+    /// Version of the qcow2 format, either 2 or 3. Defaults to 3.
+    pub version: u32,
+
+    /// Compression to use for every cluster, or `None` to store clusters uncompressed. Zstd
+    /// requires a version 3 image. Defaults to `None`.
+    pub compression: Option<CompressionType>,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        Self {
+            cluster_bits: 16,
+            version: 3,
+            compression: None,
+        }
+    }
+}
+
+impl Qcow2 {
+    /// Create a new qcow2 image in `output` from the raw disk image `source`, returning the
+    /// parsed image. `output` should be empty, and must be readable and writable.
+    ///
+    /// Clusters of the raw image which are entirely zero are left unallocated, and all other
+    /// clusters are written in order, so the resulting image contains no unused space.
+    pub fn import_raw<R, W>(
+        source: &mut R,
+        output: &mut W,
+        options: &ImportOptions,
+    ) -> Result<Qcow2, Error>
+    where
+        R: Read + Seek,
+        W: Read + Write + Seek,
+    {
+        let size = source.seek(SeekFrom::End(0))?;
+        source.seek(SeekFrom::Start(0))?;
+
+        let mut qcow = create(
+            output,
+            &CreateOptions {
+                cluster_bits: options.cluster_bits,
+                version: options.version,
+                compression_type: options.compression.unwrap_or_default(),
+                ..CreateOptions::new(size)
+            },
+        )?;
+
+        let mut writer = qcow.writer(output)?;
+        let cluster_size = writer.cluster_size();
+        let mut cluster = vec![0; cluster_size as usize];
+
+        for guest_cluster in 0..size.div_ceil(cluster_size) {
+            let len = u64::min(cluster_size, size - (guest_cluster * cluster_size)) as usize;
+            cluster[len..].fill(0);
+            source.read_exact(&mut cluster[..len])?;
+
+            if cluster.iter().all(|&byte| byte == 0) {
+                continue;
+            }
+
+            match options.compression {
+                Some(_) => writer.write_compressed_cluster(guest_cluster, &cluster)?,
+                None => writer.write_cluster(guest_cluster, &cluster)?,
+            }
+        }
+
+        writer.sync()?;
+        drop(writer);
+
+        Ok(qcow)
+    }
+}
+
+/// Import the raw disk image at `path` into a new qcow2 image at `output`, returning the parsed
+/// image.
+///
+/// `output` is created if it does not exist, and truncated if it does.
+///
+/// ## Example
+///
+/// ```rust,no_run
+/// use qcow::{CompressionType, ImportOptions};
+///
+/// let options = ImportOptions {
+///     compression: Some(CompressionType::Zstd),
+///     ..Default::default()
+/// };
+/// qcow::import_raw("disk.img", "disk.qcow2", &options)?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn import_raw(
+    path: impl AsRef<Path>,
+    output: impl AsRef<Path>,
+    options: &ImportOptions,
+) -> Result<Qcow2, Error> {
+    let mut source = BufReader::new(File::open(path).map_err(Error::FileNotFound)?);
+    let mut output = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(output)?;
+
+    Qcow2::import_raw(&mut source, &mut output, options)
+}
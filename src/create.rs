@@ -0,0 +1,373 @@
+use crate::refcount::Refcounts;
+use crate::*;
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+
+/// Options describing a new qcow2 image, for use with [`create`].
+///
+/// ## Example
+///
+/// ```rust
+/// use qcow::{CompressionType, CreateOptions};
+///
+/// // a 1 GiB image with 4 KiB clusters, compressing with zstd
+/// let options = CreateOptions {
+///     cluster_bits: 12,
+///     compression_type: CompressionType::Zstd,
+///     ..CreateOptions::new(1 << 30)
+/// };
+/// ```
+#[derive(Debug, Clone)]
+pub struct CreateOptions {
+    /// Size of the guest virtual hard disk in bytes
+    pub size: u64,
+
+    /// Number of bits used for addressing within a cluster (1 << cluster_bits is the cluster
+    /// size). Must be between 9 and 21. Defaults to 16 (64 KiB clusters).
+    pub cluster_bits: u32,
+
+    /// Version of the qcow2 format, either 2 or 3. Defaults to 3.
+    pub version: u32,
+
+    /// Width of each refcount entry as a power of two number of bits. Must be 4 for version 2
+    /// images. Defaults to 4 (16-bit refcounts).
+    pub refcount_order: u32,
+
+    /// Compression type used for compressed clusters. Zstd requires a version 3 image. Defaults
+    /// to [`CompressionType::Zlib`].
+    pub compression_type: CompressionType,
+
+    /// Backing file for any clusters not allocated in the image
+    pub backing_file: Option<String>,
+
+    /// Format of the backing file, recorded in the
+    /// [`BackingFileFormat`](header_ext::HeaderExt::BackingFileFormat) header extension
+    pub backing_format: Option<BackingFormat>,
+
+    /// How much of the guest is allocated up front. Can't be combined with a backing file, as
+    /// preallocated clusters hide it. Defaults to [`Preallocation::Off`].
+    pub preallocation: Preallocation,
+
+    /// Mark preallocated clusters with the
+    /// [`all_zeroes`](StandardClusterDescriptor::all_zeroes) flag, so they read as zeroes
+    /// without reading the image file while keeping their host clusters. Requires a version 3
+    /// image and preallocation. Defaults to false.
+    pub zero_preallocated: bool,
+}
+
+impl CreateOptions {
+    /// Create the default options for an image with a guest of `size` bytes
+    pub fn new(size: u64) -> Self {
+        Self {
+            size,
+            cluster_bits: 16,
+            version: 3,
+            refcount_order: 4,
+            compression_type: CompressionType::Zlib,
+            backing_file: None,
+            backing_format: None,
+            preallocation: Preallocation::Off,
+            zero_preallocated: false,
+        }
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), &'static str> {
+        if !(9..=21).contains(&self.cluster_bits) {
+            return Err("cluster_bits must be between 9 and 21");
+        }
+
+        if self.version != 2 && self.version != 3 {
+            return Err("version must be either 2 or 3");
+        }
+
+        if self.refcount_order > 6 {
+            return Err("refcount_order must not exceed 6");
+        }
+
+        if self.version == 2 && self.refcount_order != 4 {
+            return Err("version 2 images only support a refcount_order of 4");
+        }
+
+        if self.version == 2 && self.compression_type != CompressionType::Zlib {
+            return Err("version 2 images only support zlib compression");
+        }
+
+        if self.backing_file.is_none() && self.backing_format.is_some() {
+            return Err("a backing format requires a backing file");
+        }
+
+        if self.backing_file.is_some() && self.preallocation != Preallocation::Off {
+            return Err("preallocation can't be combined with a backing file");
+        }
+
+        if self.zero_preallocated && self.preallocation == Preallocation::Off {
+            return Err("marking preallocated clusters as zeroes requires preallocation");
+        }
+
+        if self.version == 2 && self.zero_preallocated {
+            return Err("version 2 images don't support zero clusters");
+        }
+
+        Ok(())
+    }
+}
+
+/// How much of the guest is allocated up front when creating an image, trading a larger image
+/// file for avoiding allocation while the guest is written to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Preallocation {
+    /// Nothing is allocated, so clusters are allocated as they are written
+    #[default]
+    Off,
+
+    /// Every L2 table and a host cluster for every guest cluster are allocated, but the host
+    /// clusters aren't written to
+    Metadata,
+
+    /// As with [`Preallocation::Metadata`], with the host clusters also reserved within the
+    /// filesystem holding the image file. Reserving space requires [`create_file`] on Linux,
+    /// [`create`] only extends the image file as with [`Preallocation::Metadata`].
+    Falloc,
+
+    /// As with [`Preallocation::Metadata`], with zeroes written to every host cluster
+    Full,
+}
+
+/// Create a new qcow2 image in `file`, returning the parsed image. `file` should be empty, and
+/// must be readable and writable.
+///
+/// Nothing is allocated in the guest unless [`CreateOptions::preallocation`] is set, in which
+/// case every L2 table is followed by the host clusters it describes, in guest order. The
+/// resulting image can be written to using [`Qcow2::writer`].
+///
+/// ## Example
+///
+/// ```rust
+/// use std::io::{Cursor, Write};
+/// use qcow::CreateOptions;
+///
+/// let mut file = Cursor::new(Vec::new());
+/// let mut qcow = qcow::create(&mut file, &CreateOptions::new(16 << 20))?;
+///
+/// let mut writer = qcow.writer(&mut file)?;
+/// writer.write_all(b"hello")?;
+/// writer.flush()?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn create<F>(file: &mut F, options: &CreateOptions) -> Result<Qcow2, Error>
+where
+    F: Read + Write + Seek,
+{
+    options
+        .validate()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+    let cluster_size = 1u64 << options.cluster_bits;
+    let l2_entries = cluster_size / 8;
+    let l1_size = options.size.div_ceil(cluster_size * l2_entries).max(1);
+    let l1_clusters = (l1_size * 8).div_ceil(cluster_size);
+
+    let mut refcounts = Refcounts::empty(options.cluster_bits, options.refcount_order);
+    refcounts.allocate(1)?;
+    let l1_table_offset = refcounts.allocate(l1_clusters)?;
+
+    file.seek(SeekFrom::Start(l1_table_offset))?;
+    file.write_all(&vec![0; (l1_clusters * cluster_size) as usize])?;
+
+    refcounts.flush(file)?;
+
+    let mut l1_table = vec![L1Entry::from_u64(0); l1_size as usize];
+    if options.preallocation != Preallocation::Off {
+        preallocate(file, options, &mut refcounts, &mut l1_table)?;
+
+        let l1_bytes: Vec<u8> = l1_table
+            .iter()
+            .flat_map(|entry| entry.to_u64().to_be_bytes())
+            .collect();
+        file.seek(SeekFrom::Start(l1_table_offset))?;
+        file.write_all(&l1_bytes)?;
+
+        refcounts.flush(file)?;
+    }
+    let (refcount_table_offset, refcount_table_clusters) = refcounts.table_location();
+
+    let v3_header = (options.version == 3).then(|| {
+        let has_compression_type = options.compression_type != CompressionType::Zlib;
+        Version3Header {
+            incompatible_features: IncompatibleFeatures::new()
+                .with_has_compression_type(has_compression_type),
+            compatible_features: CompatibleFeatures::new(),
+            autoclear_features: AutoClearFeatures::new(),
+            refcount_order: options.refcount_order,
+            header_len: V3_HEADER_LEN,
+            compression_type: options.compression_type,
+        }
+    });
+
+    let extensions = options
+        .backing_format
+        .iter()
+        .map(|format| header_ext::HeaderExt::BackingFileFormat(format.name().to_owned()))
+        .collect();
+
+    let mut header = QcowHeader {
+        version: options.version,
+        backing_file: options.backing_file.clone(),
+        cluster_bits: options.cluster_bits,
+        size: options.size,
+        crypt_method: EncryptionMethod::None,
+        l1_size: l1_size as u32,
+        l1_table_offset,
+        refcount_table_offset,
+        refcount_table_clusters,
+        nb_snapshots: 0,
+        snapshots_offset: 0,
+        v3_header,
+        extensions,
+    };
+
+    // the header always occupies the first cluster, so make sure it is entirely present
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&vec![0; cluster_size as usize])?;
+    crate::writer::write_header(&mut header, file)?;
+    refcounts.flush_frees(file)?;
+    file.flush()?;
+
+    Ok(Qcow2 {
+        header,
+        snapshots: Vec::new(),
+        l1_table,
+    })
+}
+
+/// Allocate an L2 table and a host cluster for every guest cluster, filling in `l1_table`
+fn preallocate<F>(
+    file: &mut F,
+    options: &CreateOptions,
+    refcounts: &mut Refcounts,
+    l1_table: &mut [L1Entry],
+) -> Result<(), Error>
+where
+    F: Write + Seek,
+{
+    let cluster_bits = options.cluster_bits;
+    let cluster_size = 1u64 << cluster_bits;
+    let l2_entries = cluster_size / 8;
+    let guest_clusters = options.size.div_ceil(cluster_size);
+    let zeroes = vec![0; cluster_size as usize];
+    let mut data_end = 0;
+
+    for (l1_index, l1_entry) in l1_table.iter_mut().enumerate() {
+        let first_cluster = l1_index as u64 * l2_entries;
+        if first_cluster >= guest_clusters {
+            break;
+        }
+
+        let count = u64::min(l2_entries, guest_clusters - first_cluster);
+        let l2_offset = refcounts.allocate(1)?;
+        let data_offset = refcounts.allocate(count)?;
+
+        let mut table = Vec::with_capacity(cluster_size as usize);
+        for index in 0..l2_entries {
+            let entry = if index < count {
+                L2Entry {
+                    cluster_descriptor: ClusterDescriptor::Standard(StandardClusterDescriptor {
+                        all_zeroes: options.zero_preallocated,
+                        host_cluster_offset: data_offset + (index * cluster_size),
+                    }),
+                    is_compressed: false,
+                    is_used: true,
+                }
+            } else {
+                L2Entry::unallocated()
+            };
+            table.extend_from_slice(&entry.to_u64(cluster_bits).to_be_bytes());
+        }
+
+        file.seek(SeekFrom::Start(l2_offset))?;
+        file.write_all(&table)?;
+        *l1_entry = L1Entry {
+            l2_offset,
+            is_used: true,
+        };
+
+        if options.preallocation == Preallocation::Full {
+            file.seek(SeekFrom::Start(data_offset))?;
+            for _ in 0..count {
+                file.write_all(&zeroes)?;
+            }
+        }
+
+        data_end = data_offset + (count * cluster_size);
+    }
+
+    // the data clusters are otherwise left unwritten, so make sure the image file covers them
+    if options.preallocation != Preallocation::Full && data_end != 0 {
+        file.seek(SeekFrom::Start(data_end - 1))?;
+        file.write_all(&[0])?;
+    }
+
+    Ok(())
+}
+
+/// Create a new qcow2 image at `path`, returning the parsed image. The file is created if it
+/// does not exist, and truncated if it does.
+///
+/// Unlike [`create`], [`Preallocation::Falloc`] reserves space for the whole image file within
+/// the filesystem, which is only supported on Linux.
+///
+/// ## Example
+///
+/// ```rust,no_run
+/// use qcow::{CreateOptions, Preallocation};
+///
+/// let options = CreateOptions {
+///     preallocation: Preallocation::Falloc,
+///     zero_preallocated: true,
+///     ..CreateOptions::new(8 << 30)
+/// };
+/// let qcow = qcow::create_file("benchmark.qcow2", &options)?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn create_file(path: impl AsRef<Path>, options: &CreateOptions) -> Result<Qcow2, Error> {
+    if options.preallocation == Preallocation::Falloc && !cfg!(target_os = "linux") {
+        return Err(Error::UnsupportedFeature(
+            "falloc preallocation on this platform",
+        ));
+    }
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+
+    let qcow = create(&mut file, options)?;
+    if options.preallocation == Preallocation::Falloc {
+        reserve(&file)?;
+    }
+
+    Ok(qcow)
+}
+
+/// Reserve space within the filesystem for the whole of `file`
+#[cfg(target_os = "linux")]
+fn reserve(file: &File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let len = file.metadata()?.len();
+    match unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, len as libc::off_t) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reserve(_file: &File) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "reserving space is only supported on Linux",
+    ))
+}
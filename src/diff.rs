@@ -0,0 +1,198 @@
+use crate::*;
+
+use std::fs::OpenOptions;
+use std::io::{self, Read, Seek, Write};
+
+/// Options for the overlay written by [`DynamicQcow::export_diff`] or [`export_diff`].
+///
+/// ## Example
+///
+/// ```rust
+/// use qcow::{CompressionType, DiffOptions};
+///
+/// let options = DiffOptions {
+///     compression: Some(CompressionType::Zlib),
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Debug, Clone)]
+pub struct DiffOptions {
+    /// Number of bits used for addressing within a cluster of the overlay (1 << cluster_bits is
+    /// the cluster size). Defaults to 16 (64 KiB clusters).
+    pub cluster_bits: u32,
+
+    /// Version of the qcow2 format of the overlay, either 2 or 3. Defaults to 3.
+    pub version: u32,
+
+    /// Compression to use for every cluster of the overlay, or `None` to store clusters
+    /// uncompressed. Zstd requires a version 3 image. Defaults to `None`.
+    pub compression: Option<CompressionType>,
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        Self {
+            cluster_bits: 16,
+            version: 3,
+            compression: None,
+        }
+    }
+}
+
+impl DynamicQcow {
+    /// Create a new qcow2 overlay in `output` on top of `base`, containing only the clusters in
+    /// which the guest of this qcow differs from that of `base`, so that the guest of the
+    /// overlay has the same contents as this qcow. Returns the parsed overlay.
+    ///
+    /// `reader` must be the source file of this qcow, and `base_reader` the source file of
+    /// `base`, which must be a qcow2 image as version 1 images can't be read as backing files.
+    /// `backing_file` is the name of `base` stored in the header of the overlay, and `output`
+    /// should be empty, and must be readable and writable. The guest of the overlay is the size
+    /// of this qcow's guest, and anything past the end of `base` is compared as zeroes.
+    ///
+    /// The guests are compared as in [`DynamicQcow::compare`], and each overlay cluster
+    /// containing a difference is copied from this qcow in full. Clusters which differ by
+    /// reading as zeroes are marked as zero clusters in version 3 overlays.
+    ///
+    /// See [`export_diff`] for exporting the difference between images on the filesystem.
+    pub fn export_diff<R, B, W>(
+        &self,
+        reader: &mut R,
+        base: &DynamicQcow,
+        base_reader: &mut B,
+        backing_file: &str,
+        output: &mut W,
+        options: &DiffOptions,
+    ) -> Result<Qcow2, Error>
+    where
+        R: Read + Seek,
+        B: Read + Seek,
+        W: Read + Write + Seek,
+    {
+        check_base(base)?;
+
+        let compare_options = CompareOptions {
+            first_only: false,
+            zero_extend: true,
+        };
+        let differences = self.compare(reader, base, base_reader, &compare_options)?;
+
+        let mut reader = self.guest_reader(reader);
+        let size = reader.guest_size();
+        let mut qcow = create(
+            output,
+            &CreateOptions {
+                cluster_bits: options.cluster_bits,
+                version: options.version,
+                compression_type: options.compression.unwrap_or_default(),
+                backing_file: Some(backing_file.to_owned()),
+                backing_format: Some(BackingFormat::Qcow2),
+                ..CreateOptions::new(size)
+            },
+        )?;
+
+        let mut writer = qcow.writer(output)?;
+        let cluster_bits = options.cluster_bits;
+        let cluster_size = writer.cluster_size();
+        let mut cluster = vec![0; cluster_size as usize];
+
+        // neighbouring differences may share a cluster, which only needs copying once
+        let mut next_cluster = 0;
+        for range in differences.differences {
+            let end = u64::min(range.end, size);
+            let start_cluster = u64::max(range.start >> cluster_bits, next_cluster);
+            let end_cluster = end.div_ceil(cluster_size);
+
+            for guest_cluster in start_cluster..end_cluster {
+                let guest_offset = guest_cluster * cluster_size;
+                let len = u64::min(cluster_size, size - guest_offset);
+                cluster[len as usize..].fill(0);
+                reader.read_at(guest_offset, &mut cluster[..len as usize])?;
+
+                if cluster.iter().all(|&byte| byte == 0) {
+                    writer.write_zeroes(guest_offset, len)?;
+                    continue;
+                }
+
+                match options.compression {
+                    Some(_) => writer.write_compressed_cluster(guest_cluster, &cluster)?,
+                    None => writer.write_cluster(guest_cluster, &cluster)?,
+                }
+            }
+
+            next_cluster = next_cluster.max(end_cluster);
+        }
+
+        writer.sync()?;
+        drop(writer);
+
+        Ok(qcow)
+    }
+}
+
+/// Create a new qcow2 overlay at `output` on top of the image at `base`, containing only the
+/// clusters in which the guest of the image at `modified` differs from it. Returns the parsed
+/// overlay.
+///
+/// The path of `base` is stored as the backing file of the overlay as given, so should be
+/// relative to wherever the overlay will be opened from. `output` is created if it does not
+/// exist, and truncated if it does. See [`DynamicQcow::export_diff`] for more details.
+///
+/// ## Example
+///
+/// ```rust,no_run
+/// use qcow::DiffOptions;
+///
+/// let overlay = qcow::export_diff(
+///     "base.qcow2",
+///     "experiment.qcow2",
+///     "experiment-delta.qcow2",
+///     &DiffOptions::default(),
+/// )?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn export_diff(
+    base: impl AsRef<Path>,
+    modified: impl AsRef<Path>,
+    output: impl AsRef<Path>,
+    options: &DiffOptions,
+) -> Result<Qcow2, Error> {
+    let backing_file = base.as_ref().to_str().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "backing file path is not valid UTF-8",
+        )
+    })?;
+
+    let mut base_file = BufReader::new(File::open(&base).map_err(Error::FileNotFound)?);
+    let base_qcow = load(&mut base_file)?;
+    check_base(&base_qcow)?;
+
+    let mut file = BufReader::new(File::open(modified).map_err(Error::FileNotFound)?);
+    let qcow = load(&mut file)?;
+
+    let mut output = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(output)?;
+
+    qcow.export_diff(
+        &mut file,
+        &base_qcow,
+        &mut base_file,
+        backing_file,
+        &mut output,
+        options,
+    )
+}
+
+/// Check that `base` can be read as the backing file of an overlay, which version 1 images
+/// can't be
+fn check_base(base: &DynamicQcow) -> Result<(), Error> {
+    match base {
+        DynamicQcow::Qcow1(_) => Err(Error::UnsupportedBackingFormat(BackingFormat::Qcow)),
+        DynamicQcow::Qcow2(_) => Ok(()),
+    }
+}
@@ -0,0 +1,157 @@
+use crate::*;
+
+use std::io::{self, Read, Seek, Write};
+use std::ops::Range;
+
+impl<'qcow, 'file, F> Writer<'qcow, 'file, F>
+where
+    F: Read + Write + Seek,
+{
+    /// Discard `len` bytes of the guest starting at `offset`, so that they read from the backing
+    /// file (or as zeroes if there is no backing file) and their host clusters can be reused.
+    ///
+    /// Only clusters entirely within the range are discarded, any clusters it partially covers
+    /// are left unchanged. Clusters still referenced by snapshots remain allocated for them.
+    ///
+    /// ## Example
+    ///
+    /// ```rust
+    /// use std::io::{Cursor, Write};
+    /// use qcow::CreateOptions;
+    ///
+    /// let mut file = Cursor::new(Vec::new());
+    /// let mut qcow = qcow::create(&mut file, &CreateOptions::new(16 << 20))?;
+    ///
+    /// let mut writer = qcow.writer(&mut file)?;
+    /// writer.write_all(&[0x55; 0x30000])?;
+    /// writer.discard(0, 0x20000)?;
+    /// writer.flush()?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn discard(&mut self, offset: u64, len: u64) -> Result<(), Error> {
+        let range = self.check_range(offset, len)?;
+        for guest_cluster in self.whole_clusters(&range) {
+            if self.is_l2_table_allocated(guest_cluster) {
+                self.deallocate_cluster(guest_cluster)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Make `len` bytes of the guest starting at `offset` read as zeroes, without writing any
+    /// data for the clusters entirely within the range where possible.
+    ///
+    /// In version 3 images, whole clusters are marked as reading as zeroes using the
+    /// [`all_zeroes`](StandardClusterDescriptor::all_zeroes) flag. Version 2 images have no such
+    /// flag, so whole clusters are deallocated instead, unless there is a backing file for them
+    /// to fall through to, in which case zeroes are written. Any clusters partially covered by
+    /// the range have zeroes written into them.
+    ///
+    /// ## Example
+    ///
+    /// ```rust
+    /// use std::io::{Cursor, Write};
+    /// use qcow::CreateOptions;
+    ///
+    /// let mut file = Cursor::new(Vec::new());
+    /// let mut qcow = qcow::create(&mut file, &CreateOptions::new(16 << 20))?;
+    ///
+    /// let mut writer = qcow.writer(&mut file)?;
+    /// writer.write_all(&[0x55; 0x30000])?;
+    /// writer.write_zeroes(0x100, 0x2ff00)?;
+    /// writer.flush()?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn write_zeroes(&mut self, offset: u64, len: u64) -> Result<(), Error> {
+        let range = self.check_range(offset, len)?;
+        let has_backing = self.qcow.header.backing_file.is_some();
+        let cluster_size = self.cluster_size();
+
+        // the range may start and end partway through clusters, or lie within a single cluster
+        let whole_clusters = self.whole_clusters(&range);
+        let whole_start = whole_clusters.start * cluster_size;
+        let whole_end = u64::min(whole_clusters.end * cluster_size, self.guest_size());
+        self.zero_partial_cluster(range.start..u64::min(whole_start, range.end))?;
+        self.zero_partial_cluster(u64::max(whole_end, range.start)..range.end)?;
+
+        let zeroes = vec![0; cluster_size as usize];
+        for guest_cluster in whole_clusters {
+            // without a backing file, unallocated clusters already read as zeroes
+            if !has_backing && !self.is_l2_table_allocated(guest_cluster) {
+                continue;
+            }
+
+            if self.qcow.header.v3_header.is_some() {
+                if has_backing || !self.l2_entry(guest_cluster)?.is_unallocated() {
+                    self.zero_cluster(guest_cluster)?;
+                }
+            } else if has_backing {
+                self.store_cluster(guest_cluster, &zeroes)?;
+            } else {
+                self.deallocate_cluster(guest_cluster)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write zeroes over the given range of the guest, which must lie within a single cluster
+    fn zero_partial_cluster(&mut self, range: Range<u64>) -> Result<(), Error> {
+        if range.is_empty() {
+            return Ok(());
+        }
+
+        let guest_cluster = range.start >> self.qcow.header.cluster_bits;
+        let entry = self.l2_entry(guest_cluster)?;
+        let reads_as_zeroes = match &entry.cluster_descriptor {
+            ClusterDescriptor::Standard(cluster) if cluster.all_zeroes => true,
+            _ => entry.is_unallocated() && self.qcow.header.backing_file.is_none(),
+        };
+        if reads_as_zeroes {
+            return Ok(());
+        }
+
+        let pos = self.pos;
+        self.pos = range.start;
+        let result = self.write_at_pos(&vec![0; (range.end - range.start) as usize]);
+        self.pos = pos;
+
+        result.map(|_| ())
+    }
+
+    /// Ensure the given range lies within the guest, returning it as a range of guest offsets
+    fn check_range(&self, offset: u64, len: u64) -> Result<Range<u64>, Error> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.guest_size() => Ok(offset..end),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "range extends past end of virtual disk",
+            )
+            .into()),
+        }
+    }
+
+    /// Get the guest clusters entirely covered by the given range of guest offsets, including a
+    /// final cluster which extends past the end of the guest if the range reaches the end
+    fn whole_clusters(&self, range: &Range<u64>) -> Range<u64> {
+        let cluster_size = self.cluster_size();
+        let start = range.start.div_ceil(cluster_size);
+        let end = if range.end == self.guest_size() {
+            range.end.div_ceil(cluster_size)
+        } else {
+            range.end / cluster_size
+        };
+
+        start..end.max(start)
+    }
+
+    /// Returns true if the L2 table covering the given guest cluster is allocated
+    fn is_l2_table_allocated(&self, guest_cluster: u64) -> bool {
+        let l1_index = guest_cluster / self.qcow.l2_entries();
+        self.qcow
+            .l1_table
+            .get(l1_index as usize)
+            .is_some_and(|l1_entry| l1_entry.l2_offset != 0)
+    }
+}
@@ -1,13 +1,77 @@
 use thiserror::Error;
 
+use crate::BackingFormat;
+
 /// An error encountered by the qcow crate from either a parsing failure or an I/O error.
 #[derive(Error, Debug)]
 pub enum Error {
     /// Error opening the qcow file
     #[error("The qcow file could not successfully be opened")]
-    FileNotFound(std::io::Error),
+    FileNotFound(#[source] std::io::Error),
 
     /// Error that occurs while parsing the qcow
     #[error("The qcow file failed to parse")]
     ParseError(#[from] binread::Error),
+
+    /// An I/O error that occurs while accessing an image
+    #[error("An I/O error occurred while accessing the image")]
+    Io(#[from] std::io::Error),
+
+    /// The contents of the backing file do not match the format declared by the header
+    #[error("The backing file was declared as {declared} but contains a {detected} image")]
+    BackingFormatMismatch {
+        /// Format declared by the backing file format header extension
+        declared: BackingFormat,
+
+        /// Format detected from the contents of the backing file
+        detected: BackingFormat,
+    },
+
+    /// The operation requires a backing file, but the image doesn't have one
+    #[error("The image does not have a backing file")]
+    NoBackingFile,
+
+    /// A refcount would exceed the maximum value representable by the image's refcount width
+    #[error("The refcount of the cluster at {0:#x} would overflow")]
+    RefcountOverflow(u64),
+
+    /// The image uses a feature which is not supported when modifying images
+    #[error("Modifying images which use {0} is not supported")]
+    UnsupportedFeature(&'static str),
+
+    /// Shrinking the guest would discard clusters allocated past its new end
+    #[error("Shrinking the guest would discard data allocated at guest offset {0:#x}")]
+    ShrinkDiscardsData(u64),
+
+    /// The backing file is of a format which cannot be read by this crate
+    #[error("Backing files of format {0} are not supported")]
+    UnsupportedBackingFormat(BackingFormat),
+
+    /// The backing file previously failed to open for the given reason, so isn't tried again
+    #[error("The backing file could not be opened: {0}")]
+    BackingFileUnavailable(String),
+}
+
+impl Error {
+    /// Describe the error along with each error which caused it
+    pub(crate) fn describe(&self) -> String {
+        let mut description = self.to_string();
+        let mut source = std::error::Error::source(self);
+        while let Some(err) = source {
+            description.push_str(": ");
+            description.push_str(&err.to_string());
+            source = err.source();
+        }
+
+        description
+    }
+}
+
+impl From<Error> for std::io::Error {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Io(err) => err,
+            err => std::io::Error::other(err),
+        }
+    }
 }
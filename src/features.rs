@@ -7,10 +7,32 @@ fn reverse<T>(func: impl Fn([u8; 8]) -> T) -> impl Fn([u8; 8]) -> T {
     }
 }
 
+macro_rules! impl_to_u64 {
+    ($($ty:ty),*) => {
+        $(
+            impl $ty {
+                /// Get the raw value of the bitmask as stored in the header
+                pub(crate) fn to_u64(self) -> u64 {
+                    u64::from_le_bytes(self.into_bytes())
+                }
+            }
+        )*
+    };
+}
+
+impl_to_u64!(IncompatibleFeatures, CompatibleFeatures, AutoClearFeatures);
+
+impl IncompatibleFeatures {
+    /// Get the bits of the bitmask which are set but not known to this crate
+    pub(crate) fn unknown_bits(self) -> u64 {
+        self.to_u64() & !0x1f
+    }
+}
+
 /// Bitmask of incompatible features. An implementation must
 /// fail to open an image if an unknown bit is set.
 #[bitfield(bits = 64)]
-#[derive(BinRead, Debug)]
+#[derive(BinRead, Debug, Clone, Copy)]
 #[br(map = reverse(Self::from_bytes))]
 pub struct IncompatibleFeatures {
     /// Dirty bit.  If this bit is set then refcounts may be inconsistent, make sure to scan L1/L2
@@ -43,7 +65,7 @@ pub struct IncompatibleFeatures {
 /// Bitmask of compatible features. An implementation can
 /// safely ignore any unknown bits that are set.
 #[bitfield(bits = 64)]
-#[derive(BinRead, Debug)]
+#[derive(BinRead, Debug, Clone, Copy)]
 #[br(map = reverse(Self::from_bytes))]
 pub struct CompatibleFeatures {
     /// Lazy refcounts bit.  If this bit is set then lazy refcount updates can be used.  This means
@@ -57,7 +79,7 @@ pub struct CompatibleFeatures {
 /// write to an image with unknown auto-clear features if it
 /// clears the respective bits from this field first.
 #[bitfield(bits = 64)]
-#[derive(BinRead, Debug)]
+#[derive(BinRead, Debug, Clone, Copy)]
 #[br(map = reverse(Self::from_bytes))]
 pub struct AutoClearFeatures {
     /// Bitmaps extension bit
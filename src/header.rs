@@ -1,6 +1,13 @@
 use crate::*;
 use crate::header_ext::HeaderExt;
 
+/// Length of a version 2 header, after which the header extensions begin
+pub(crate) const V2_HEADER_LEN: u64 = 72;
+
+/// Length of a version 3 header written by this crate, including the compression type field
+/// and the padding following it
+pub(crate) const V3_HEADER_LEN: u32 = 112;
+
 /// Top-level header of Qcow format
 #[derive_binread]
 #[derive(Debug)]
@@ -87,11 +94,16 @@ pub struct QcowHeader {
     pub(crate) snapshots_offset: u64,
 
     /// Part of header only present in qcow version 3, otherwise set to `None`
-    #[br(align_after = 8, if(version == 3))]
+    #[br(if(version == 3))]
     pub v3_header: Option<Version3Header>,
 
     /// Extentions to the header format
-    #[br(parse_with = until_exclusive(|ext: &HeaderExt| ext.is_end()))]
+    #[br(
+        seek_before = SeekFrom::Start(
+            v3_header.as_ref().map_or(V2_HEADER_LEN, |hdr| hdr.header_len as u64)
+        ),
+        parse_with = until_exclusive(|ext: &HeaderExt| ext.is_end())
+    )]
     pub extensions: Vec<HeaderExt>,
 }
 
@@ -118,8 +130,8 @@ pub struct Version3Header {
     /// This value may not exceed 6 (i.e. refcount_bits = 64).
     pub refcount_order: u32,
 
-    #[br(temp)]
-    header_len: u32,
+    /// Length of the header structure in bytes, after which the header extensions begin
+    pub(crate) header_len: u32,
 
     /// Defines the compression method used for compressed clusters.
     ///
@@ -165,6 +177,59 @@ impl Default for CompressionType {
     }
 }
 
+impl QcowHeader {
+    /// Serialize the header followed by its extensions and the backing file name, as they are
+    /// laid out at the start of the image file.
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let header_len = match self.v3_header {
+            Some(_) => V3_HEADER_LEN as usize,
+            None => V2_HEADER_LEN as usize,
+        };
+
+        let mut extensions = Vec::new();
+        for ext in self.extensions.iter().filter(|ext| !ext.is_end()) {
+            ext.write_to(&mut extensions);
+        }
+        HeaderExt::End.write_to(&mut extensions);
+
+        let backing_file = self.backing_file.as_deref().unwrap_or_default().as_bytes();
+        let backing_file_offset = match self.backing_file {
+            Some(_) => (header_len + extensions.len()) as u64,
+            None => 0,
+        };
+
+        let mut bytes = Vec::with_capacity(header_len + extensions.len() + backing_file.len());
+        bytes.extend_from_slice(b"QFI\xfb");
+        bytes.extend_from_slice(&self.version.to_be_bytes());
+        bytes.extend_from_slice(&backing_file_offset.to_be_bytes());
+        bytes.extend_from_slice(&(backing_file.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.cluster_bits.to_be_bytes());
+        bytes.extend_from_slice(&self.size.to_be_bytes());
+        bytes.extend_from_slice(&(self.crypt_method as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.l1_size.to_be_bytes());
+        bytes.extend_from_slice(&self.l1_table_offset.to_be_bytes());
+        bytes.extend_from_slice(&self.refcount_table_offset.to_be_bytes());
+        bytes.extend_from_slice(&self.refcount_table_clusters.to_be_bytes());
+        bytes.extend_from_slice(&self.nb_snapshots.to_be_bytes());
+        bytes.extend_from_slice(&self.snapshots_offset.to_be_bytes());
+
+        if let Some(v3_header) = &self.v3_header {
+            bytes.extend_from_slice(&v3_header.incompatible_features.to_u64().to_be_bytes());
+            bytes.extend_from_slice(&v3_header.compatible_features.to_u64().to_be_bytes());
+            bytes.extend_from_slice(&v3_header.autoclear_features.to_u64().to_be_bytes());
+            bytes.extend_from_slice(&v3_header.refcount_order.to_be_bytes());
+            bytes.extend_from_slice(&V3_HEADER_LEN.to_be_bytes());
+            bytes.push(v3_header.compression_type as u8);
+            bytes.resize(header_len, 0);
+        }
+
+        bytes.extend_from_slice(&extensions);
+        bytes.extend_from_slice(backing_file);
+
+        bytes
+    }
+}
+
 #[derive(BinRead)]
 #[br(import(_offset: u64,))]
 pub(crate) struct FileString(#[br(ignore)] pub(crate) Option<String>);
@@ -35,3 +35,20 @@ pub struct FeatureName {
     })]
     pub feature_name: String,
 }
+
+impl FeatureName {
+    /// Serialize the feature name as stored within the feature name table
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let kind = match self.kind {
+            FeatureKind::IncompatibleFeature => 0,
+            FeatureKind::CompatibleFeatures => 1,
+            FeatureKind::AutoClearFeatures => 2,
+        };
+
+        let mut bytes = vec![kind, self.bit_number];
+        bytes.extend(self.feature_name.bytes().take(0x2e));
+        bytes.resize(0x30, 0);
+
+        bytes
+    }
+}
@@ -71,6 +71,32 @@ impl HeaderExt {
     pub(crate) fn is_end(&self) -> bool {
         matches!(self, Self::End)
     }
+
+    /// Get the type of data provided by this header extension
+    pub fn kind(&self) -> HeaderExtKind {
+        match self {
+            Self::End => HeaderExtKind::End,
+            Self::FeatureNameTable(_) => HeaderExtKind::FeatureNameTable,
+            Self::BackingFileFormat(_) => HeaderExtKind::BackingFileFormat,
+            Self::ExternalDataPath(_) => HeaderExtKind::ExternalDataPath,
+            Self::Unparsed { kind, .. } => *kind,
+        }
+    }
+
+    /// Serialize the header extension, including its type, length and padding, to `bytes`
+    pub(crate) fn write_to(&self, bytes: &mut Vec<u8>) {
+        let data = match self {
+            Self::End => Vec::new(),
+            Self::FeatureNameTable(names) => names.iter().flat_map(FeatureName::to_bytes).collect(),
+            Self::BackingFileFormat(name) | Self::ExternalDataPath(name) => name.as_bytes().to_vec(),
+            Self::Unparsed { data, .. } => data.clone(),
+        };
+
+        bytes.extend_from_slice(&self.kind().magic().to_be_bytes());
+        bytes.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&data);
+        bytes.resize(bytes.len() + ((8 - (data.len() % 8)) % 8), 0);
+    }
 }
 
 /// The type of data provided by the given header extension
@@ -114,3 +140,18 @@ pub enum HeaderExtKind {
     /// A type of header extension unrecognized by this crate, possibly from the future!
     Other(u32),
 }
+
+impl HeaderExtKind {
+    /// Get the magic number used to identify this type of header extension within the image
+    pub fn magic(&self) -> u32 {
+        match self {
+            Self::End => 0,
+            Self::FeatureNameTable => 0x6803f857,
+            Self::BackingFileFormat => 0xe2792aca,
+            Self::BitmapsExtension => 0x23852875,
+            Self::FullDiskEncryption => 0x0537be77,
+            Self::ExternalDataPath => 0x44415441,
+            Self::Other(magic) => *magic,
+        }
+    }
+}
@@ -0,0 +1,263 @@
+use crate::header_ext::{HeaderExt, HeaderExtKind};
+use crate::refcount;
+use crate::*;
+
+use std::collections::BTreeSet;
+use std::convert::TryInto;
+use std::fmt;
+use std::io::{Read, Seek};
+use std::ops::Range;
+
+/// A range of bytes within the image file holding a single kind of structure, as returned by
+/// [`Qcow2::host_layout`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostRegion {
+    /// Range of bytes within the image file occupied by the region
+    pub range: Range<u64>,
+
+    /// What the region holds
+    pub kind: HostRegionKind,
+}
+
+/// The kind of structure held by a [`HostRegion`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum HostRegionKind {
+    /// The fixed-size part of the header
+    Header,
+
+    /// The header extension area, including the end of area marker
+    HeaderExtensions,
+
+    /// The name of the backing file
+    BackingFileName,
+
+    /// The active L1 table
+    L1Table,
+
+    /// The L1 table of the snapshot at the given index within [`Qcow2::snapshots`]
+    SnapshotL1Table(usize),
+
+    /// One or more L2 tables, of either the active image or snapshots
+    L2Table,
+
+    /// The refcount table
+    RefcountTable,
+
+    /// One or more refcount blocks
+    RefcountBlock,
+
+    /// The snapshot table
+    SnapshotTable,
+
+    /// The bitmap directory, as pointed to by the bitmaps header extension
+    BitmapDirectory,
+
+    /// Uncompressed guest data, of either the active image or snapshots
+    Data,
+
+    /// Compressed guest data, of either the active image or snapshots. Compressed clusters are
+    /// rounded up to whole sectors, so neighbouring compressed clusters may overlap.
+    CompressedData,
+}
+
+impl HostRegionKind {
+    /// Get a short name for the kind of region, such as `l1_table`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Header => "header",
+            Self::HeaderExtensions => "header_extensions",
+            Self::BackingFileName => "backing_file_name",
+            Self::L1Table => "l1_table",
+            Self::SnapshotL1Table(_) => "snapshot_l1_table",
+            Self::L2Table => "l2_table",
+            Self::RefcountTable => "refcount_table",
+            Self::RefcountBlock => "refcount_block",
+            Self::SnapshotTable => "snapshot_table",
+            Self::BitmapDirectory => "bitmap_directory",
+            Self::Data => "data",
+            Self::CompressedData => "compressed_data",
+        }
+    }
+}
+
+impl fmt::Display for HostRegionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SnapshotL1Table(index) => write!(f, "{} {}", self.name(), index),
+            _ => f.write_str(self.name()),
+        }
+    }
+}
+
+impl Qcow2 {
+    /// Get every region of the image file holding metadata or guest data, sorted by offset.
+    /// Neighbouring regions of the same kind are merged, so for example a run of L2 tables
+    /// placed back to back is a single region.
+    ///
+    /// Regions are where the metadata of the image says they are, so they may extend past the
+    /// end of a truncated image file or overlap each other in a corrupted one. Everything not
+    /// covered by a region is unused. Every L2 table is read from `reader`, which must be the
+    /// source file of the qcow. Data clusters of L2 tables past the end of the file are left
+    /// out, as are refcount blocks pointed to by the part of the refcount table past it.
+    ///
+    /// ## Example
+    ///
+    /// ```rust
+    /// use std::io::{Cursor, Write};
+    /// use qcow::{CreateOptions, HostRegionKind};
+    ///
+    /// let mut file = Cursor::new(Vec::new());
+    /// let mut qcow = qcow::create(&mut file, &CreateOptions::new(16 << 20))?;
+    ///
+    /// let mut writer = qcow.writer(&mut file)?;
+    /// writer.write_all(&[0x55; 0x10000])?;
+    /// writer.flush()?;
+    /// drop(writer);
+    ///
+    /// let layout = qcow.host_layout(&mut file)?;
+    /// assert_eq!(layout[0].kind, HostRegionKind::Header);
+    ///
+    /// for region in &layout {
+    ///     println!("{:#x}-{:#x}: {}", region.range.start, region.range.end, region.kind);
+    /// }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn host_layout(&self, reader: &mut (impl Read + Seek)) -> Result<Vec<HostRegion>, Error> {
+        let cluster_bits = self.header.cluster_bits;
+        let cluster_size = self.cluster_size();
+        let mut regions = BTreeSet::new();
+        let mut add = |start: u64, len: u64, kind| {
+            if len != 0 {
+                regions.insert((start, start + len, kind));
+            }
+        };
+
+        let header_len = self
+            .header
+            .v3_header
+            .as_ref()
+            .map_or(V2_HEADER_LEN, |v3_header| v3_header.header_len as u64);
+        let mut extensions = Vec::new();
+        for ext in &self.header.extensions {
+            ext.write_to(&mut extensions);
+        }
+        HeaderExt::End.write_to(&mut extensions);
+        add(0, header_len, HostRegionKind::Header);
+        add(
+            header_len,
+            extensions.len() as u64,
+            HostRegionKind::HeaderExtensions,
+        );
+
+        // the location of the backing file name isn't kept after parsing the header
+        let mut backing_file = [0; 12];
+        reader.seek(SeekFrom::Start(8))?;
+        reader.read_exact(&mut backing_file)?;
+        let backing_file_offset = u64::from_be_bytes(backing_file[..8].try_into().unwrap());
+        let backing_file_len = u32::from_be_bytes(backing_file[8..].try_into().unwrap());
+        if backing_file_offset != 0 {
+            let len = backing_file_len as u64;
+            add(backing_file_offset, len, HostRegionKind::BackingFileName);
+        }
+
+        if let Some((offset, len)) = self.bitmap_directory() {
+            add(offset, len, HostRegionKind::BitmapDirectory);
+        }
+
+        let table_offset = self.header.refcount_table_offset;
+        let table_len = (self.header.refcount_table_clusters as u64) << cluster_bits;
+        add(table_offset, table_len, HostRegionKind::RefcountTable);
+        let refcount_table = refcount::read_table(&self.header, reader)?;
+        for (_, block_offset) in refcount::allocated_blocks(&refcount_table) {
+            add(block_offset, cluster_size, HostRegionKind::RefcountBlock);
+        }
+
+        let snapshot_table_len: u64 = self
+            .snapshots
+            .iter()
+            .map(|snapshot| snapshot.to_bytes().len() as u64)
+            .sum();
+        let snapshots_offset = self.header.snapshots_offset;
+        add(
+            snapshots_offset,
+            snapshot_table_len,
+            HostRegionKind::SnapshotTable,
+        );
+
+        let l1_len = self.l1_table.len() as u64 * 8;
+        add(self.header.l1_table_offset, l1_len, HostRegionKind::L1Table);
+        for (index, snapshot) in self.snapshots.iter().enumerate() {
+            let l1_len = snapshot.l1_table.len() as u64 * 8;
+            let kind = HostRegionKind::SnapshotL1Table(index);
+            add(snapshot.l1_table_offset, l1_len, kind);
+        }
+
+        let mut l2_offsets: BTreeSet<u64> = self
+            .l1_table
+            .iter()
+            .chain(
+                self.snapshots
+                    .iter()
+                    .flat_map(|snapshot| &snapshot.l1_table),
+            )
+            .map(|l1_entry| l1_entry.l2_offset)
+            .collect();
+        l2_offsets.remove(&0);
+
+        for l2_offset in l2_offsets {
+            add(l2_offset, cluster_size, HostRegionKind::L2Table);
+
+            // the data of L2 tables past the end of a truncated file is unknown
+            let l1_entry = L1Entry::from_u64(l2_offset);
+            let entries = match l1_entry.read_l2(reader, cluster_bits) {
+                Some(entries) => entries,
+                None => continue,
+            };
+            for entry in entries {
+                match &entry.cluster_descriptor {
+                    ClusterDescriptor::Compressed(cluster) => {
+                        let range = cluster.host_range();
+                        let len = range.end - range.start;
+                        add(range.start, len, HostRegionKind::CompressedData);
+                    }
+                    ClusterDescriptor::Standard(cluster) if cluster.host_cluster_offset != 0 => {
+                        let offset = cluster.host_cluster_offset;
+                        add(offset, cluster_size, HostRegionKind::Data);
+                    }
+                    ClusterDescriptor::Standard(_) => (),
+                }
+            }
+        }
+
+        // merge neighbouring (or overlapping) regions of the same kind
+        let mut layout: Vec<HostRegion> = Vec::new();
+        for (start, end, kind) in regions {
+            match layout.last_mut() {
+                Some(last) if last.kind == kind && start <= last.range.end => {
+                    last.range.end = last.range.end.max(end);
+                }
+                _ => layout.push(HostRegion {
+                    range: start..end,
+                    kind,
+                }),
+            }
+        }
+
+        Ok(layout)
+    }
+
+    /// Get the offset and length of the bitmap directory from the bitmaps header extension
+    fn bitmap_directory(&self) -> Option<(u64, u64)> {
+        self.header.extensions.iter().find_map(|ext| match ext {
+            HeaderExt::Unparsed {
+                kind: HeaderExtKind::BitmapsExtension,
+                data,
+            } if data.len() >= 24 => {
+                let len = u64::from_be_bytes(data[8..16].try_into().unwrap());
+                let offset = u64::from_be_bytes(data[16..24].try_into().unwrap());
+                Some((offset, len))
+            }
+            _ => None,
+        })
+    }
+}
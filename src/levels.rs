@@ -6,7 +6,7 @@ use std::io;
 use flate2::read::DeflateDecoder;
 
 /// An entry in an L1 table that can be used to lookup the location of an L2 table
-#[derive(BinRead, Debug)]
+#[derive(BinRead, Debug, Clone)]
 #[br(map = Self::from_u64)]
 pub struct L1Entry {
     /// The offset into the image file at which the L2
@@ -21,13 +21,18 @@ pub struct L1Entry {
 }
 
 impl L1Entry {
-    fn from_u64(x: u64) -> Self {
+    pub(crate) fn from_u64(x: u64) -> Self {
         L1Entry {
             l2_offset: x & 0x00ff_ffff_ffff_fe00,
             is_used: x & 0x8000_0000_0000_0000 != 0,
         }
     }
 
+    /// Encode the entry as it is stored within an L1 table
+    pub(crate) fn to_u64(&self) -> u64 {
+        self.l2_offset | if self.is_used { 0x8000_0000_0000_0000 } else { 0 }
+    }
+
     /// Reads the L2 table corresponding to this L1 entry from the given file
     pub fn read_l2(
         &self,
@@ -44,6 +49,26 @@ impl L1Entry {
                 .collect()
         )
     }
+
+    /// Reads a single entry of the L2 table corresponding to this L1 entry from the given file.
+    ///
+    /// If no L2 table is allocated for this L1 entry, an unallocated entry is returned.
+    pub fn read_l2_entry(
+        &self,
+        reader: &mut (impl Read + Seek),
+        index: u64,
+        cluster_bits: u32,
+    ) -> io::Result<L2Entry> {
+        if self.l2_offset == 0 {
+            return Ok(L2Entry::unallocated());
+        }
+
+        reader.seek(SeekFrom::Start(self.l2_offset + (index * 8)))?;
+        let mut entry = [0; 8];
+        reader.read_exact(&mut entry)?;
+
+        Ok(L2Entry::from_u64(u64::from_be_bytes(entry), cluster_bits))
+    }
 }
 
 #[derive(BinRead)]
@@ -77,7 +102,7 @@ pub struct L2Entry {
 }
 
 impl L2Entry {
-    fn from_u64(x: u64, cluster_bits: u32) -> Self {
+    pub(crate) fn from_u64(x: u64, cluster_bits: u32) -> Self {
         let is_compressed = x & 0x4000_0000_0000_0000 != 0;
         L2Entry {
             cluster_descriptor: ClusterDescriptor::from_u64(
@@ -90,6 +115,91 @@ impl L2Entry {
         }
     }
 
+    /// Encode the entry as it is stored within an L2 table
+    pub(crate) fn to_u64(&self, cluster_bits: u32) -> u64 {
+        let descriptor = match &self.cluster_descriptor {
+            ClusterDescriptor::Standard(cluster) => cluster.to_u64(),
+            ClusterDescriptor::Compressed(cluster) => {
+                0x4000_0000_0000_0000 | cluster.to_u64(cluster_bits)
+            }
+        };
+
+        descriptor | if self.is_used { 0x8000_0000_0000_0000 } else { 0 }
+    }
+
+    /// Create an entry describing an uncompressed cluster stored at `host_cluster_offset`
+    pub(crate) fn standard(host_cluster_offset: u64) -> Self {
+        Self {
+            cluster_descriptor: ClusterDescriptor::Standard(StandardClusterDescriptor {
+                all_zeroes: false,
+                host_cluster_offset,
+            }),
+            is_compressed: false,
+            is_used: false,
+        }
+    }
+
+    /// Create an entry describing a compressed cluster whose data starts at `host_offset` and
+    /// spans `additional_sector_count` sectors past the sector containing `host_offset`
+    pub(crate) fn compressed(host_offset: u64, additional_sector_count: u64) -> Self {
+        Self {
+            cluster_descriptor: ClusterDescriptor::Compressed(CompressedClusterDescriptor {
+                host_cluster_offset: host_offset,
+                additional_sector_count,
+            }),
+            is_compressed: true,
+            is_used: false,
+        }
+    }
+
+    /// Returns the offset of the cluster within the host file if it is stored uncompressed and
+    /// has a host cluster allocated for it (even if it reads as zeroes)
+    pub(crate) fn standard_host_offset(&self) -> Option<u64> {
+        match &self.cluster_descriptor {
+            ClusterDescriptor::Standard(cluster) if cluster.host_cluster_offset != 0 => {
+                Some(cluster.host_cluster_offset)
+            }
+            _ => None,
+        }
+    }
+
+    /// Get the host offset of every cluster used by the entry, including every cluster the
+    /// compressed data of a compressed cluster overlaps
+    pub(crate) fn host_clusters(&self, cluster_bits: u32) -> Vec<u64> {
+        match &self.cluster_descriptor {
+            ClusterDescriptor::Standard(_) => self.standard_host_offset().into_iter().collect(),
+            ClusterDescriptor::Compressed(cluster) => {
+                let range = cluster.host_range();
+                ((range.start >> cluster_bits)..=((range.end - 1) >> cluster_bits))
+                    .map(|cluster| cluster << cluster_bits)
+                    .collect()
+            }
+        }
+    }
+
+    /// Create an entry describing a cluster which reads as zeroes without any host cluster
+    /// allocated for it. Only valid in version 3 images.
+    pub(crate) fn zero() -> Self {
+        Self::from_u64(1, 9)
+    }
+
+    /// Create an entry describing an unallocated cluster
+    pub(crate) fn unallocated() -> Self {
+        Self::from_u64(0, 9)
+    }
+
+    /// Returns true if the cluster is not allocated within this image, meaning its contents come
+    /// from the backing file (if any) or read as zeroes otherwise.
+    pub fn is_unallocated(&self) -> bool {
+        matches!(
+            self.cluster_descriptor,
+            ClusterDescriptor::Standard(StandardClusterDescriptor {
+                all_zeroes: false,
+                host_cluster_offset: 0,
+            })
+        )
+    }
+
     /// Read the contents of a given L2 Entry from `reader` into `buf`.
     pub fn read_contents(
         &self,
@@ -194,6 +304,10 @@ impl StandardClusterDescriptor {
             host_cluster_offset: (x & 0x00ff_ffff_ffff_fe00)
         }
     }
+
+    fn to_u64(&self) -> u64 {
+        self.host_cluster_offset | if self.all_zeroes { 1 } else { 0 }
+    }
 }
 
 /// A descriptor describing a compressed cluster
@@ -231,6 +345,21 @@ impl CompressedClusterDescriptor {
             additional_sector_count: (x & !mask(host_cluster_bits)) >> host_cluster_bits,
         }
     }
+
+    fn to_u64(&self, cluster_bits: u32) -> u64 {
+        let host_cluster_bits = 62 - (cluster_bits - 8);
+
+        (self.host_cluster_offset & mask(host_cluster_bits))
+            | (self.additional_sector_count << host_cluster_bits)
+    }
+
+    /// Get the range of bytes within the host file which may contain the compressed data
+    pub(crate) fn host_range(&self) -> std::ops::Range<u64> {
+        let start_sector = self.host_cluster_offset & !0x1ff;
+        let end = start_sector + ((self.additional_sector_count + 1) * 0x200);
+
+        self.host_cluster_offset..end
+    }
 }
 
 impl ClusterDescriptor {
@@ -33,25 +33,81 @@
 //! ## Important types/functions
 //!
 //! * Retrieving a qcow - [`open`] (from path), [`load`] (from reader), [`load_from_memory`] (from
-//! slice)
+//!   slice)
 //! * Converting to qcow2 - [`DynamicQcow::unwrap_qcow2`]
 //! * Reading from a virtual hard disk - [`Qcow2::reader`] (returns [`Reader`], which implements
-//! [`Read`](std::io::Read) + [`Seek`](std::io::Seek))
+//!   [`Read`](std::io::Read) + [`Seek`](std::io::Seek))
+//! * Writing to a virtual hard disk - [`Qcow2::writer`] (returns [`Writer`], which implements
+//!   [`Write`](std::io::Write) + [`Seek`](std::io::Seek))
+//! * Discarding or zeroing ranges of a virtual hard disk - [`Writer::discard`] and
+//!   [`Writer::write_zeroes`]
+//! * Committing an overlay into its backing file - [`commit`]
+//! * Moving an overlay onto a different backing file - [`rebase`]
+//! * Converting to a sparse raw image - [`convert_to_raw`]
+//! * Comparing the guest contents of two images - [`compare`] or [`DynamicQcow::compare`]
+//! * Exporting the difference between two images as a new overlay - [`export_diff`] or
+//!   [`DynamicQcow::export_diff`]
+//! * Creating a new qcow2 image - [`create`] or [`create_file`] (empty, optionally
+//!   preallocated) or [`import_raw`] (from a raw image)
+//! * Measuring the image file size needed for a new qcow2 image - [`measure`],
+//!   [`measure_size`] or [`DynamicQcow::measure`]
+//! * Rewriting a qcow2 image with a different cluster size, version or compression -
+//!   [`reencode`]
+//! * Compacting a qcow2 image in place - [`compact`]
+//! * Resizing the guest virtual hard disk - [`Writer::resize`] or [`resize`]
+//! * Changing the version, lazy refcounts or refcount width of an image in place -
+//!   [`Writer::amend`] or [`amend`]
 //!
 //! ## Features
 //!
 //! * Parse qcow files
 //! * Full qcow version 1 support
 //!   * Support for parsing the header and some associated data
+//!   * Support for reading the contents of the virtual disk ([`Qcow1::reader`])
 //! * Full qcow version 2-3 support
 //!   * Header parsing, including extra version 3 header data
 //!   * Header extension parsing, allowing you to use addition data they provide
 //!   * Lookup table (L1 and L2) parsing, only loading L2 tables on demand
 //!   * Snapshot parsing, including snapshot L1 lookup tables
+//!   * Translating guest offsets to their L1/L2 entries and location within the image file
+//!     ([`Qcow2::guest_to_host`])
+//!   * Finding everything referencing each host cluster of an image
+//!     ([`Qcow2::host_cluster_map`])
+//!   * Recovering data from orphaned and leaked clusters, using stale L1 and L2 tables to guess
+//!     which guest offsets they held ([`Qcow2::orphaned_clusters`])
+//!   * Mapping the layout of metadata and guest data within the image file
+//!     ([`Qcow2::host_layout`])
+//!   * Gathering allocation, compression and fragmentation statistics, including how many
+//!     clusters are unique to each snapshot ([`Qcow2::statistics`])
 //!   * Support for reading the contents of the virtual disk
 //!     * Includes compression support (for both zlib and zstd)
 //!     * Cluster lookup caching, backtracking on cache miss
 //!     * Allows arbitrary seeking within the guest
+//!     * Reads unallocated clusters from qcow2 or raw backing files
+//!     * Backing files can be located using a custom [`BackingResolver`]
+//!     * Mapping which layer of the backing chain supplies each range of the guest
+//!       ([`Reader::allocation_map`])
+//!     * Skipping to the next data or hole in the guest without reading unallocated clusters
+//!       ([`Reader::seek_data`] and [`Reader::seek_hole`])
+//!   * Support for writing to the virtual disk in place
+//!     * Clusters shared with snapshots are copied on write
+//!     * Optionally compressing written clusters using zlib or zstd
+//!     * Discarding ranges of the guest or writing zeroes to them without allocating clusters
+//!     * Growing or shrinking the guest virtual hard disk
+//!     * Upgrading or downgrading between versions 2 and 3, toggling lazy refcounts and changing
+//!       the refcount width in place
+//!   * Committing an overlay into its qcow2 or raw backing file
+//!   * Rebasing an overlay onto a different backing file, either safely or header-only
+//! * Converting qcow and qcow2 images (including their backing chain) to sparse raw images
+//! * Comparing the guest contents of two qcow or qcow2 images (including their backing chains),
+//!   skipping ranges which are unallocated in both
+//! * Exporting the difference between two images as a new qcow2 overlay backed by the original
+//!   image, containing only the clusters which differ
+//! * Creating new qcow2 images, and importing raw images into qcow2 with optional zlib or zstd
+//!   compression
+//! * Re-encoding qcow2 images with a new cluster size, version, refcount width or compression,
+//!   preserving internal snapshots and their VM state
+//! * Compacting qcow2 images in place, laying out data in guest order and reclaiming unused space
 #![warn(missing_docs)]
 use binread::{
     derive_binread,
@@ -68,6 +124,68 @@ mod methods;
 mod reader;
 pub use reader::*;
 
+mod writer;
+pub use writer::*;
+
+mod refcount;
+
+mod commit;
+pub use commit::*;
+
+mod rebase;
+pub use rebase::*;
+
+mod convert;
+pub use convert::*;
+
+mod create;
+pub use create::*;
+
+mod reencode;
+pub use reencode::*;
+
+mod compact;
+pub use compact::*;
+
+mod resize;
+pub use resize::*;
+
+mod amend;
+pub use amend::*;
+
+mod discard;
+
+mod map;
+pub use map::*;
+
+mod seek;
+
+mod translate;
+pub use translate::*;
+
+mod owners;
+pub use owners::*;
+
+mod recover;
+pub use recover::*;
+
+mod layout;
+pub use layout::*;
+
+mod stats;
+pub use stats::*;
+
+mod compare;
+pub use compare::*;
+
+mod diff;
+pub use diff::*;
+
+mod measure;
+pub use measure::*;
+
+mod compressed;
+
 mod error;
 pub use error::Error;
 
@@ -90,6 +208,12 @@ pub use snapshots::*;
 mod features;
 pub use features::*;
 
+mod backing;
+pub use backing::*;
+
+mod chain;
+pub use chain::*;
+
 mod dynamic_qcow;
 pub use dynamic_qcow::DynamicQcow;
 
@@ -0,0 +1,384 @@
+use crate::levels::L2Entry;
+use crate::*;
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Seek};
+use std::ops::Range;
+
+/// How the contents of an [`Extent`] of the guest are stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtentKind {
+    /// Data stored uncompressed within the layer
+    Data,
+
+    /// Data stored compressed within the layer. A compressed extent never spans more than a
+    /// single cluster of the layer.
+    Compressed,
+
+    /// Marked as reading as zeroes within the layer, without reading from any layer below it
+    Zero,
+
+    /// Not allocated in any layer of the backing chain, and so reads as zeroes
+    Unallocated,
+}
+
+/// A contiguous range of the guest whose contents come from the same layer of the backing
+/// chain and are stored in the same way, as produced by [`Reader::allocation_map`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Extent {
+    /// Offset of the start of the extent within the guest
+    pub start: u64,
+
+    /// Length of the extent in bytes
+    pub len: u64,
+
+    /// How the contents of the extent are stored
+    pub kind: ExtentKind,
+
+    /// Index of the layer supplying the extent within the layers returned by
+    /// [`Qcow2::backing_chain`], where 0 is the qcow itself. For unallocated extents this is the
+    /// deepest layer which was checked.
+    pub layer: usize,
+
+    /// Offset within the file of the supplying layer at which the contents of the extent are
+    /// stored. For compressed extents this is the start of the compressed data of the cluster.
+    /// `None` for zero and unallocated extents.
+    pub host_offset: Option<u64>,
+}
+
+impl Extent {
+    /// Get the guest offset just past the end of the extent
+    pub fn end(&self) -> u64 {
+        self.start + self.len
+    }
+
+    /// Returns true if the extent is stored within the qcow itself rather than a backing file
+    pub fn is_local(&self) -> bool {
+        self.layer == 0 && self.kind != ExtentKind::Unallocated
+    }
+
+    /// Extend this extent to cover `next` if it directly follows it and is stored the same way
+    fn merge(&mut self, next: &Extent) -> bool {
+        if self.end() != next.start || self.kind != next.kind || self.layer != next.layer {
+            return false;
+        }
+
+        // compressed extents only continue within the same compressed cluster
+        let contiguous = match (self.kind, self.host_offset, next.host_offset) {
+            (ExtentKind::Compressed, offset, next_offset) => offset == next_offset,
+            (_, Some(offset), Some(next_offset)) => offset + self.len == next_offset,
+            _ => true,
+        };
+
+        if contiguous {
+            self.len += next.len;
+        }
+
+        contiguous
+    }
+}
+
+/// An iterator over the [`Extent`]s of a guest in order, as returned by
+/// [`Reader::allocation_map`]
+pub struct AllocationMap<'a, 'qcow, 'reader, R>
+where
+    R: Read + Seek,
+{
+    reader: &'a mut Reader<'qcow, 'reader, R>,
+    cursor: MapCursor,
+}
+
+/// How far through the guest an [`AllocationMap`] has got, kept apart from the reader so the
+/// reader can still be used in between looking up extents
+#[derive(Debug, Clone)]
+pub(crate) struct MapCursor {
+    /// guest offset up to which extents have been looked up
+    pos: u64,
+
+    /// extents which have been looked up but not yet returned
+    pending: VecDeque<Extent>,
+}
+
+impl<'qcow, 'reader, R> Reader<'qcow, 'reader, R>
+where
+    R: Read + Seek,
+{
+    /// Iterate over the guest in order as a series of [`Extent`]s, describing which layer of the
+    /// backing chain supplies each part of the guest and where it is stored, similar to
+    /// `qemu-img map`.
+    ///
+    /// Only the L1 and L2 tables of each layer are read, not the contents of the guest. The read
+    /// position of the reader is unaffected.
+    ///
+    /// ## Example
+    ///
+    /// ```rust
+    /// use std::io::{Cursor, Seek, SeekFrom, Write};
+    /// use qcow::{CreateOptions, ExtentKind};
+    ///
+    /// let mut file = Cursor::new(Vec::new());
+    /// let mut qcow = qcow::create(&mut file, &CreateOptions::new(16 << 20))?;
+    ///
+    /// let mut writer = qcow.writer(&mut file)?;
+    /// writer.seek(SeekFrom::Start(0x20000))?;
+    /// writer.write_all(&[0x55; 0x10000])?;
+    /// writer.flush()?;
+    /// drop(writer);
+    ///
+    /// let mut reader = qcow.reader(&mut file);
+    /// for extent in reader.allocation_map() {
+    ///     let extent = extent?;
+    ///     if extent.kind == ExtentKind::Data {
+    ///         println!("{:#x}-{:#x} at {:#x?}", extent.start, extent.end(), extent.host_offset);
+    ///     }
+    /// }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn allocation_map(&mut self) -> AllocationMap<'_, 'qcow, 'reader, R> {
+        self.allocation_map_from(0)
+    }
+
+    /// Iterate over the [`Extent`]s of the guest starting at the given guest offset, where the
+    /// first extent is cut short to start at that offset
+    pub(crate) fn allocation_map_from(
+        &mut self,
+        start: u64,
+    ) -> AllocationMap<'_, 'qcow, 'reader, R> {
+        AllocationMap {
+            reader: self,
+            cursor: MapCursor::new(start),
+        }
+    }
+
+    /// Append the extents covering the given range of the guest to `extents`, where this reader
+    /// is for the given layer of the backing chain
+    fn map_range(
+        &mut self,
+        range: Range<u64>,
+        layer: usize,
+        extents: &mut Vec<Extent>,
+    ) -> Result<(), Error> {
+        let cluster_bits = self.cluster_bits();
+        let cluster_size = self.cluster_size();
+        let l2_entries = cluster_size / 8;
+        let guest_size = self.guest_size();
+
+        let mut pos = range.start;
+        while pos < range.end {
+            // anything past the end of the layer reads as zeroes
+            if pos >= guest_size {
+                push_extent(
+                    extents,
+                    pos..range.end,
+                    ExtentKind::Unallocated,
+                    layer,
+                    None,
+                );
+                break;
+            }
+
+            let l1_index = (pos >> cluster_bits) / l2_entries;
+            let table_end = (l1_index + 1) * l2_entries * cluster_size;
+            let table_end = range.end.min(guest_size).min(table_end);
+            let table = match self.qcow.l1_table.get(l1_index as usize) {
+                Some(l1_entry) if l1_entry.l2_offset != 0 => {
+                    l1_entry.read_l2(&mut *self.reader, cluster_bits).ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::UnexpectedEof, "L2 table could not be read")
+                    })?
+                }
+                _ => Vec::new(),
+            };
+            let entry_at = |pos: u64| {
+                table
+                    .get(((pos >> cluster_bits) % l2_entries) as usize)
+                    .cloned()
+                    .unwrap_or_else(L2Entry::unallocated)
+            };
+
+            while pos < table_end {
+                let entry = entry_at(pos);
+                let cluster_end = ((pos >> cluster_bits) + 1) << cluster_bits;
+                let end = table_end.min(cluster_end);
+
+                match &entry.cluster_descriptor {
+                    ClusterDescriptor::Standard(cluster) if cluster.all_zeroes => {
+                        push_extent(extents, pos..end, ExtentKind::Zero, layer, None);
+                    }
+                    ClusterDescriptor::Standard(cluster) if cluster.host_cluster_offset != 0 => {
+                        let host_offset = cluster.host_cluster_offset + (pos & (cluster_size - 1));
+                        push_extent(
+                            extents,
+                            pos..end,
+                            ExtentKind::Data,
+                            layer,
+                            Some(host_offset),
+                        );
+                    }
+                    ClusterDescriptor::Compressed(cluster) => {
+                        let host_offset = Some(cluster.host_cluster_offset);
+                        push_extent(
+                            extents,
+                            pos..end,
+                            ExtentKind::Compressed,
+                            layer,
+                            host_offset,
+                        );
+                    }
+                    ClusterDescriptor::Standard(_) => {
+                        // look up a whole run of unallocated clusters in the backing file at once
+                        let mut end = end;
+                        while end < table_end && entry_at(end).is_unallocated() {
+                            end = table_end.min(end + cluster_size);
+                        }
+
+                        self.map_backing(pos..end, layer, extents)?;
+                        pos = end;
+                        continue;
+                    }
+                }
+
+                pos = end;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Append the extents covering the given range of the guest, which is unallocated in this
+    /// layer, from the layers below it
+    fn map_backing(
+        &mut self,
+        range: Range<u64>,
+        layer: usize,
+        extents: &mut Vec<Extent>,
+    ) -> Result<(), Error> {
+        match self.get_backing_reader()? {
+            Some(BackingReader::Qcow2(reader)) => reader.map_range(range, layer + 1, extents)?,
+            Some(BackingReader::Raw(reader)) => {
+                let data_end = range.end.min(reader.len()).max(range.start);
+                let data = range.start..data_end;
+                push_extent(
+                    extents,
+                    data,
+                    ExtentKind::Data,
+                    layer + 1,
+                    Some(range.start),
+                );
+                push_extent(
+                    extents,
+                    data_end..range.end,
+                    ExtentKind::Unallocated,
+                    layer + 1,
+                    None,
+                );
+            }
+            None => push_extent(extents, range, ExtentKind::Unallocated, layer, None),
+        }
+
+        Ok(())
+    }
+}
+
+/// Append an extent to `extents`, merging it into the previous extent where possible. Empty
+/// ranges are ignored.
+fn push_extent(
+    extents: &mut Vec<Extent>,
+    range: Range<u64>,
+    kind: ExtentKind,
+    layer: usize,
+    host_offset: Option<u64>,
+) {
+    if range.is_empty() {
+        return;
+    }
+
+    let extent = Extent {
+        start: range.start,
+        len: range.end - range.start,
+        kind,
+        layer,
+        host_offset,
+    };
+
+    let merged = extents.last_mut().is_some_and(|last| last.merge(&extent));
+    if !merged {
+        extents.push(extent);
+    }
+}
+
+impl MapCursor {
+    /// Start looking up extents at the given guest offset, where the first extent is cut short
+    /// to start at that offset
+    pub(crate) fn new(start: u64) -> Self {
+        Self {
+            pos: start,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Get the next extent of the guest of `reader`, which must be the same reader every time
+    pub(crate) fn next_extent<R>(
+        &mut self,
+        reader: &mut Reader<'_, '_, R>,
+    ) -> Option<Result<Extent, Error>>
+    where
+        R: Read + Seek,
+    {
+        let guest_size = reader.guest_size();
+        loop {
+            // extents may continue into the next L2 table, so look ahead before returning one
+            if self.pending.len() < 2 && self.pos < guest_size {
+                if let Err(err) = self.fill(reader) {
+                    self.pos = guest_size;
+                    self.pending.clear();
+                    return Some(Err(err));
+                }
+                continue;
+            }
+
+            let mut extent = self.pending.pop_front()?;
+            while let Some(next) = self.pending.front() {
+                if !extent.merge(next) {
+                    break;
+                }
+                self.pending.pop_front();
+
+                if self.pending.is_empty() && self.pos < guest_size {
+                    if let Err(err) = self.fill(reader) {
+                        self.pos = guest_size;
+                        return Some(Err(err));
+                    }
+                }
+            }
+
+            return Some(Ok(extent));
+        }
+    }
+
+    /// Look up the extents of the next L2 table's worth of the guest
+    fn fill<R>(&mut self, reader: &mut Reader<'_, '_, R>) -> Result<(), Error>
+    where
+        R: Read + Seek,
+    {
+        let span = reader.cluster_size() * (reader.cluster_size() / 8);
+        let end = reader.guest_size().min((self.pos / span + 1) * span);
+
+        let mut extents = Vec::new();
+        reader.map_range(self.pos..end, 0, &mut extents)?;
+        self.pending.extend(extents);
+        self.pos = end;
+
+        Ok(())
+    }
+}
+
+impl<'a, 'qcow, 'reader, R> Iterator for AllocationMap<'a, 'qcow, 'reader, R>
+where
+    R: Read + Seek,
+{
+    type Item = Result<Extent, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.cursor.next_extent(self.reader)
+    }
+}
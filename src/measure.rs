@@ -0,0 +1,460 @@
+use crate::*;
+
+use std::collections::BTreeSet;
+use std::io::{self, Read, Seek};
+
+/// Size of the sectors compressed clusters are packed into
+const SECTOR_SIZE: u64 = 512;
+
+/// Options describing the qcow2 image to measure the size of, for use with [`measure`],
+/// [`measure_size`] or [`DynamicQcow::measure`].
+///
+/// ## Example
+///
+/// ```rust
+/// use qcow::{MeasureOptions, Preallocation};
+///
+/// let options = MeasureOptions {
+///     cluster_bits: 12,
+///     preallocation: Preallocation::Metadata,
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Debug, Clone)]
+pub struct MeasureOptions {
+    /// Number of bits used for addressing within a cluster (1 << cluster_bits is the cluster
+    /// size). Must be between 9 and 21. Defaults to 16 (64 KiB clusters).
+    pub cluster_bits: u32,
+
+    /// Version of the qcow2 format, either 2 or 3. Defaults to 3.
+    pub version: u32,
+
+    /// Width of each refcount entry as a power of two number of bits. Must be 4 for version 2
+    /// images. Defaults to 4 (16-bit refcounts).
+    pub refcount_order: u32,
+
+    /// How much of the guest is allocated up front. Can't be combined with compression.
+    /// Defaults to [`Preallocation::Off`].
+    pub preallocation: Preallocation,
+
+    /// Compression to use for every cluster, or `None` to store clusters uncompressed. Zstd
+    /// requires a version 3 image. Defaults to `None`.
+    pub compression: Option<CompressionType>,
+}
+
+impl Default for MeasureOptions {
+    fn default() -> Self {
+        Self {
+            cluster_bits: 16,
+            version: 3,
+            refcount_order: 4,
+            preallocation: Preallocation::Off,
+            compression: None,
+        }
+    }
+}
+
+impl MeasureOptions {
+    fn validate(&self, size: u64) -> Result<(), Error> {
+        let options = CreateOptions {
+            cluster_bits: self.cluster_bits,
+            version: self.version,
+            refcount_order: self.refcount_order,
+            compression_type: self.compression.unwrap_or_default(),
+            preallocation: self.preallocation,
+            ..CreateOptions::new(size)
+        };
+
+        let result = match options.validate() {
+            Ok(()) if self.preallocation != Preallocation::Off && self.compression.is_some() => {
+                Err("preallocation can't be combined with compression")
+            }
+            result => result,
+        };
+
+        result.map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err).into())
+    }
+}
+
+/// The length of the image file needed for a qcow2 image, as returned by [`measure`],
+/// [`measure_size`] and [`DynamicQcow::measure`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Measurement {
+    /// Length in bytes of the image file holding the contents of the source, with the given
+    /// preallocation
+    pub required: u64,
+
+    /// Length in bytes of the image file with every guest cluster allocated, as with
+    /// preallocation
+    pub fully_allocated: u64,
+}
+
+/// A model of how host clusters are allocated while creating an image and writing its guest in
+/// order, following the allocation strategy of the refcounts and writer without tracking the
+/// refcount of every cluster
+struct Allocator {
+    cluster_bits: u32,
+    refcount_order: u32,
+
+    /// number of host clusters tracked, all clusters past this are free
+    len: u64,
+
+    /// free host clusters before `len`
+    holes: BTreeSet<u64>,
+
+    /// cluster index and number of clusters of the refcount table
+    table: (u64, u64),
+
+    /// indices within the refcount table of every allocated refcount block
+    blocks: BTreeSet<u64>,
+
+    /// index of every L1 entry with an L2 table allocated
+    l2_tables: BTreeSet<u64>,
+
+    /// offset following the last compressed cluster written, 0 if there is no partially
+    /// filled cluster to continue from
+    compressed_offset: u64,
+
+    /// refcount of the host cluster containing `compressed_offset`
+    compressed_refcount: u64,
+
+    /// length of the image file
+    file_len: u64,
+}
+
+impl Allocator {
+    /// Start with the clusters allocated by [`create`]: the header, the L1 table, and the
+    /// refcount table and block covering them
+    fn new(options: &MeasureOptions, size: u64) -> Self {
+        let cluster_size = 1u64 << options.cluster_bits;
+        let l2_entries = cluster_size / 8;
+        let l1_size = size.div_ceil(cluster_size * l2_entries).max(1);
+
+        let mut allocator = Self {
+            cluster_bits: options.cluster_bits,
+            refcount_order: options.refcount_order,
+            len: 0,
+            holes: BTreeSet::new(),
+            table: (0, 0),
+            blocks: BTreeSet::new(),
+            l2_tables: BTreeSet::new(),
+            compressed_offset: 0,
+            compressed_refcount: 0,
+            file_len: 0,
+        };
+
+        allocator.allocate_written(1);
+        allocator.allocate_written((l1_size * 8).div_ceil(cluster_size));
+        allocator.flush_refcounts();
+
+        allocator
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    fn is_free(&self, cluster: u64) -> bool {
+        cluster >= self.len || self.holes.contains(&cluster)
+    }
+
+    /// Allocate the first run of `count` free clusters, returning the index of the first
+    fn allocate(&mut self, count: u64) -> u64 {
+        let start = self
+            .holes
+            .iter()
+            .copied()
+            .find(|&start| (start..start + count).all(|cluster| self.is_free(cluster)))
+            .unwrap_or(self.len);
+
+        for cluster in start..start + count {
+            self.holes.remove(&cluster);
+        }
+        self.len = self.len.max(start + count);
+
+        start
+    }
+
+    /// Allocate `count` clusters which are written in full, returning the index of the first
+    fn allocate_written(&mut self, count: u64) -> u64 {
+        let start = self.allocate(count);
+        self.file_len = self.file_len.max((start + count) << self.cluster_bits);
+
+        start
+    }
+
+    /// Allocate and write `count` guest clusters starting at `guest_cluster` stored
+    /// uncompressed, which must all be covered by the same L2 table
+    fn data_clusters(&mut self, guest_cluster: u64, count: u64) {
+        self.l2_table(guest_cluster);
+        self.allocate_written(count);
+    }
+
+    /// Allocate and write a guest cluster compressed to `len` bytes, packing it after the
+    /// previously written compressed cluster where possible
+    fn compressed_cluster(&mut self, guest_cluster: u64, len: u64) {
+        self.l2_table(guest_cluster);
+
+        let cluster_size = self.cluster_size();
+        let sectors_len = len.div_ceil(SECTOR_SIZE) * SECTOR_SIZE;
+        let max_refcount = u64::MAX >> (64 - (1 << self.refcount_order));
+
+        let offset = self.compressed_offset;
+        let first_cluster = offset >> self.cluster_bits;
+        let last_cluster = (offset + sectors_len - 1) >> self.cluster_bits;
+        let can_extend = offset != 0
+            && self.compressed_refcount < max_refcount
+            && (first_cluster + 1..=last_cluster).all(|cluster| self.is_free(cluster));
+
+        let start = if can_extend {
+            for cluster in first_cluster + 1..=last_cluster {
+                self.holes.remove(&cluster);
+            }
+            self.len = self.len.max(last_cluster + 1);
+            self.compressed_refcount = if last_cluster == first_cluster {
+                self.compressed_refcount + 1
+            } else {
+                1
+            };
+
+            offset
+        } else {
+            self.compressed_refcount = 1;
+            self.allocate(sectors_len.div_ceil(cluster_size)) << self.cluster_bits
+        };
+
+        // only the compressed data itself is written, so the last cluster may be partial
+        let end = start + sectors_len;
+        self.compressed_offset = if end & (cluster_size - 1) == 0 {
+            0
+        } else {
+            end
+        };
+        self.file_len = self.file_len.max(start + len);
+    }
+
+    /// Allocate the L2 table covering `guest_cluster` if it isn't already
+    fn l2_table(&mut self, guest_cluster: u64) {
+        let l1_index = guest_cluster / (self.cluster_size() / 8);
+        if self.l2_tables.insert(l1_index) {
+            self.allocate_written(1);
+        }
+    }
+
+    /// Allocate refcount blocks for every cluster in use and relocate the refcount table as
+    /// needed, as when writing out the refcounts
+    fn flush_refcounts(&mut self) {
+        let cluster_size = self.cluster_size();
+        let entries_per_block = (cluster_size * 8) >> self.refcount_order;
+
+        // previous tables stay allocated until the refcounts pointing to them have been written
+        let mut stale_table_clusters = Vec::new();
+
+        // every block before this is either allocated or covers no clusters in use, until a
+        // hole is filled
+        let mut first_missing = 0;
+        loop {
+            let blocks_needed = self.len.div_ceil(entries_per_block);
+            let table_capacity = self.table.1 * cluster_size / 8;
+
+            if blocks_needed > table_capacity {
+                // leave some room for growth so the table doesn't need to move again soon
+                let clusters = (blocks_needed * 8).div_ceil(cluster_size) + 1;
+                let offset = self.allocate_written(clusters);
+
+                let (old_offset, old_clusters) = self.table;
+                stale_table_clusters.extend(old_offset..old_offset + old_clusters);
+                self.table = (offset, clusters);
+                first_missing = 0;
+                continue;
+            }
+
+            let missing_block = (first_missing..blocks_needed).find(|&block| {
+                let first = block * entries_per_block;
+                let last = u64::min(first + entries_per_block, self.len);
+                !self.blocks.contains(&block) && (first..last).any(|cluster| !self.is_free(cluster))
+            });
+
+            match missing_block {
+                Some(block) => {
+                    let old_len = self.len;
+                    if self.allocate_written(1) < old_len {
+                        first_missing = 0;
+                    } else {
+                        first_missing = block + 1;
+                    }
+                    self.blocks.insert(block);
+                }
+                None => break,
+            }
+        }
+
+        self.holes.extend(stale_table_clusters);
+    }
+
+    /// Finish writing the image, returning the length of the image file
+    fn finish(mut self) -> u64 {
+        self.flush_refcounts();
+        self.file_len
+    }
+}
+
+/// Measure the length of an image file with every guest cluster of a `size` byte guest
+/// allocated in order
+fn fully_allocated(options: &MeasureOptions, size: u64) -> u64 {
+    let mut allocator = Allocator::new(options, size);
+    let cluster_size = 1u64 << options.cluster_bits;
+    let l2_entries = cluster_size / 8;
+    let guest_clusters = size.div_ceil(cluster_size);
+    for first_cluster in (0..guest_clusters).step_by(l2_entries as usize) {
+        let count = u64::min(l2_entries, guest_clusters - first_cluster);
+        allocator.data_clusters(first_cluster, count);
+    }
+
+    allocator.finish()
+}
+
+/// Measure the length of the image file needed for an image with a guest of `size` bytes and
+/// nothing written to the guest, other than any preallocation.
+///
+/// See [`DynamicQcow::measure`] for measuring the image needed to hold the contents of an
+/// existing image.
+///
+/// ## Example
+///
+/// ```rust
+/// use std::io::Cursor;
+/// use qcow::{CreateOptions, MeasureOptions};
+///
+/// let measurement = qcow::measure_size(16 << 20, &MeasureOptions::default())?;
+///
+/// let mut file = Cursor::new(Vec::new());
+/// qcow::create(&mut file, &CreateOptions::new(16 << 20))?;
+/// assert_eq!(measurement.required, file.get_ref().len() as u64);
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn measure_size(size: u64, options: &MeasureOptions) -> Result<Measurement, Error> {
+    options.validate(size)?;
+
+    let fully_allocated = fully_allocated(options, size);
+    let required = match options.preallocation {
+        Preallocation::Off => Allocator::new(options, size).finish(),
+        _ => fully_allocated,
+    };
+
+    Ok(Measurement {
+        required,
+        fully_allocated,
+    })
+}
+
+impl DynamicQcow {
+    /// Measure the length of the image file needed for a qcow2 image holding the contents of the
+    /// guest of this qcow, including anything supplied by its backing chain, as created by
+    /// [`Qcow2::import_raw`] from a raw copy of the guest. `reader` must be the source file of
+    /// the qcow.
+    ///
+    /// Clusters of the new image which would be entirely zero are left unallocated, the same as
+    /// when importing. Ranges which are unallocated or zeroed in this qcow are skipped without
+    /// being read, but everything else is read to check for zeroes and, if compressing, to
+    /// find out the size of each compressed cluster.
+    ///
+    /// See [`measure`] for measuring an image on the filesystem, or [`measure_size`] for
+    /// measuring an empty image.
+    pub fn measure<R>(&self, reader: &mut R, options: &MeasureOptions) -> Result<Measurement, Error>
+    where
+        R: Read + Seek,
+    {
+        let mut reader = self.guest_reader(reader);
+        let size = reader.guest_size();
+        options.validate(size)?;
+
+        let fully_allocated = fully_allocated(options, size);
+        if options.preallocation != Preallocation::Off {
+            return Ok(Measurement {
+                required: fully_allocated,
+                fully_allocated,
+            });
+        }
+
+        let cluster_size = 1u64 << options.cluster_bits;
+        let mut allocator = Allocator::new(options, size);
+        let mut cluster = vec![0; cluster_size as usize];
+        let mut extent = (0, false);
+
+        let guest_clusters = size.div_ceil(cluster_size);
+        let mut guest_cluster = 0;
+        while guest_cluster < guest_clusters {
+            let start = guest_cluster * cluster_size;
+            let end = u64::min(start + cluster_size, size);
+
+            // skip clusters which are known to read as zeroes throughout, up to the cluster
+            // containing the next data
+            let mut pos = start;
+            while pos < end {
+                if pos >= extent.0 {
+                    extent = reader.extent_at(pos)?;
+                }
+                if !extent.1 {
+                    break;
+                }
+
+                pos = extent.0;
+            }
+            if pos >= end {
+                guest_cluster = u64::max(guest_cluster + 1, pos / cluster_size);
+                continue;
+            }
+
+            let len = (end - start) as usize;
+            cluster[len..].fill(0);
+            reader.read_at(start, &mut cluster[..len])?;
+            if cluster.iter().all(|&byte| byte == 0) {
+                guest_cluster += 1;
+                continue;
+            }
+
+            match options.compression {
+                Some(compression_type) => {
+                    let compressed = compression_type.compress(&cluster)?;
+                    match compressed.len() as u64 {
+                        len if len >= cluster_size => allocator.data_clusters(guest_cluster, 1),
+                        len => allocator.compressed_cluster(guest_cluster, len),
+                    }
+                }
+                None => allocator.data_clusters(guest_cluster, 1),
+            }
+
+            guest_cluster += 1;
+        }
+
+        Ok(Measurement {
+            required: allocator.finish(),
+            fully_allocated,
+        })
+    }
+}
+
+/// Measure the length of the image file needed for a qcow2 image holding the contents of the
+/// guest of the image at `path`, including anything supplied by its backing chain.
+///
+/// See [`DynamicQcow::measure`] for more details.
+///
+/// ## Example
+///
+/// ```rust,no_run
+/// use qcow::MeasureOptions;
+///
+/// let measurement = qcow::measure("experiment.qcow2", &MeasureOptions::default())?;
+/// println!(
+///     "{} bytes needed, {} if fully allocated",
+///     measurement.required, measurement.fully_allocated
+/// );
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn measure(path: impl AsRef<Path>, options: &MeasureOptions) -> Result<Measurement, Error> {
+    let mut file = BufReader::new(File::open(path).map_err(Error::FileNotFound)?);
+    let qcow = load(&mut file)?;
+
+    qcow.measure(&mut file, options)
+}
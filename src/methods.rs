@@ -5,6 +5,29 @@ impl Qcow2 {
     pub fn cluster_size(&self) -> u64 {
         self.header.cluster_size()
     }
+
+    /// Get the number of entries in each L2 table
+    pub(crate) fn l2_entries(&self) -> u64 {
+        self.cluster_size() / (std::mem::size_of::<u64>() as u64)
+    }
+
+    /// Look up the L2 entry describing the cluster containing `guest_offset` within the active
+    /// L1 table, returning an unallocated entry if no L2 table exists for the offset.
+    pub(crate) fn l2_entry_at(
+        &self,
+        reader: &mut (impl Read + Seek),
+        guest_offset: u64,
+    ) -> std::io::Result<L2Entry> {
+        let cluster = guest_offset >> self.header.cluster_bits;
+        match self.l1_table.get((cluster / self.l2_entries()) as usize) {
+            Some(l1_entry) => l1_entry.read_l2_entry(
+                reader,
+                cluster % self.l2_entries(),
+                self.header.cluster_bits,
+            ),
+            None => Ok(L2Entry::unallocated()),
+        }
+    }
 }
 
 impl QcowHeader {
@@ -12,6 +35,15 @@ impl QcowHeader {
     pub fn cluster_size(&self) -> u64 {
         1 << self.cluster_bits
     }
+
+    /// Get the compression type used for compressed clusters, defaulting to
+    /// [`CompressionType::Zlib`] for images without a compression type field
+    pub fn compression_type(&self) -> CompressionType {
+        self.v3_header
+            .as_ref()
+            .map(|hdr| hdr.compression_type)
+            .unwrap_or_default()
+    }
 }
 
 impl Qcow1 {
@@ -0,0 +1,238 @@
+use crate::refcount;
+use crate::*;
+
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Seek};
+
+/// Something within a qcow which references a host cluster, as recorded in a
+/// [`HostClusterMap`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClusterOwner {
+    /// The header, including any header extensions and the backing file name
+    Header,
+
+    /// Part of the active L1 table
+    L1Table,
+
+    /// Part of the L1 table of the snapshot at the given index within [`Qcow2::snapshots`]
+    SnapshotL1Table(usize),
+
+    /// An L2 table, pointed to by the given L1 entry of either the active L1 table (if
+    /// `snapshot` is `None`) or the L1 table of the snapshot at the given index
+    L2Table {
+        /// Index of the snapshot whose L1 table points to the L2 table, `None` for the active
+        /// L1 table
+        snapshot: Option<usize>,
+
+        /// Index of the L1 entry pointing to the L2 table
+        l1_index: u64,
+    },
+
+    /// The contents of a guest cluster, either of the active image (if `snapshot` is `None`)
+    /// or of the snapshot at the given index. Guest offsets of snapshots past the end of their
+    /// virtual disk hold their VM state.
+    GuestData {
+        /// Index of the snapshot the guest cluster belongs to, `None` for the active image
+        snapshot: Option<usize>,
+
+        /// Offset of the start of the guest cluster within the guest
+        guest_offset: u64,
+
+        /// Whether the host cluster holds (part of) the compressed data of the guest cluster
+        compressed: bool,
+    },
+
+    /// Part of the refcount table
+    RefcountTable,
+
+    /// The refcount block at the given index within the refcount table
+    RefcountBlock(u64),
+
+    /// Part of the snapshot table
+    SnapshotTable,
+}
+
+/// An index from each host cluster of a qcow to everything referencing it, as returned by
+/// [`Qcow2::host_cluster_map`]
+#[derive(Debug, Clone)]
+pub struct HostClusterMap {
+    cluster_bits: u32,
+
+    /// owners of each referenced host cluster, keyed by host cluster offset
+    owners: BTreeMap<u64, Vec<ClusterOwner>>,
+}
+
+impl HostClusterMap {
+    /// Get everything referencing the host cluster containing `host_offset`, which is empty if
+    /// nothing references it
+    pub fn owners(&self, host_offset: u64) -> &[ClusterOwner] {
+        let cluster_offset = (host_offset >> self.cluster_bits) << self.cluster_bits;
+        self.owners
+            .get(&cluster_offset)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Iterate over the offset of every referenced host cluster in order, along with
+    /// everything referencing it
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &[ClusterOwner])> + '_ {
+        self.owners
+            .iter()
+            .map(|(&offset, owners)| (offset, owners.as_slice()))
+    }
+
+    /// Get the number of host clusters referenced by anything
+    pub fn len(&self) -> usize {
+        self.owners.len()
+    }
+
+    /// Returns true if no host clusters are referenced
+    pub fn is_empty(&self) -> bool {
+        self.owners.is_empty()
+    }
+
+    fn add(&mut self, host_offset: u64, owner: ClusterOwner) {
+        self.owners.entry(host_offset).or_default().push(owner);
+    }
+
+    /// Add `owner` to every host cluster overlapping `len` bytes starting at `host_offset`
+    fn add_range(&mut self, host_offset: u64, len: u64, owner: ClusterOwner) {
+        let cluster_size = 1 << self.cluster_bits;
+        let start = host_offset >> self.cluster_bits;
+        let end = (host_offset + len.max(1)).div_ceil(cluster_size);
+        for cluster in start..end {
+            self.add(cluster << self.cluster_bits, owner.clone());
+        }
+    }
+}
+
+impl Qcow2 {
+    /// Build an index of everything referencing each host cluster of the image: the header,
+    /// the refcount table and blocks, the snapshot table, the active and snapshot L1 tables,
+    /// L2 tables, and guest data of the active image and every snapshot.
+    ///
+    /// Every L2 table is read from `reader`, which must be the source file of the qcow. Host
+    /// clusters without any owners are either free or leaked. L2 tables past the end of a
+    /// truncated file are still owners of their clusters, but the guest data they point to is
+    /// left out.
+    ///
+    /// ## Example
+    ///
+    /// ```rust
+    /// use std::io::{Cursor, Write};
+    /// use qcow::{ClusterOwner, CreateOptions};
+    ///
+    /// let mut file = Cursor::new(Vec::new());
+    /// let mut qcow = qcow::create(&mut file, &CreateOptions::new(16 << 20))?;
+    ///
+    /// let mut writer = qcow.writer(&mut file)?;
+    /// writer.write_all(&[0x55; 0x10000])?;
+    /// writer.flush()?;
+    /// drop(writer);
+    ///
+    /// let map = qcow.host_cluster_map(&mut file)?;
+    /// assert_eq!(map.owners(0x10), [ClusterOwner::Header]);
+    ///
+    /// for (host_offset, owners) in map.iter() {
+    ///     println!("{:#x}: {:?}", host_offset, owners);
+    /// }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn host_cluster_map(
+        &self,
+        reader: &mut (impl Read + Seek),
+    ) -> Result<HostClusterMap, Error> {
+        let refcount_table = refcount::read_table(&self.header, reader)?;
+        self.host_cluster_map_with(reader, refcount::allocated_blocks(&refcount_table))
+    }
+
+    /// Build the index of [`Qcow2::host_cluster_map`] given the index within the refcount table
+    /// and host offset of every allocated refcount block
+    pub(crate) fn host_cluster_map_with(
+        &self,
+        reader: &mut (impl Read + Seek),
+        refcount_blocks: impl Iterator<Item = (u64, u64)>,
+    ) -> Result<HostClusterMap, Error> {
+        let cluster_bits = self.header.cluster_bits;
+        let mut map = HostClusterMap {
+            cluster_bits,
+            owners: BTreeMap::new(),
+        };
+
+        map.add(0, ClusterOwner::Header);
+
+        let table_offset = self.header.refcount_table_offset;
+        let table_len = (self.header.refcount_table_clusters as u64) << cluster_bits;
+        map.add_range(table_offset, table_len, ClusterOwner::RefcountTable);
+        for (index, block_offset) in refcount_blocks {
+            map.add(block_offset, ClusterOwner::RefcountBlock(index));
+        }
+
+        if !self.snapshots.is_empty() {
+            let snapshot_table_len: u64 = self
+                .snapshots
+                .iter()
+                .map(|snapshot| snapshot.to_bytes().len() as u64)
+                .sum();
+            let offset = self.header.snapshots_offset;
+            map.add_range(offset, snapshot_table_len, ClusterOwner::SnapshotTable);
+        }
+
+        let l1_len = self.l1_table.len() as u64 * 8;
+        map.add_range(self.header.l1_table_offset, l1_len, ClusterOwner::L1Table);
+        for (index, snapshot) in self.snapshots.iter().enumerate() {
+            let l1_len = snapshot.l1_table.len() as u64 * 8;
+            let owner = ClusterOwner::SnapshotL1Table(index);
+            map.add_range(snapshot.l1_table_offset, l1_len, owner);
+        }
+
+        // L2 tables are often shared between the active image and snapshots, so each is only
+        // read once
+        let mut l2_tables = HashMap::new();
+        let l1_tables = std::iter::once((None, &self.l1_table)).chain(
+            self.snapshots
+                .iter()
+                .enumerate()
+                .map(|(index, snapshot)| (Some(index), &snapshot.l1_table)),
+        );
+        for (snapshot, l1_table) in l1_tables {
+            for (l1_index, l1_entry) in l1_table.iter().enumerate() {
+                if l1_entry.l2_offset == 0 {
+                    continue;
+                }
+
+                let l1_index = l1_index as u64;
+                map.add(
+                    l1_entry.l2_offset,
+                    ClusterOwner::L2Table { snapshot, l1_index },
+                );
+
+                // the guest data of L2 tables past the end of a truncated file is unknown
+                let entries = match l2_tables.entry(l1_entry.l2_offset) {
+                    Entry::Occupied(entries) => entries.into_mut(),
+                    Entry::Vacant(vacant) => vacant.insert(l1_entry.read_l2(reader, cluster_bits)),
+                };
+                let entries = match entries {
+                    Some(entries) => entries,
+                    None => continue,
+                };
+
+                let first_cluster = l1_index * self.l2_entries();
+                for (l2_index, entry) in entries.iter().enumerate() {
+                    let guest_offset = (first_cluster + l2_index as u64) << cluster_bits;
+                    for host_offset in entry.host_clusters(cluster_bits) {
+                        let owner = ClusterOwner::GuestData {
+                            snapshot,
+                            guest_offset,
+                            compressed: entry.is_compressed,
+                        };
+                        map.add(host_offset, owner);
+                    }
+                }
+            }
+        }
+
+        Ok(map)
+    }
+}
@@ -2,10 +2,9 @@ use crate::levels::{L1Entry, L2Entry};
 use crate::*;
 
 use std::convert::TryInto;
-use std::fs::File;
-use std::io::{self, BufReader, Read, Seek};
-
-type BackingReader = Reader<'static, 'static, BufReader<File>>;
+use std::io::{self, Read, Seek};
+use std::ops::{Deref, DerefMut};
+use std::rc::Rc;
 
 /// A reader for reading from the guest virtual drive. Should be constructed using
 /// [`Qcow2::reader`].
@@ -30,12 +29,17 @@ pub struct Reader<'qcow, 'reader, R>
 where
     R: Read + Seek,
 {
-    qcow: &'qcow Qcow2,
+    pub(crate) qcow: MaybeOwned<'qcow, Qcow2>,
+
+    /// reader for the backing file, opened upon first use. If opening it failed, the reason is
+    /// kept instead so the backing file isn't looked for again on every read.
+    backing_reader: Option<Result<Option<Box<BackingReader>>, String>>,
 
-    backing_reader: Option<Box<BackingReader>>,
+    /// resolver used for locating the contents of the backing file
+    resolver: Rc<dyn BackingResolver>,
 
     /// inner reader used for reading/seeking in the host file (the qcow itself)
-    reader: &'reader mut R,
+    pub(crate) reader: MaybeOwnedMut<'reader, R>,
 
     /// current position of the reader within the guest
     pos: u64,
@@ -43,7 +47,7 @@ where
     // l1 key and cache. if l1 is being accessed by something with an outdated key,
     // the l1_cache needs to be refreshed before returning.
     l1_key: u64,
-    l1_cache: &'qcow L1Entry,
+    l1_cache: L1Entry,
     l2_table_cache: Vec<L2Entry>,
 
     // l2 key and cache. if l2 is being accessed by something with an outdated key,
@@ -84,47 +88,49 @@ impl Qcow2 {
     where
         R: Read + Seek,
     {
+        Reader::new(MaybeOwned::Borrowed(self), MaybeOwnedMut::Borrowed(reader))
+    }
+
+    /// Create a reader which owns both the qcow and the reader for its host file, as is needed
+    /// for reading backing files.
+    pub(crate) fn into_reader<R>(self, reader: R) -> Reader<'static, 'static, R>
+    where
+        R: Read + Seek,
+    {
+        Reader::new(
+            MaybeOwned::Owned(Box::new(self)),
+            MaybeOwnedMut::Owned(reader),
+        )
+    }
+}
+
+impl<'qcow, 'reader, R> Reader<'qcow, 'reader, R>
+where
+    R: Read + Seek,
+{
+    fn new(qcow: MaybeOwned<'qcow, Qcow2>, mut reader: MaybeOwnedMut<'reader, R>) -> Self {
         let pos = 0;
         let l1_key = 0;
         let l2_key = 0;
-        let qcow = self;
-        let l1_cache = self
+        let l1_cache = qcow
             .l1_table
             .get(l1_key as usize)
+            .cloned()
             .expect("No L1 table entries found");
-        let l2_table_cache = l1_cache
-            .read_l2(reader, qcow.header.cluster_bits)
-            .expect("No L2 table found");
+        let l2_table_cache = if l1_cache.l2_offset != 0 {
+            l1_cache
+                .read_l2(&mut *reader, qcow.header.cluster_bits)
+                .expect("No L2 table found")
+        } else {
+            Vec::new()
+        };
         let l2_cache = l2_table_cache
             .get(l2_key as usize)
-            .expect("No L2 table entries found")
-            .clone();
-
-        let mut current_cluster = vec![0; self.cluster_size() as usize].into_boxed_slice();
-        l2_cache
-            .read_contents(
-                reader,
-                &mut current_cluster[..],
-                qcow.header
-                    .v3_header
-                    .as_ref()
-                    .map(|hdr| hdr.compression_type)
-                    .unwrap_or_default(),
-            )
-            .or_else(|err| {
-                if self
-                    .header
-                    .backing_file
-                    .as_deref()
-                    .map(|path| Path::new(path).exists())
-                    .unwrap_or(false)
-                {
-                    Ok(())
-                } else {
-                    Err(err)
-                }
-            })
-            .expect("Failed to read first qcow cluster");
+            .cloned()
+            .unwrap_or_else(L2Entry::unallocated);
+
+        // contents are loaded upon the first read
+        let current_cluster = vec![0; qcow.cluster_size() as usize].into_boxed_slice();
 
         Reader {
             qcow,
@@ -137,6 +143,7 @@ impl Qcow2 {
             l2_key,
             current_cluster,
             backing_reader: None,
+            resolver: Rc::new(FileResolver),
         }
     }
 }
@@ -150,21 +157,87 @@ where
         self.pos
     }
 
-    /// Returns a reference to a reader for the backing qcow file, if such a backing file exists.
-    pub fn get_backing_qcow_reader(&mut self) -> Option<&mut BackingReader> {
+    /// Returns the size of the guest virtual hard disk
+    pub fn guest_size(&self) -> u64 {
+        self.qcow.header.size
+    }
+
+    /// Use `resolver` to locate the contents of the backing file (and any backing files further
+    /// down the chain) instead of opening the path stored in the header.
+    ///
+    /// See [`BackingResolver`] for an example.
+    pub fn with_backing_resolver(self, resolver: impl BackingResolver + 'static) -> Self {
+        self.with_shared_resolver(Rc::new(resolver))
+    }
+
+    pub(crate) fn with_shared_resolver(mut self, resolver: Rc<dyn BackingResolver>) -> Self {
+        self.resolver = resolver;
+        self.backing_reader = None;
+        self
+    }
+
+    /// Returns a reference to a reader for the backing file, if such a backing file exists.
+    ///
+    /// The backing file is opened upon first use, honoring the format declared by the
+    /// [`BackingFileFormat`](crate::header_ext::HeaderExt::BackingFileFormat) header extension.
+    /// If it can't be opened, the error is returned and every later call returns
+    /// [`Error::BackingFileUnavailable`] without trying again.
+    pub fn get_backing_reader(&mut self) -> Result<Option<&mut BackingReader>, Error> {
         if self.backing_reader.is_none() {
-            let backing_file = self.qcow.header.backing_file.as_deref()?;
+            match BackingReader::open(&self.qcow.header, Rc::clone(&self.resolver)) {
+                Ok(backing_reader) => self.backing_reader = Some(Ok(backing_reader.map(Box::new))),
+                Err(err) => {
+                    self.backing_reader = Some(Err(err.describe()));
+                    return Err(err);
+                }
+            }
+        }
 
-            // leaking because nobody will open more than 3 in a single run, which is still only a
-            // couple KB of memory.
-            let file = Box::leak(Box::new(BufReader::new(File::open(backing_file).ok()?)));
-            let qcow = Box::leak(Box::new(crate::load(file).ok()?.unwrap_qcow2()));
-            self.backing_reader = Some(Box::new(qcow.reader(file)));
+        match &mut self.backing_reader {
+            Some(Ok(backing_reader)) => Ok(backing_reader.as_deref_mut()),
+            Some(Err(reason)) => Err(Error::BackingFileUnavailable(reason.clone())),
+            None => Ok(None),
+        }
+    }
 
-            println!("Successfully created backing reader");
+    /// Returns a reference to a reader for the backing qcow file, if such a backing file exists
+    /// and is a qcow2 image.
+    pub fn get_backing_qcow_reader(
+        &mut self,
+    ) -> Option<&mut Reader<'static, 'static, Box<dyn ReadSeek>>> {
+        match self.get_backing_reader().ok()? {
+            Some(BackingReader::Qcow2(reader)) => Some(reader),
+            _ => None,
         }
+    }
 
-        self.backing_reader.as_deref_mut()
+    /// Find which layer of the backing chain supplies the contents of the guest at
+    /// `guest_offset`, as an index into the layers returned by [`Qcow2::backing_chain`] (where
+    /// 0 is this qcow).
+    ///
+    /// Returns `None` if no layer supplies the given offset, meaning it reads as zeroes.
+    /// Clusters which are explicitly zeroed are considered to be supplied by the layer in which
+    /// they are marked as such.
+    pub fn supplying_layer(&mut self, guest_offset: u64) -> Result<Option<usize>, Error> {
+        if guest_offset >= self.guest_size() {
+            return Ok(None);
+        }
+
+        if !self
+            .qcow
+            .l2_entry_at(&mut *self.reader, guest_offset)?
+            .is_unallocated()
+        {
+            return Ok(Some(0));
+        }
+
+        let layer = match self.get_backing_reader()? {
+            Some(BackingReader::Qcow2(reader)) => reader.supplying_layer(guest_offset)?,
+            Some(BackingReader::Raw(reader)) => (guest_offset < reader.len()).then_some(0),
+            None => return Ok(None),
+        };
+
+        Ok(layer.map(|layer| layer + 1))
     }
 
     fn update_l1_cache(&mut self) -> io::Result<()> {
@@ -173,19 +246,27 @@ where
 
         if self.l1_key != l1_key {
             self.l1_key = l1_key;
-            self.l1_cache = self.qcow.l1_table.get(l1_key as usize).ok_or_else(|| {
-                io::Error::new(
-                    io::ErrorKind::UnexpectedEof,
-                    "Read position past end of virtual disk",
-                )
-            })?;
-
-            self.l2_table_cache = self
-                .l1_cache
-                .read_l2(self.reader, self.qcow.header.cluster_bits)
+            self.l1_cache = self
+                .qcow
+                .l1_table
+                .get(l1_key as usize)
+                .cloned()
                 .ok_or_else(|| {
-                    io::Error::new(io::ErrorKind::UnexpectedEof, "L2 table could not be read")
+                    io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Read position past end of virtual disk",
+                    )
                 })?;
+
+            self.l2_table_cache = if self.l1_cache.l2_offset != 0 {
+                self.l1_cache
+                    .read_l2(&mut *self.reader, self.qcow.header.cluster_bits)
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::UnexpectedEof, "L2 table could not be read")
+                    })?
+            } else {
+                Vec::new()
+            };
         }
 
         Ok(())
@@ -197,34 +278,30 @@ where
         let l2_index = l2_key % l2_entries;
 
         if self.l2_key != l2_key {
-            self.l2_key = l2_key;
             self.update_l1_cache()?;
-            if self.l1_cache.l2_offset != 0 {
-                self.l2_cache = self.l2_table_cache[l2_index as usize].clone();
-                self.l2_key = l2_key;
-            }
+            self.l2_cache = self
+                .l2_table_cache
+                .get(l2_index as usize)
+                .cloned()
+                .unwrap_or_else(L2Entry::unallocated);
+            self.l2_key = l2_key;
         }
 
-        if self.l1_cache.l2_offset == 0 {
+        if self.l2_cache.is_unallocated() {
             if self.qcow.header.backing_file.is_some() {
                 return Err(io::Error::new(
                     io::ErrorKind::NotFound,
-                    "l2_offset == 0, backing file expected",
+                    "cluster is unallocated, backing file expected",
                 ));
             } else {
-                // empty cluster?
+                // unallocated clusters without a backing file read as zeroes
                 self.current_cluster.fill(0);
             }
         } else {
             self.l2_cache.read_contents(
-                self.reader,
+                &mut *self.reader,
                 &mut self.current_cluster[..],
-                self.qcow
-                    .header
-                    .v3_header
-                    .as_ref()
-                    .map(|hdr| hdr.compression_type)
-                    .unwrap_or_default(),
+                self.qcow.header.compression_type(),
             )?;
         }
 
@@ -247,6 +324,10 @@ where
     R: Read + Seek,
 {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos >= self.guest_size() {
+            return Ok(0);
+        }
+
         match self.update_l2_cache() {
             Ok(()) => {
                 let cluster_size = self.cluster_size();
@@ -254,6 +335,7 @@ where
                 let bytes_remaining_in_cluster = cluster_size - pos_in_cluster;
 
                 let read_len = u64::min(bytes_remaining_in_cluster, buf.len() as u64);
+                let read_len = u64::min(read_len, self.guest_size() - self.pos);
                 let read_end: usize = (pos_in_cluster + read_len).try_into().unwrap();
                 let pos_in_cluster: usize = pos_in_cluster.try_into().unwrap();
 
@@ -265,18 +347,32 @@ where
 
                 Ok(read_len as usize)
             }
-            Err(err) => (move || {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
                 let pos = self.pos;
-                let reader = self.get_backing_qcow_reader()?;
-
-                reader.seek(SeekFrom::Start(pos)).ok()?;
-                let bytes_read = reader.read(buf).ok()?;
+                let cluster_size = self.cluster_size();
+                let read_len = u64::min(cluster_size - (pos % cluster_size), buf.len() as u64);
+                let read_len = u64::min(read_len, self.guest_size() - pos) as usize;
+
+                let reader = self
+                    .get_backing_reader()
+                    .map_err(io::Error::other)?
+                    .ok_or(err)?;
+
+                reader.seek(SeekFrom::Start(pos))?;
+                let bytes_read = match reader.read(&mut buf[..read_len])? {
+                    // anything past the end of a smaller backing file reads as zeroes
+                    0 => {
+                        buf[..read_len].fill(0);
+                        read_len
+                    }
+                    bytes_read => bytes_read,
+                };
 
                 self.pos += bytes_read as u64;
 
-                Some(bytes_read)
-            })()
-            .ok_or(err),
+                Ok(bytes_read)
+            }
+            Err(err) => Err(err),
         }
     }
 }
@@ -323,3 +419,46 @@ where
         Ok(self.pos)
     }
 }
+
+/// A value which is either borrowed or owned by a [`Reader`]
+pub(crate) enum MaybeOwned<'a, T> {
+    Borrowed(&'a T),
+    Owned(Box<T>),
+}
+
+impl<T> Deref for MaybeOwned<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match self {
+            Self::Borrowed(value) => value,
+            Self::Owned(value) => value,
+        }
+    }
+}
+
+/// A value which is either mutably borrowed or owned by a [`Reader`]
+pub(crate) enum MaybeOwnedMut<'a, T> {
+    Borrowed(&'a mut T),
+    Owned(T),
+}
+
+impl<T> Deref for MaybeOwnedMut<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match self {
+            Self::Borrowed(value) => value,
+            Self::Owned(value) => value,
+        }
+    }
+}
+
+impl<T> DerefMut for MaybeOwnedMut<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        match self {
+            Self::Borrowed(value) => value,
+            Self::Owned(value) => value,
+        }
+    }
+}
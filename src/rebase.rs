@@ -0,0 +1,226 @@
+use crate::header_ext::HeaderExt;
+use crate::*;
+
+use std::fs::OpenOptions;
+use std::io::{self, Read, Seek, Write};
+use std::rc::Rc;
+
+/// Maximum length of a backing file name stored in the header
+const MAX_BACKING_FILE_LEN: usize = 1023;
+
+/// How an overlay should be moved onto a new backing file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RebaseMode {
+    /// Copy any clusters which differ between the old and new backing files into the overlay,
+    /// so that the contents of the guest are unchanged
+    Safe,
+
+    /// Only change the backing file stored in the header, for when the new backing file has the
+    /// same contents as the old one (such as when it has been moved or renamed)
+    Unsafe,
+}
+
+impl<'qcow, 'file, F> Writer<'qcow, 'file, F>
+where
+    F: Read + Write + Seek,
+{
+    /// Move the image onto the backing file `backing_file` (or onto no backing file at all),
+    /// returning the number of guest bytes copied into the image.
+    ///
+    /// Every cluster not allocated in the image is read from both the old and new backing files,
+    /// and copied into the image if they differ, so that the contents of the guest are left
+    /// unchanged. Both backing files are located using the writer's [`BackingResolver`].
+    ///
+    /// If `format` is `None` the format detected from the new backing file is recorded in the
+    /// [`BackingFileFormat`](HeaderExt::BackingFileFormat) header extension.
+    pub fn rebase(
+        &mut self,
+        backing_file: Option<&str>,
+        format: Option<BackingFormat>,
+    ) -> Result<u64, Error> {
+        let mut new_backing = match backing_file {
+            Some(backing_file) => {
+                let source = self.resolver.resolve(backing_file).map_err(Error::FileNotFound)?;
+                Some(BackingReader::from_source(source, format, Rc::clone(&self.resolver))?)
+            }
+            None => None,
+        };
+        let format = new_backing.as_ref().map(BackingReader::format);
+        self.check_backing_file(backing_file, format.as_ref())?;
+
+        let cluster_size = self.cluster_size();
+        let guest_clusters = self.guest_size().div_ceil(cluster_size);
+        let mut new_cluster = vec![0; cluster_size as usize];
+        let mut copied = 0;
+
+        for guest_cluster in 0..guest_clusters {
+            if !self.l2_entry(guest_cluster)?.is_unallocated() {
+                continue;
+            }
+
+            let old_cluster = self.read_cluster(guest_cluster)?;
+            match &mut new_backing {
+                Some(new_backing) => {
+                    new_backing.seek(SeekFrom::Start(guest_cluster * cluster_size))?;
+                    new_backing.read_exact(&mut new_cluster)?;
+                }
+                None => new_cluster.fill(0),
+            }
+
+            // only the part of the final cluster within the guest is visible
+            let guest_offset = guest_cluster * cluster_size;
+            let len = u64::min(cluster_size, self.guest_size() - guest_offset) as usize;
+            if old_cluster[..len] != new_cluster[..len] {
+                self.write_cluster(guest_cluster, &old_cluster)?;
+                copied += len as u64;
+            }
+        }
+
+        self.set_backing_file(backing_file, format);
+
+        Ok(copied)
+    }
+
+    /// Change the backing file stored in the header to `backing_file` (or remove it), without
+    /// checking whether the contents of the new backing file match the old one.
+    ///
+    /// If `format` is `None` the [`BackingFileFormat`](HeaderExt::BackingFileFormat) header
+    /// extension is removed, leaving the format to be detected from the backing file.
+    pub fn rebase_unsafe(
+        &mut self,
+        backing_file: Option<&str>,
+        format: Option<BackingFormat>,
+    ) -> Result<(), Error> {
+        self.check_backing_file(backing_file, format.as_ref())?;
+        self.set_backing_file(backing_file, format);
+
+        Ok(())
+    }
+
+    /// Ensure the given backing file can be stored in the header
+    fn check_backing_file(
+        &self,
+        backing_file: Option<&str>,
+        format: Option<&BackingFormat>,
+    ) -> Result<(), Error> {
+        let backing_file_len = backing_file.map_or(0, str::len);
+        if backing_file_len > MAX_BACKING_FILE_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "backing file name is {} bytes, longer than the maximum of {}",
+                    backing_file_len, MAX_BACKING_FILE_LEN
+                ),
+            )
+            .into());
+        }
+
+        // the header is always written in its entirety within the first cluster, so the
+        // existing header (minus the old backing file) gives the space used by everything else
+        let header = &self.qcow.header;
+        let old_format_len = header
+            .extensions
+            .iter()
+            .filter(|ext| matches!(ext, HeaderExt::BackingFileFormat(_)))
+            .map(ext_len)
+            .sum::<usize>();
+        let new_format_len = format.map_or(0, |format| {
+            ext_len(&HeaderExt::BackingFileFormat(format.name().to_owned()))
+        });
+        let old_backing_len = header.backing_file.as_deref().map_or(0, str::len);
+
+        let len = header.to_bytes().len() - old_format_len - old_backing_len
+            + new_format_len
+            + backing_file_len;
+        if len as u64 > self.cluster_size() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "header, header extensions and backing file name do not fit in the first cluster",
+            )
+            .into());
+        }
+
+        Ok(())
+    }
+
+    fn set_backing_file(&mut self, backing_file: Option<&str>, format: Option<BackingFormat>) {
+        let header = &mut self.qcow.header;
+        header.backing_file = backing_file.map(str::to_owned);
+        header
+            .extensions
+            .retain(|ext| !matches!(ext, HeaderExt::BackingFileFormat(_)));
+
+        if let Some(format) = format {
+            let end = header
+                .extensions
+                .iter()
+                .position(HeaderExt::is_end)
+                .unwrap_or(header.extensions.len());
+            header
+                .extensions
+                .insert(end, HeaderExt::BackingFileFormat(format.name().to_owned()));
+        }
+
+        self.header_dirty = true;
+        self.backing_reader = None;
+    }
+}
+
+/// Get the number of bytes a header extension takes up in the header
+fn ext_len(ext: &HeaderExt) -> usize {
+    let mut bytes = Vec::new();
+    ext.write_to(&mut bytes);
+
+    bytes.len()
+}
+
+/// Move the qcow at `path` onto the backing file `backing_file`, or onto no backing file if
+/// `None`. Returns the number of guest bytes copied into the qcow, which is always 0 for
+/// [`RebaseMode::Unsafe`].
+///
+/// Both the old and new backing files are opened using the paths as stored in the header. See
+/// [`Writer::rebase`] for using a custom [`BackingResolver`].
+///
+/// ## Example
+///
+/// ```rust,no_run
+/// use qcow::{BackingFormat, RebaseMode};
+///
+/// // the base image has been moved, but its contents are unchanged
+/// qcow::rebase(
+///     "experiment.qcow2",
+///     Some("/images/base.qcow2"),
+///     Some(BackingFormat::Qcow2),
+///     RebaseMode::Unsafe,
+/// )?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn rebase(
+    path: impl AsRef<Path>,
+    backing_file: Option<&str>,
+    format: Option<BackingFormat>,
+    mode: RebaseMode,
+) -> Result<u64, Error> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .map_err(Error::FileNotFound)?;
+
+    let mut qcow = match load(&mut BufReader::new(&mut file))? {
+        DynamicQcow::Qcow2(qcow) => qcow,
+        DynamicQcow::Qcow1(_) => return Err(Error::UnsupportedFeature("qcow version 1")),
+    };
+
+    let mut writer = qcow.writer(&mut file)?;
+    let copied = match mode {
+        RebaseMode::Safe => writer.rebase(backing_file, format)?,
+        RebaseMode::Unsafe => {
+            writer.rebase_unsafe(backing_file, format)?;
+            0
+        }
+    };
+    writer.sync()?;
+
+    Ok(copied)
+}
@@ -0,0 +1,226 @@
+use crate::refcount::Refcounts;
+use crate::*;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryInto;
+use std::io::{Read, Seek};
+
+/// A host cluster with non-zero contents which nothing in the qcow references, as returned by
+/// [`Qcow2::orphaned_clusters`]
+#[derive(Debug, Clone)]
+pub struct OrphanedCluster {
+    /// Offset of the cluster within the image file
+    pub host_offset: u64,
+
+    /// Refcount of the cluster. Non-zero for leaked clusters, which are still counted as in use
+    /// despite nothing referencing them.
+    pub refcount: u64,
+
+    /// Whether the contents of the cluster look like a stale L1 or L2 table
+    pub looks_like_table: bool,
+
+    /// Stale L2 entries which once pointed to the cluster, found within other orphaned
+    /// clusters which look like L2 tables
+    pub former_owners: Vec<FormerOwner>,
+}
+
+impl OrphanedCluster {
+    /// Returns true if the cluster is leaked, meaning it still has a non-zero refcount
+    pub fn is_leaked(&self) -> bool {
+        self.refcount != 0
+    }
+}
+
+/// A stale L2 entry which once pointed to an [`OrphanedCluster`], which can be used to recover
+/// the guest data it held with [`Qcow2::read_former_contents`]
+#[derive(Debug, Clone)]
+pub struct FormerOwner {
+    /// Offset within the image file of the orphaned cluster holding the stale L2 table
+    pub l2_table_offset: u64,
+
+    /// Index of the stale entry within the L2 table
+    pub l2_index: u64,
+
+    /// Index of the L1 entry which pointed to the L2 table, if a stale L1 table pointing to it
+    /// was found. This assumes the stale L1 table started at the beginning of its cluster.
+    pub l1_index: Option<u64>,
+
+    /// Offset of the guest cluster the entry described, if the L1 index is known
+    pub guest_offset: Option<u64>,
+
+    /// The stale L2 entry itself
+    pub l2_entry: L2Entry,
+}
+
+impl Qcow2 {
+    /// Find every host cluster up to the end of the image file which isn't referenced by any
+    /// metadata, L2 table or snapshot of the image but still holds non-zero data, such as the
+    /// data of a deleted snapshot.
+    ///
+    /// Orphaned clusters which look like L2 tables are parsed to guess which of the other
+    /// orphaned clusters each of their entries pointed to, and orphaned clusters which look
+    /// like L1 tables are used to guess the guest offsets those L2 tables covered. These are
+    /// only guesses, as nothing distinguishes a stale table from data which happens to look
+    /// like one.
+    ///
+    /// `reader` must be the source file of the qcow. Every cluster of the file is read.
+    ///
+    /// ## Example
+    ///
+    /// ```rust
+    /// use std::io::Cursor;
+    /// use qcow::CreateOptions;
+    ///
+    /// let mut file = Cursor::new(Vec::new());
+    /// let qcow = qcow::create(&mut file, &CreateOptions::new(16 << 20))?;
+    ///
+    /// // a cluster left over from something which no longer exists
+    /// file.get_mut().extend_from_slice(&[0x55; 0x10000]);
+    ///
+    /// let orphans = qcow.orphaned_clusters(&mut file)?;
+    /// assert_eq!(orphans.len(), 1);
+    /// assert!(!orphans[0].is_leaked());
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn orphaned_clusters(
+        &self,
+        reader: &mut (impl Read + Seek),
+    ) -> Result<Vec<OrphanedCluster>, Error> {
+        let cluster_bits = self.header.cluster_bits;
+        let cluster_size = self.cluster_size();
+        let file_len = reader.seek(SeekFrom::End(0))?;
+        let refcounts = Refcounts::load_truncated(&self.header, reader)?;
+        let owners = self.host_cluster_map_with(reader, refcounts.blocks())?;
+
+        let mut orphans = BTreeMap::new();
+        let mut tables = BTreeMap::new();
+        let mut cluster = Vec::with_capacity(cluster_size as usize);
+        for host_offset in (0..file_len).step_by(cluster_size as usize) {
+            if !owners.owners(host_offset).is_empty() {
+                continue;
+            }
+
+            cluster.clear();
+            reader.seek(SeekFrom::Start(host_offset))?;
+            (&mut *reader)
+                .take(cluster_size)
+                .read_to_end(&mut cluster)?;
+            if cluster.iter().all(|&byte| byte == 0) {
+                continue;
+            }
+
+            cluster.resize(cluster_size as usize, 0);
+            if let Some(entries) = parse_table(&cluster, cluster_bits, file_len) {
+                tables.insert(host_offset, entries);
+            }
+
+            let orphan = OrphanedCluster {
+                host_offset,
+                refcount: refcounts.get(host_offset),
+                looks_like_table: tables.contains_key(&host_offset),
+                former_owners: Vec::new(),
+            };
+            orphans.insert(host_offset, orphan);
+        }
+
+        // tables pointing to other tables are L1 tables, giving the L1 index of the L2 tables
+        // they point to
+        let mut l1_tables = HashSet::new();
+        let mut l1_indices = HashMap::new();
+        for (&table_offset, entries) in &tables {
+            for (l1_index, &entry) in entries.iter().enumerate() {
+                let l2_offset = entry & 0x00ff_ffff_ffff_fe00;
+                let is_l1_entry = entry & 0x4000_0000_0000_0001 == 0;
+                if is_l1_entry && l2_offset != 0 && tables.contains_key(&l2_offset) {
+                    l1_indices.entry(l2_offset).or_insert(l1_index as u64);
+                    l1_tables.insert(table_offset);
+                }
+            }
+        }
+
+        for (&l2_table_offset, entries) in &tables {
+            if l1_tables.contains(&l2_table_offset) {
+                continue;
+            }
+
+            let l1_index = l1_indices.get(&l2_table_offset).copied();
+            for (l2_index, &entry) in entries.iter().enumerate() {
+                let l2_entry = L2Entry::from_u64(entry, cluster_bits);
+                let l2_index = l2_index as u64;
+                let guest_offset = l1_index
+                    .map(|l1_index| ((l1_index * self.l2_entries()) + l2_index) << cluster_bits);
+
+                for host_offset in l2_entry.host_clusters(cluster_bits) {
+                    if let Some(orphan) = orphans.get_mut(&host_offset) {
+                        orphan.former_owners.push(FormerOwner {
+                            l2_table_offset,
+                            l2_index,
+                            l1_index,
+                            guest_offset,
+                            l2_entry: l2_entry.clone(),
+                        });
+                    }
+                }
+            }
+        }
+
+        Ok(orphans.into_values().collect())
+    }
+
+    /// Read the guest cluster described by a stale L2 entry into `buf`, decompressing it if
+    /// needed. `buf` should be the size of a cluster, and `reader` must be the source file of
+    /// the qcow.
+    ///
+    /// See [`Qcow2::orphaned_clusters`] for finding stale L2 entries.
+    pub fn read_former_contents(
+        &self,
+        reader: &mut (impl Read + Seek),
+        owner: &FormerOwner,
+        buf: &mut [u8],
+    ) -> Result<(), Error> {
+        let compression_type = self.header.compression_type();
+        owner
+            .l2_entry
+            .read_contents(reader, buf, compression_type)?;
+
+        Ok(())
+    }
+}
+
+/// Parse a cluster as the entries of an L1 or L2 table if every entry is either empty or a
+/// plausible pointer into the image file, and at least one entry points somewhere
+fn parse_table(cluster: &[u8], cluster_bits: u32, file_len: u64) -> Option<Vec<u64>> {
+    let entries: Vec<u64> = cluster
+        .chunks_exact(8)
+        .map(|entry| u64::from_be_bytes(entry.try_into().unwrap()))
+        .collect();
+
+    let mut points_anywhere = false;
+    for &entry in &entries {
+        if entry == 0 {
+            continue;
+        }
+
+        let host_offset = match L2Entry::from_u64(entry, cluster_bits).cluster_descriptor {
+            ClusterDescriptor::Compressed(cluster) => cluster.host_cluster_offset,
+            ClusterDescriptor::Standard(cluster) => {
+                // reserved bits must be clear, and host clusters are aligned
+                let reserved = entry & 0x3f00_0000_0000_01fe != 0;
+                let offset = cluster.host_cluster_offset;
+                if reserved || offset & ((1 << cluster_bits) - 1) != 0 {
+                    return None;
+                }
+
+                offset
+            }
+        };
+
+        if host_offset >= file_len {
+            return None;
+        }
+
+        points_anywhere |= host_offset != 0;
+    }
+
+    points_anywhere.then_some(entries)
+}
@@ -0,0 +1,421 @@
+use crate::*;
+
+use std::fs::OpenOptions;
+use std::io::{self, Read, Seek, Write};
+use std::rc::Rc;
+
+/// Options for rewriting a qcow2 image into a new qcow2 image with a different encoding, for use
+/// with [`Qcow2::reencode`] or [`reencode`].
+///
+/// ## Example
+///
+/// ```rust,no_run
+/// use qcow::ReencodeOptions;
+///
+/// let qcow = qcow::open("archive.qcow2")?.unwrap_qcow2();
+///
+/// // keep everything the same except for using 64 KiB clusters
+/// let options = ReencodeOptions {
+///     cluster_bits: 16,
+///     ..ReencodeOptions::matching(&qcow)
+/// };
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct ReencodeOptions {
+    /// Number of bits used for addressing within a cluster (1 << cluster_bits is the cluster
+    /// size). Must be between 9 and 21.
+    pub cluster_bits: u32,
+
+    /// Version of the qcow2 format, either 2 or 3
+    pub version: u32,
+
+    /// Width of each refcount entry as a power of two number of bits. Must be 4 for version 2
+    /// images.
+    pub refcount_order: u32,
+
+    /// Compression to use for every cluster, or `None` to store clusters uncompressed. Zstd
+    /// requires a version 3 image.
+    pub compression: Option<CompressionType>,
+}
+
+impl ReencodeOptions {
+    /// Options keeping the cluster size, version and refcount width of `qcow`, with every
+    /// cluster stored uncompressed
+    pub fn matching(qcow: &Qcow2) -> Self {
+        Self {
+            cluster_bits: qcow.header.cluster_bits,
+            version: qcow.header.version,
+            refcount_order: qcow.header.refcount_order(),
+            compression: None,
+        }
+    }
+}
+
+impl Qcow2 {
+    /// Rewrite the image into a new qcow2 image in `output` using the cluster size, version,
+    /// refcount width and compression given by `options`, returning the parsed new image.
+    /// `output` should be empty, and must be readable and writable. `reader` must be the source
+    /// file of the qcow.
+    ///
+    /// The new image keeps the same backing file, with the same clusters of the guest allocated
+    /// (rounded out to the new cluster size). Internal snapshots are preserved along with their
+    /// VM state, and clusters which are unchanged between snapshots remain shared. Clusters
+    /// which read as zeroes are stored as zero clusters in version 3 images, or left unallocated
+    /// if there is no backing file.
+    pub fn reencode<R, W>(
+        &self,
+        reader: &mut R,
+        output: &mut W,
+        options: &ReencodeOptions,
+    ) -> Result<Qcow2, Error>
+    where
+        R: Read + Seek,
+        W: Read + Write + Seek,
+    {
+        if self.header.crypt_method != EncryptionMethod::None {
+            return Err(Error::UnsupportedFeature("encryption"));
+        }
+
+        let mut qcow = create(
+            output,
+            &CreateOptions {
+                cluster_bits: options.cluster_bits,
+                version: options.version,
+                refcount_order: options.refcount_order,
+                compression_type: options.compression.unwrap_or_default(),
+                backing_file: self.header.backing_file.clone(),
+                backing_format: self.header.backing_file_format(),
+                ..CreateOptions::new(self.header.size)
+            },
+        )?;
+
+        let mut writer = qcow.writer(output)?;
+        let mut source = SourceReader::new(self, reader);
+        let cluster_size = writer.cluster_size();
+
+        let snapshot_states = self.snapshots.iter().map(|snapshot| {
+            let disk_size = snapshot
+                .extra_data
+                .virtual_disk_size
+                .unwrap_or(self.header.size);
+
+            let state = SourceState {
+                l1_table: &snapshot.l1_table,
+                disk_size,
+                vm_state_offset: vm_state_offset(disk_size, self.header.cluster_bits),
+                vm_state_len: snapshot.vm_state_len(),
+            };
+
+            (state, Some(snapshot))
+        });
+        let active_state = SourceState {
+            l1_table: &self.l1_table,
+            disk_size: self.header.size,
+            vm_state_offset: 0,
+            vm_state_len: 0,
+        };
+        let states: Vec<_> = snapshot_states.chain([(active_state, None)]).collect();
+
+        // the active L1 table must cover the VM state of each snapshot while it is written
+        let extent = states
+            .iter()
+            .map(|(state, _)| {
+                let vm_state_offset = vm_state_offset(state.disk_size, options.cluster_bits);
+                u64::max(state.disk_size, vm_state_offset + state.vm_state_len)
+            })
+            .max()
+            .unwrap_or(0);
+        writer.grow_l1_table(extent.div_ceil(cluster_size * (cluster_size / 8)))?;
+
+        // snapshots are written oldest first, so only the clusters which differ from the
+        // previous snapshot have to be written again
+        for (state, snapshot) in &states {
+            let vm_state_start = vm_state_offset(state.disk_size, options.cluster_bits);
+            let vm_state_clusters = (vm_state_start / cluster_size)
+                ..(vm_state_start + state.vm_state_len).div_ceil(cluster_size);
+
+            copy_state(
+                &mut writer,
+                &mut source,
+                state,
+                vm_state_clusters.clone(),
+                options,
+            )?;
+
+            if let Some(snapshot) = snapshot {
+                writer.create_snapshot(reencoded_snapshot(snapshot, state, options.version))?;
+
+                // like qemu, the VM state is only kept within the snapshot
+                for guest_cluster in vm_state_clusters {
+                    writer.deallocate_cluster(guest_cluster)?;
+                }
+            }
+        }
+
+        writer.sync()?;
+        drop(writer);
+
+        Ok(qcow)
+    }
+}
+
+/// Get the guest offset at which the VM state of a snapshot starts, which is the start of the
+/// first L1 entry past the end of the guest disk
+fn vm_state_offset(disk_size: u64, cluster_bits: u32) -> u64 {
+    let l1_entry_span = 1u64 << (cluster_bits + cluster_bits - 3);
+
+    disk_size.div_ceil(l1_entry_span) * l1_entry_span
+}
+
+/// Build the snapshot table entry for a snapshot being copied into the new image
+fn reencoded_snapshot(snapshot: &Snapshot, state: &SourceState, version: u32) -> Snapshot {
+    // version 3 requires the extra data to include the VM state and virtual disk sizes
+    let extra_data_size = if version == 3 {
+        snapshot.extra_data_size.max(16)
+    } else {
+        snapshot.extra_data_size
+    };
+
+    Snapshot {
+        l1_table_offset: 0,
+        l1_table: Vec::new(),
+        time: snapshot.time,
+        guest_runtime: snapshot.guest_runtime,
+        vm_state_size: snapshot.vm_state_size,
+        extra_data_size,
+        extra_data_bytes: snapshot.extra_data_bytes.clone(),
+        extra_data: SnapshotExtraData {
+            vm_state_size: state.vm_state_len,
+            virtual_disk_size: Some(state.disk_size),
+            instruction_count: snapshot.extra_data.instruction_count,
+        },
+        unique_id: snapshot.unique_id.clone(),
+        name: snapshot.name.clone(),
+    }
+}
+
+/// Bring every cluster of the active image in line with the given state of the source image,
+/// writing only the clusters whose contents differ
+fn copy_state<R, F>(
+    writer: &mut Writer<'_, '_, F>,
+    source: &mut SourceReader<'_, R>,
+    state: &SourceState,
+    vm_state_clusters: std::ops::Range<u64>,
+    options: &ReencodeOptions,
+) -> Result<(), Error>
+where
+    R: Read + Seek,
+    F: Read + Write + Seek,
+{
+    let cluster_size = writer.cluster_size();
+    let guest_clusters = writer.qcow.l1_table.len() as u64 * writer.qcow.l2_entries();
+    let disk_clusters = state.disk_size.div_ceil(cluster_size);
+
+    for guest_cluster in 0..guest_clusters {
+        let mut cluster = vec![0; cluster_size as usize];
+        let allocated = if guest_cluster < disk_clusters {
+            let offset = guest_cluster * cluster_size;
+            let len = u64::min(cluster_size, state.disk_size - offset) as usize;
+            source.read(state.l1_table, offset, &mut cluster[..len], true)?
+        } else if vm_state_clusters.contains(&guest_cluster) {
+            let offset = (guest_cluster - vm_state_clusters.start) * cluster_size;
+            let len = u64::min(cluster_size, state.vm_state_len - offset) as usize;
+            let source_offset = state.vm_state_offset + offset;
+            source.read(state.l1_table, source_offset, &mut cluster[..len], false)?
+        } else {
+            false
+        };
+
+        if !allocated {
+            writer.deallocate_cluster(guest_cluster)?;
+            continue;
+        }
+
+        if !writer.l2_entry(guest_cluster)?.is_unallocated()
+            && writer.read_cluster(guest_cluster)? == cluster
+        {
+            continue;
+        }
+
+        if cluster.iter().all(|&byte| byte == 0) {
+            if writer.qcow.header.backing_file.is_none() {
+                writer.deallocate_cluster(guest_cluster)?;
+                continue;
+            }
+
+            if options.version == 3 {
+                writer.zero_cluster(guest_cluster)?;
+                continue;
+            }
+        }
+
+        match options.compression {
+            Some(_) => writer.write_compressed_cluster(guest_cluster, &cluster)?,
+            None => writer.write_cluster(guest_cluster, &cluster)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// One of the states of the guest captured by the source image, either a snapshot or the active
+/// image
+struct SourceState<'a> {
+    l1_table: &'a [L1Entry],
+    disk_size: u64,
+
+    /// guest offset of the VM state within the source image
+    vm_state_offset: u64,
+    vm_state_len: u64,
+}
+
+/// Reads the contents of the source image for any of its states
+struct SourceReader<'qcow, R> {
+    qcow: &'qcow Qcow2,
+    reader: &'qcow mut R,
+    backing_reader: Option<BackingReader>,
+
+    /// most recently read host cluster, keyed by its L2 entry, so clusters shared between
+    /// snapshots or split into several smaller clusters are only read once
+    cached_cluster: Option<(u64, Vec<u8>)>,
+}
+
+impl<'qcow, R> SourceReader<'qcow, R>
+where
+    R: Read + Seek,
+{
+    fn new(qcow: &'qcow Qcow2, reader: &'qcow mut R) -> Self {
+        Self {
+            qcow,
+            reader,
+            backing_reader: None,
+            cached_cluster: None,
+        }
+    }
+
+    /// Read `buf.len()` bytes of the given state starting at `offset`, with unallocated clusters
+    /// read from the backing file if `backed` is set or as zeroes otherwise. Returns false
+    /// without reading anything if none of the range is allocated in the image.
+    fn read(
+        &mut self,
+        l1_table: &[L1Entry],
+        offset: u64,
+        buf: &mut [u8],
+        backed: bool,
+    ) -> Result<bool, Error> {
+        let cluster_bits = self.qcow.header.cluster_bits;
+        let cluster_size = self.qcow.cluster_size();
+        let l2_entries = self.qcow.l2_entries();
+
+        let mut pieces = Vec::new();
+        let mut pos = offset;
+        let end = offset + buf.len() as u64;
+        while pos < end {
+            let len = u64::min(cluster_size - (pos % cluster_size), end - pos);
+            let guest_cluster = pos >> cluster_bits;
+            let entry = match l1_table.get((guest_cluster / l2_entries) as usize) {
+                Some(l1_entry) => {
+                    l1_entry.read_l2_entry(self.reader, guest_cluster % l2_entries, cluster_bits)?
+                }
+                None => L2Entry::unallocated(),
+            };
+
+            pieces.push((pos, len, entry));
+            pos += len;
+        }
+
+        if pieces.iter().all(|(_, _, entry)| entry.is_unallocated()) {
+            return Ok(false);
+        }
+
+        for (pos, len, entry) in pieces {
+            let start = (pos - offset) as usize;
+            let piece = &mut buf[start..start + len as usize];
+
+            if !entry.is_unallocated() {
+                let cluster = self.read_cluster(&entry)?;
+                let pos_in_cluster = (pos % cluster_size) as usize;
+                piece.copy_from_slice(&cluster[pos_in_cluster..pos_in_cluster + piece.len()]);
+            } else if backed {
+                match self.get_backing_reader()? {
+                    Some(backing) => {
+                        backing.seek(SeekFrom::Start(pos))?;
+                        backing.read_exact(piece)?;
+                    }
+                    None => piece.fill(0),
+                }
+            } else {
+                piece.fill(0);
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn read_cluster(&mut self, entry: &L2Entry) -> io::Result<&[u8]> {
+        let key = entry.to_u64(self.qcow.header.cluster_bits) & !(1 << 63);
+        let is_cached = matches!(&self.cached_cluster, Some((cached_key, _)) if *cached_key == key);
+
+        if !is_cached {
+            let mut cluster = vec![0; self.qcow.cluster_size() as usize];
+            entry.read_contents(
+                self.reader,
+                &mut cluster,
+                self.qcow.header.compression_type(),
+            )?;
+            self.cached_cluster = Some((key, cluster));
+        }
+
+        Ok(&self.cached_cluster.as_ref().unwrap().1)
+    }
+
+    fn get_backing_reader(&mut self) -> Result<Option<&mut BackingReader>, Error> {
+        if self.backing_reader.is_none() {
+            self.backing_reader = BackingReader::open(&self.qcow.header, Rc::new(FileResolver))?;
+        }
+
+        Ok(self.backing_reader.as_mut())
+    }
+}
+
+/// Rewrite the qcow2 image at `path` into a new qcow2 image at `output` using the cluster size,
+/// version, refcount width and compression given by `options`, preserving its snapshots.
+/// Returns the parsed new image.
+///
+/// `output` is created if it does not exist, and truncated if it does.
+///
+/// ## Example
+///
+/// ```rust,no_run
+/// use qcow::{CompressionType, ReencodeOptions};
+///
+/// let qcow = qcow::open("archive.qcow2")?.unwrap_qcow2();
+/// let options = ReencodeOptions {
+///     cluster_bits: 16,
+///     compression: Some(CompressionType::Zstd),
+///     ..ReencodeOptions::matching(&qcow)
+/// };
+/// qcow::reencode("archive.qcow2", "archive-64k.qcow2", &options)?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn reencode(
+    path: impl AsRef<Path>,
+    output: impl AsRef<Path>,
+    options: &ReencodeOptions,
+) -> Result<Qcow2, Error> {
+    let mut file = BufReader::new(File::open(path).map_err(Error::FileNotFound)?);
+    let qcow = match load(&mut file)? {
+        DynamicQcow::Qcow2(qcow) => qcow,
+        DynamicQcow::Qcow1(_) => return Err(Error::UnsupportedFeature("qcow version 1")),
+    };
+
+    let mut output = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(output)?;
+
+    qcow.reencode(&mut file, &mut output, options)
+}
@@ -0,0 +1,492 @@
+//! In-memory tracking of the reference counts of host clusters, used for allocating clusters
+//! when modifying an image.
+use crate::*;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryInto;
+use std::io::{self, Write};
+
+/// The reference counts of every host cluster within an image, along with the location of the
+/// refcount table and refcount blocks used to store them.
+#[derive(Debug)]
+pub(crate) struct Refcounts {
+    cluster_bits: u32,
+    refcount_order: u32,
+
+    /// offset of the refcount table within the host file
+    table_offset: u64,
+
+    /// number of clusters occupied by the refcount table
+    table_clusters: u32,
+
+    /// host offsets of each refcount block, 0 if the block is unallocated
+    table: Vec<u64>,
+
+    /// refcount of every host cluster, indexed by host cluster index
+    counts: Vec<u64>,
+
+    dirty_blocks: BTreeSet<usize>,
+    table_dirty: bool,
+
+    /// refcount before being decremented of every host cluster decremented since the last
+    /// [`Refcounts::flush_frees`], which is written in its place until then so the refcounts
+    /// on disk never drop below what the metadata on disk references
+    pending_frees: BTreeMap<usize, u64>,
+
+    /// host offsets of clusters of previous refcount tables, which remain allocated until
+    /// [`Refcounts::flush_frees`] as the header on disk may still point to them
+    stale_table_clusters: Vec<u64>,
+
+    /// index of the lowest host cluster which might be free
+    free_hint: u64,
+}
+
+impl Refcounts {
+    /// Load the refcounts of every cluster in the image, treating any clusters up to `file_len`
+    /// which are not covered by the refcount table as free.
+    pub(crate) fn load(
+        header: &QcowHeader,
+        reader: &mut (impl Read + Seek),
+        file_len: u64,
+    ) -> io::Result<Self> {
+        let cluster_size = header.cluster_size();
+        let table_len = (header.refcount_table_clusters as u64 * cluster_size) / 8;
+
+        let mut table_bytes = vec![0; (table_len * 8) as usize];
+        reader.seek(SeekFrom::Start(header.refcount_table_offset))?;
+        reader.read_exact(&mut table_bytes)?;
+
+        let table = parse_table(&table_bytes);
+        Self::load_blocks(header, reader, table, file_len, |reader, block| {
+            reader.read_exact(block)
+        })
+    }
+
+    /// Load the refcounts of an image whose file may be truncated, for inspecting it without
+    /// modifying it. Refcount table entries and refcounts past the end of the file are treated
+    /// as zero.
+    pub(crate) fn load_truncated(
+        header: &QcowHeader,
+        reader: &mut (impl Read + Seek),
+    ) -> io::Result<Self> {
+        let table = read_table(header, reader)?;
+        Self::load_blocks(header, reader, table, 0, read_partial)
+    }
+
+    /// Read every allocated refcount block of `table` with `read_block`
+    fn load_blocks<R: Read + Seek>(
+        header: &QcowHeader,
+        reader: &mut R,
+        table: Vec<u64>,
+        file_len: u64,
+        read_block: impl Fn(&mut R, &mut [u8]) -> io::Result<()>,
+    ) -> io::Result<Self> {
+        let cluster_size = header.cluster_size();
+        let mut refcounts = Self::empty(header.cluster_bits, header.refcount_order());
+        refcounts.table_offset = header.refcount_table_offset;
+        refcounts.table_clusters = header.refcount_table_clusters;
+
+        let entries_per_block = refcounts.entries_per_block();
+        let mut block = vec![0; cluster_size as usize];
+        for (block_index, block_offset) in allocated_blocks(&table) {
+            reader.seek(SeekFrom::Start(block_offset))?;
+            read_block(reader, &mut block)?;
+
+            let first = block_index as usize * entries_per_block;
+            for i in 0..entries_per_block {
+                let count = refcounts.decode(&block, i);
+                if count != 0 {
+                    refcounts.ensure_len(first + i + 1);
+                    refcounts.counts[first + i] = count;
+                }
+            }
+        }
+
+        refcounts.table = table;
+        refcounts.ensure_len(file_len.div_ceil(cluster_size) as usize);
+        refcounts.table_dirty = false;
+        refcounts.dirty_blocks.clear();
+        refcounts.pending_frees.clear();
+
+        Ok(refcounts)
+    }
+
+    /// Create an empty set of refcounts without a refcount table
+    pub(crate) fn empty(cluster_bits: u32, refcount_order: u32) -> Self {
+        Self {
+            cluster_bits,
+            refcount_order,
+            table_offset: 0,
+            table_clusters: 0,
+            table: Vec::new(),
+            counts: Vec::new(),
+            dirty_blocks: BTreeSet::new(),
+            table_dirty: true,
+            pending_frees: BTreeMap::new(),
+            stale_table_clusters: Vec::new(),
+            free_hint: 0,
+        }
+    }
+
+    /// Get the offset and size (in clusters) of the refcount table
+    pub(crate) fn table_location(&self) -> (u64, u32) {
+        (self.table_offset, self.table_clusters)
+    }
+
+    /// Get the host offsets of every cluster holding the refcount table or a refcount block
+    pub(crate) fn metadata_clusters(&self) -> Vec<u64> {
+        let cluster_size = 1u64 << self.cluster_bits;
+        (0..self.table_clusters as u64)
+            .map(|i| self.table_offset + (i * cluster_size))
+            .chain(self.table.iter().copied().filter(|&offset| offset != 0))
+            .collect()
+    }
+
+    /// Get the index within the refcount table and host offset of every allocated refcount
+    /// block
+    pub(crate) fn blocks(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        allocated_blocks(&self.table)
+    }
+
+    /// Copy the refcount of every cluster into a new set of refcounts using entries of
+    /// `1 << refcount_order` bits, without a refcount table
+    pub(crate) fn with_order(&self, refcount_order: u32) -> Result<Self, Error> {
+        let mut refcounts = Self::empty(self.cluster_bits, refcount_order);
+        for (index, &count) in self.counts.iter().enumerate() {
+            if count != 0 {
+                refcounts.set((index as u64) << self.cluster_bits, count)?;
+            }
+        }
+
+        Ok(refcounts)
+    }
+
+    /// Get the width of each refcount entry as a power of two number of bits
+    pub(crate) fn refcount_order(&self) -> u32 {
+        self.refcount_order
+    }
+
+    /// Get the number of host clusters being tracked, all clusters past this are free
+    pub(crate) fn len(&self) -> u64 {
+        self.counts.len() as u64
+    }
+
+    /// Get the refcount of the host cluster containing `host_offset`
+    pub(crate) fn get(&self, host_offset: u64) -> u64 {
+        self.counts
+            .get((host_offset >> self.cluster_bits) as usize)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Set the refcount of the host cluster containing `host_offset`
+    pub(crate) fn set(&mut self, host_offset: u64, count: u64) -> Result<(), Error> {
+        if count > self.max_refcount() {
+            return Err(Error::RefcountOverflow(host_offset));
+        }
+
+        let index = (host_offset >> self.cluster_bits) as usize;
+        self.ensure_len(index + 1);
+
+        let old_count = self.counts[index];
+        if count < old_count {
+            let pending = self.pending_frees.entry(index).or_insert(old_count);
+            *pending = u64::max(*pending, old_count);
+        }
+
+        self.counts[index] = count;
+        self.dirty_blocks.insert(index / self.entries_per_block());
+
+        if count == 0 {
+            self.free_hint = u64::min(self.free_hint, index as u64);
+        }
+
+        Ok(())
+    }
+
+    /// Increment the refcount of the host cluster containing `host_offset`
+    pub(crate) fn increment(&mut self, host_offset: u64) -> Result<(), Error> {
+        self.set(host_offset, self.get(host_offset) + 1)
+    }
+
+    /// Decrement the refcount of the host cluster containing `host_offset`, returning the new
+    /// refcount.
+    pub(crate) fn decrement(&mut self, host_offset: u64) -> Result<u64, Error> {
+        let count = self.get(host_offset).checked_sub(1).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("refcount of cluster at {:#x} is already zero", host_offset),
+            )
+        })?;
+        self.set(host_offset, count)?;
+
+        Ok(count)
+    }
+
+    /// Returns true if the host cluster containing `host_offset` can be allocated. Clusters
+    /// with a pending free can't be, as metadata on disk may still reference them.
+    pub(crate) fn is_free(&self, host_offset: u64) -> bool {
+        let index = (host_offset >> self.cluster_bits) as usize;
+        self.get(host_offset) == 0 && !self.pending_frees.contains_key(&index)
+    }
+
+    /// Allocate `count` contiguous free host clusters, returning the offset of the first one
+    pub(crate) fn allocate(&mut self, count: u64) -> Result<u64, Error> {
+        let mut start = self.free_hint;
+        let mut index = start;
+        while index < start + count && index < self.len() {
+            if !self.is_free(index << self.cluster_bits) {
+                start = index + 1;
+            }
+
+            index += 1;
+        }
+
+        for index in start..start + count {
+            self.set(index << self.cluster_bits, 1)?;
+        }
+
+        while self.free_hint < self.len() && !self.is_free(self.free_hint << self.cluster_bits) {
+            self.free_hint += 1;
+        }
+
+        Ok(start << self.cluster_bits)
+    }
+
+    /// Write the refcounts out to the image, allocating refcount blocks and relocating the
+    /// refcount table as needed. Returns the location of the refcount table if it was changed.
+    ///
+    /// Only increments are written: decremented refcounts keep their previous value, and any
+    /// previous refcount table stays allocated, until [`Refcounts::flush_frees`] is called once
+    /// the L2 tables, L1 tables and header referencing the new refcounts have been written.
+    /// Interrupting a write part way through can then only leak clusters, never leave clusters
+    /// in use with a refcount of zero.
+    pub(crate) fn flush(
+        &mut self,
+        writer: &mut (impl Write + Seek),
+    ) -> Result<Option<(u64, u32)>, Error> {
+        let cluster_size = 1u64 << self.cluster_bits;
+        let entries_per_block = self.entries_per_block();
+        let old_location = self.table_location();
+
+        // allocating refcount blocks or a new table can require further refcount blocks, so
+        // repeat until every block in use has been allocated
+        loop {
+            let blocks_needed = self.counts.len().div_ceil(entries_per_block);
+            let table_capacity = (self.table_clusters as u64 * cluster_size / 8) as usize;
+
+            if blocks_needed > table_capacity {
+                // leave some room for growth so the table doesn't need to move again soon
+                let clusters = (blocks_needed as u64 * 8).div_ceil(cluster_size) + 1;
+                let offset = self.allocate(clusters)?;
+
+                for i in 0..self.table_clusters as u64 {
+                    let cluster = self.table_offset + (i * cluster_size);
+                    self.stale_table_clusters.push(cluster);
+                }
+
+                self.table_offset = offset;
+                self.table_clusters = clusters as u32;
+                self.table.resize((clusters * cluster_size / 8) as usize, 0);
+                self.table_dirty = true;
+                continue;
+            }
+
+            let missing_block = (0..blocks_needed).find(|&block| {
+                self.table[block] == 0
+                    && self.counts[block * entries_per_block..]
+                        .iter()
+                        .take(entries_per_block)
+                        .any(|&count| count != 0)
+            });
+
+            match missing_block {
+                Some(block) => {
+                    self.table[block] = self.allocate(1)?;
+                    self.dirty_blocks.insert(block);
+                    self.table_dirty = true;
+                }
+                None => break,
+            }
+        }
+
+        let dirty_blocks = std::mem::take(&mut self.dirty_blocks);
+        self.write_blocks(writer, dirty_blocks, true)?;
+
+        if self.table_dirty {
+            let table: Vec<u8> = self
+                .table
+                .iter()
+                .flat_map(|offset| offset.to_be_bytes())
+                .collect();
+
+            writer.seek(SeekFrom::Start(self.table_offset))?;
+            writer.write_all(&table)?;
+            self.table_dirty = false;
+        }
+
+        let new_location = self.table_location();
+        Ok((new_location != old_location).then_some(new_location))
+    }
+
+    /// Write out every refcount decremented since the last call, and free any previous refcount
+    /// tables. Must only be called once nothing written to the image references the freed
+    /// clusters, after [`Refcounts::flush`] and writing the tables and header.
+    pub(crate) fn flush_frees(&mut self, writer: &mut (impl Write + Seek)) -> Result<(), Error> {
+        for cluster in std::mem::take(&mut self.stale_table_clusters) {
+            self.decrement(cluster)?;
+        }
+
+        let entries_per_block = self.entries_per_block();
+        let pending_frees = std::mem::take(&mut self.pending_frees);
+        if let Some(&first) = pending_frees.keys().next() {
+            self.free_hint = u64::min(self.free_hint, first as u64);
+        }
+
+        let blocks: BTreeSet<usize> = pending_frees
+            .into_keys()
+            .map(|index| index / entries_per_block)
+            .chain(std::mem::take(&mut self.dirty_blocks))
+            .collect();
+
+        self.write_blocks(writer, blocks, false)
+    }
+
+    /// Write the given refcount blocks, keeping pending frees at their previous refcount if
+    /// `hold_frees` is set
+    fn write_blocks(
+        &self,
+        writer: &mut (impl Write + Seek),
+        blocks: BTreeSet<usize>,
+        hold_frees: bool,
+    ) -> Result<(), Error> {
+        let cluster_size = 1u64 << self.cluster_bits;
+        let entries_per_block = self.entries_per_block();
+
+        let mut block = vec![0; cluster_size as usize];
+        for block_index in blocks {
+            let block_offset = match self.table.get(block_index) {
+                Some(&offset) if offset != 0 => offset,
+                _ => continue,
+            };
+
+            block.fill(0);
+            let first = block_index * entries_per_block;
+            for i in 0..entries_per_block {
+                if let Some(&count) = self.counts.get(first + i) {
+                    let count = match self.pending_frees.get(&(first + i)) {
+                        Some(&pending) if hold_frees => u64::max(count, pending),
+                        _ => count,
+                    };
+                    self.encode(&mut block, i, count);
+                }
+            }
+
+            writer.seek(SeekFrom::Start(block_offset))?;
+            writer.write_all(&block)?;
+        }
+
+        Ok(())
+    }
+
+    fn ensure_len(&mut self, len: usize) {
+        if self.counts.len() < len {
+            self.counts.resize(len, 0);
+        }
+    }
+
+    fn refcount_bits(&self) -> usize {
+        1 << self.refcount_order
+    }
+
+    /// Get the largest refcount which can be stored for a single cluster
+    pub(crate) fn max_refcount(&self) -> u64 {
+        u64::MAX >> (64 - self.refcount_bits())
+    }
+
+    fn entries_per_block(&self) -> usize {
+        ((1usize << self.cluster_bits) * 8) / self.refcount_bits()
+    }
+
+    fn decode(&self, block: &[u8], index: usize) -> u64 {
+        let bits = self.refcount_bits();
+        if bits < 8 {
+            let byte = block[(index * bits) / 8];
+            ((byte >> ((index * bits) % 8)) & (self.max_refcount() as u8)) as u64
+        } else {
+            let bytes = bits / 8;
+            block[index * bytes..(index + 1) * bytes]
+                .iter()
+                .fold(0, |count, &byte| (count << 8) | byte as u64)
+        }
+    }
+
+    fn encode(&self, block: &mut [u8], index: usize, count: u64) {
+        let bits = self.refcount_bits();
+        if bits < 8 {
+            let shift = (index * bits) % 8;
+            let byte = &mut block[(index * bits) / 8];
+            *byte &= !((self.max_refcount() as u8) << shift);
+            *byte |= (count as u8) << shift;
+        } else {
+            let bytes = bits / 8;
+            block[index * bytes..(index + 1) * bytes]
+                .copy_from_slice(&count.to_be_bytes()[8 - bytes..]);
+        }
+    }
+}
+
+impl QcowHeader {
+    /// Get the width of a refcount entry as a power of two number of bits, which is always 4
+    /// (16-bit refcounts) for version 2 images.
+    pub fn refcount_order(&self) -> u32 {
+        self.v3_header
+            .as_ref()
+            .map_or(4, |v3_header| v3_header.refcount_order)
+    }
+}
+
+/// Read the refcount table of an image, leaving out any entries past the end of the file
+pub(crate) fn read_table(
+    header: &QcowHeader,
+    reader: &mut (impl Read + Seek),
+) -> io::Result<Vec<u64>> {
+    let table_len = header.refcount_table_clusters as u64 * header.cluster_size();
+    let mut table_bytes = Vec::new();
+    reader.seek(SeekFrom::Start(header.refcount_table_offset))?;
+    reader.take(table_len).read_to_end(&mut table_bytes)?;
+
+    Ok(parse_table(&table_bytes))
+}
+
+/// Get the index within `table` and host offset of every allocated refcount block
+pub(crate) fn allocated_blocks(table: &[u64]) -> impl Iterator<Item = (u64, u64)> + '_ {
+    table
+        .iter()
+        .enumerate()
+        .filter(|(_, &offset)| offset != 0)
+        .map(|(index, &offset)| (index as u64, offset))
+}
+
+fn parse_table(table_bytes: &[u8]) -> Vec<u64> {
+    table_bytes
+        .chunks_exact(8)
+        .map(|entry| u64::from_be_bytes(entry.try_into().unwrap()) & 0xffff_ffff_ffff_fe00)
+        .collect()
+}
+
+/// Fill `buf` from `reader`, zeroing whatever is past the end of the file
+fn read_partial(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(len) => filled += len,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+    buf[filled..].fill(0);
+
+    Ok(())
+}
@@ -0,0 +1,182 @@
+use crate::*;
+
+use std::fs::OpenOptions;
+use std::io::{self, Read, Seek, Write};
+
+/// Maximum size of an L1 table in bytes, as enforced by qemu
+const MAX_L1_TABLE_SIZE: u64 = 32 << 20;
+
+/// How clusters past the new end of the guest should be handled when shrinking it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResizeMode {
+    /// Refuse to shrink the guest if any clusters past the new end are allocated
+    Safe,
+
+    /// Discard any clusters past the new end of the guest
+    Force,
+}
+
+impl<'qcow, 'file, F> Writer<'qcow, 'file, F>
+where
+    F: Read + Write + Seek,
+{
+    /// Change the size of the guest virtual hard disk to `size` bytes.
+    ///
+    /// Growing the guest enlarges the L1 table (moving it if it no longer fits in its clusters),
+    /// with the new space reading as zeroes. Where the backing file extends past the old end of
+    /// the guest, the new space covering it is zeroed as by [`write_zeroes`](Self::write_zeroes).
+    /// Shrinking the guest frees every cluster past the new end, and fails with
+    /// [`Error::ShrinkDiscardsData`] if any of them are allocated unless `mode` is
+    /// [`ResizeMode::Force`].
+    ///
+    /// As with qemu, version 2 images with snapshots can't be resized, and images with
+    /// snapshots can't be shrunk.
+    ///
+    /// ## Example
+    ///
+    /// ```rust
+    /// use std::io::Cursor;
+    /// use qcow::{CreateOptions, ResizeMode};
+    ///
+    /// let mut file = Cursor::new(Vec::new());
+    /// let mut qcow = qcow::create(&mut file, &CreateOptions::new(16 << 20))?;
+    ///
+    /// let mut writer = qcow.writer(&mut file)?;
+    /// writer.resize(1 << 30, ResizeMode::Safe)?;
+    /// assert_eq!(writer.guest_size(), 1 << 30);
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn resize(&mut self, size: u64, mode: ResizeMode) -> Result<(), Error> {
+        let old_size = self.guest_size();
+        if size == old_size {
+            return Ok(());
+        }
+
+        if !self.qcow.snapshots.is_empty() {
+            if self.qcow.header.v3_header.is_none() {
+                return Err(Error::UnsupportedFeature(
+                    "snapshots when resizing version 2 images",
+                ));
+            }
+
+            if size < old_size {
+                return Err(Error::UnsupportedFeature("snapshots when shrinking"));
+            }
+        }
+
+        let cluster_size = self.cluster_size();
+        let l1_size = size.div_ceil(cluster_size * self.qcow.l2_entries()).max(1);
+        if l1_size * 8 > MAX_L1_TABLE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "size is too large for the L1 table of the image",
+            )
+            .into());
+        }
+
+        if size > old_size {
+            self.grow(size, l1_size)
+        } else {
+            self.shrink(size, l1_size, mode)
+        }
+    }
+
+    fn grow(&mut self, size: u64, l1_size: u64) -> Result<(), Error> {
+        let cluster_size = self.cluster_size();
+        let old_size = self.guest_size();
+
+        // the last cluster may contain stale data past the old end, which must read as zeroes
+        let tail = old_size % cluster_size;
+        let last_cluster = old_size / cluster_size;
+        if tail != 0 && !self.l2_entry(last_cluster)?.is_unallocated() {
+            let mut cluster = self.read_cluster(last_cluster)?;
+            if cluster[tail as usize..].iter().any(|&byte| byte != 0) {
+                cluster[tail as usize..].fill(0);
+                self.write_cluster(last_cluster, &cluster)?;
+            }
+        }
+
+        self.grow_l1_table(l1_size)?;
+        self.qcow.header.size = size;
+        self.header_dirty = true;
+
+        // as with qemu the new space reads as zeroes, so any part of the backing file which now
+        // falls within the guest has to be hidden
+        let backing_size = match self.get_backing_reader()? {
+            Some(backing) => backing.guest_size(),
+            None => 0,
+        };
+        if backing_size > old_size {
+            let end = u64::min(size, backing_size.div_ceil(cluster_size) * cluster_size);
+            self.write_zeroes(old_size, end - old_size)?;
+        }
+
+        Ok(())
+    }
+
+    fn shrink(&mut self, size: u64, l1_size: u64, mode: ResizeMode) -> Result<(), Error> {
+        let cluster_size = self.cluster_size();
+        let l2_entries = self.qcow.l2_entries();
+        let first_cluster = size.div_ceil(cluster_size);
+        let end_cluster = self.qcow.l1_table.len() as u64 * l2_entries;
+
+        if mode == ResizeMode::Safe {
+            for guest_cluster in first_cluster..end_cluster {
+                // skip over L2 tables which aren't allocated without loading them
+                let l1_entry = &self.qcow.l1_table[(guest_cluster / l2_entries) as usize];
+                if l1_entry.l2_offset == 0 {
+                    continue;
+                }
+
+                if !self.l2_entry(guest_cluster)?.is_unallocated() {
+                    return Err(Error::ShrinkDiscardsData(guest_cluster * cluster_size));
+                }
+            }
+        }
+
+        // clusters sharing an L2 table with the new end are freed individually, while L2 tables
+        // entirely past the new end are freed along with their clusters
+        for guest_cluster in first_cluster..(l1_size * l2_entries) {
+            if self.qcow.l1_table[(guest_cluster / l2_entries) as usize].l2_offset != 0 {
+                self.deallocate_cluster(guest_cluster)?;
+            }
+        }
+        self.truncate_l1_table(l1_size)?;
+        self.qcow.header.size = size;
+        self.header_dirty = true;
+
+        Ok(())
+    }
+}
+
+/// Change the size of the guest virtual hard disk of the qcow2 image at `path` to `size` bytes.
+///
+/// See [`Writer::resize`] for more details.
+///
+/// ## Example
+///
+/// ```rust,no_run
+/// use qcow::ResizeMode;
+///
+/// // grow the guest to 20 GiB
+/// qcow::resize("experiment.qcow2", 20 << 30, ResizeMode::Safe)?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn resize(path: impl AsRef<Path>, size: u64, mode: ResizeMode) -> Result<(), Error> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .map_err(Error::FileNotFound)?;
+
+    let mut qcow = match load(&mut BufReader::new(&mut file))? {
+        DynamicQcow::Qcow2(qcow) => qcow,
+        DynamicQcow::Qcow1(_) => return Err(Error::UnsupportedFeature("qcow version 1")),
+    };
+
+    let mut writer = qcow.writer(&mut file)?;
+    writer.resize(size, mode)?;
+    writer.sync()?;
+
+    Ok(())
+}
@@ -0,0 +1,98 @@
+use crate::*;
+
+use std::io::{Read, Seek, SeekFrom};
+
+impl<'qcow, 'reader, R> Reader<'qcow, 'reader, R>
+where
+    R: Read + Seek,
+{
+    /// Seek forward to the start of the next region of the guest containing data, similar to
+    /// `lseek` with `SEEK_DATA`. If the current position is already within data it is left as is.
+    ///
+    /// Data is anything stored in a layer of the backing chain, whether compressed or not.
+    /// Clusters marked as zeroes and clusters unallocated in every layer are holes. Only the L1
+    /// and L2 tables are read, not the contents of the guest.
+    ///
+    /// Returns the new position, or `None` (leaving the position unchanged) if there is no data
+    /// between the current position and the end of the guest.
+    ///
+    /// ## Example
+    ///
+    /// ```rust
+    /// use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+    /// use qcow::CreateOptions;
+    ///
+    /// let mut file = Cursor::new(Vec::new());
+    /// let mut qcow = qcow::create(&mut file, &CreateOptions::new(16 << 20))?;
+    ///
+    /// let mut writer = qcow.writer(&mut file)?;
+    /// writer.seek(SeekFrom::Start(0x30000))?;
+    /// writer.write_all(&[0x55; 0x10000])?;
+    /// writer.flush()?;
+    /// drop(writer);
+    ///
+    /// // only read the parts of the guest which contain data
+    /// let mut reader = qcow.reader(&mut file);
+    /// while let Some(start) = reader.seek_data()? {
+    ///     let end = reader.seek_hole()?.unwrap();
+    ///     reader.seek(SeekFrom::Start(start))?;
+    ///
+    ///     let mut data = vec![0; (end - start) as usize];
+    ///     reader.read_exact(&mut data)?;
+    ///     assert_eq!((start, end), (0x30000, 0x40000));
+    /// }
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn seek_data(&mut self) -> Result<Option<u64>, Error> {
+        self.seek_to_next(|extent| matches!(extent.kind, ExtentKind::Data | ExtentKind::Compressed))
+    }
+
+    /// Seek forward to the start of the next hole in the guest, similar to `lseek` with
+    /// `SEEK_HOLE`. If the current position is already within a hole it is left as is.
+    ///
+    /// Holes are clusters marked as zeroes and clusters unallocated in every layer of the
+    /// backing chain. As with `lseek`, the end of the guest counts as a hole, so this only
+    /// returns `None` if the current position is at or past the end of the guest. Only the L1
+    /// and L2 tables are read, not the contents of the guest.
+    ///
+    /// See [`Reader::seek_data`] for an example.
+    pub fn seek_hole(&mut self) -> Result<Option<u64>, Error> {
+        if self.guest_pos() >= self.guest_size() {
+            return Ok(None);
+        }
+
+        let hole = self.seek_to_next(|extent| {
+            matches!(extent.kind, ExtentKind::Zero | ExtentKind::Unallocated)
+        })?;
+
+        match hole {
+            Some(offset) => Ok(Some(offset)),
+            None => {
+                let end = self.guest_size();
+                self.seek(SeekFrom::Start(end))?;
+                Ok(Some(end))
+            }
+        }
+    }
+
+    /// Seek to the first offset at or after the current position which is within an extent
+    /// matching the predicate, returning the new position
+    fn seek_to_next(&mut self, predicate: impl Fn(&Extent) -> bool) -> Result<Option<u64>, Error> {
+        let pos = self.guest_pos();
+
+        let mut found = None;
+        for extent in self.allocation_map_from(pos) {
+            let extent = extent?;
+            if predicate(&extent) {
+                found = Some(extent.start.max(pos));
+                break;
+            }
+        }
+
+        if let Some(offset) = found {
+            self.seek(SeekFrom::Start(offset))?;
+        }
+
+        Ok(found)
+    }
+}
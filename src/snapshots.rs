@@ -1,13 +1,14 @@
 use crate::*;
 
+use std::io::{Read, Seek, Write};
+
 /// An entry in the snapshot table representing the system state at a moment in time
 #[derive_binread]
 #[derive(Debug)]
 pub struct Snapshot {
     /// Offset into the image file at which the L1 table for the
     /// snapshot starts. Must be aligned to a cluster boundary.
-    #[br(temp)]
-    l1_table_offset: u64,
+    pub(crate) l1_table_offset: u64,
 
     /// Number of entries in the L1 table of the snapshots
     #[br(temp)]
@@ -40,8 +41,13 @@ pub struct Snapshot {
     /// larger than the virtual disk presented to the guest)
     pub vm_state_size: u32,
 
-    #[br(temp)]
-    extra_data_size: u32,
+    /// Size of the extra data in bytes, including any fields not understood by this crate
+    pub(crate) extra_data_size: u32,
+
+    /// Raw contents of the extra data, so that any fields not understood by this crate are
+    /// preserved when the snapshot table is rewritten
+    #[br(restore_position, count = extra_data_size)]
+    pub(crate) extra_data_bytes: Vec<u8>,
 
     /// Optional extra snapshot data that comes from format updates
     #[br(pad_size_to = extra_data_size)]
@@ -53,14 +59,14 @@ pub struct Snapshot {
     pub unique_id: String,
 
     /// Name of the snapshot
-    #[br(count = name_len, try_map = String::from_utf8)]
+    #[br(count = name_len, try_map = String::from_utf8, align_after = 8)]
     pub name: String,
 }
 
 /// Optional extra snapshot data that comes from format updates
 ///
 /// **Note:** Version 3 snapshots must have both vm_state_size and virtual_disk_size present.
-#[derive(BinRead, Debug)]
+#[derive(BinRead, Debug, Clone)]
 #[br(import(size: u32))]
 pub struct SnapshotExtraData {
     /// Size of the VM state in bytes. 0 if no VM state is saved. If this field is present,
@@ -80,7 +86,7 @@ pub struct SnapshotExtraData {
 
 /// Represents the time a snapshot was taken in the form of seconds, nanoseconds. The nanoseconds
 /// represent the sub-second time of the snapshot.
-#[derive(BinRead, Debug)]
+#[derive(BinRead, Debug, Clone, Copy)]
 pub struct SnapshotTime {
     /// Seconds since the unix epoch
     pub secs: u32,
@@ -88,3 +94,133 @@ pub struct SnapshotTime {
     /// Subsecond portion of time in nanoseconds
     pub nanosecs: u32,
 }
+
+impl Snapshot {
+    /// Size of the VM state in bytes, preferring the 64-bit size from the extra data if present
+    pub fn vm_state_len(&self) -> u64 {
+        if self.extra_data_size >= 8 {
+            self.extra_data.vm_state_size
+        } else {
+            self.vm_state_size as u64
+        }
+    }
+
+    /// Serialize the snapshot table entry, including the padding to a multiple of 8 bytes
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&self.l1_table_offset.to_be_bytes());
+        bytes.extend_from_slice(&(self.l1_table.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&(self.unique_id.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&(self.name.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&self.time.secs.to_be_bytes());
+        bytes.extend_from_slice(&self.time.nanosecs.to_be_bytes());
+        bytes.extend_from_slice(&self.guest_runtime.to_be_bytes());
+        bytes.extend_from_slice(&self.vm_state_size.to_be_bytes());
+        bytes.extend_from_slice(&self.extra_data_size.to_be_bytes());
+
+        // the known fields are updated in place, leaving anything else as it was read
+        let extra_data = &self.extra_data;
+        let mut extra_bytes = self.extra_data_bytes.clone();
+        extra_bytes.resize(self.extra_data_size as usize, 0);
+        let fields = [
+            Some(extra_data.vm_state_size as i64),
+            extra_data.virtual_disk_size.map(|size| size as i64),
+            extra_data.instruction_count,
+        ];
+        for (field, value) in extra_bytes.chunks_exact_mut(8).zip(fields) {
+            if let Some(value) = value {
+                field.copy_from_slice(&value.to_be_bytes());
+            }
+        }
+        bytes.extend_from_slice(&extra_bytes);
+
+        bytes.extend_from_slice(self.unique_id.as_bytes());
+        bytes.extend_from_slice(self.name.as_bytes());
+        bytes.resize(bytes.len().next_multiple_of(8), 0);
+
+        bytes
+    }
+}
+
+impl<'qcow, 'file, F> Writer<'qcow, 'file, F>
+where
+    F: Read + Write + Seek,
+{
+    /// Add `snapshot` to the snapshot table, capturing the current contents of the guest. The
+    /// L1 table of `snapshot` is replaced by a copy of the active L1 table, sharing every L2
+    /// table and data cluster with the active image until they are next written to.
+    pub(crate) fn create_snapshot(&mut self, mut snapshot: Snapshot) -> Result<(), Error> {
+        // the shared L2 tables are read back from the image, so pending changes must be written
+        self.sync()?;
+
+        let cluster_size = self.cluster_size();
+        let l1_table: Vec<L1Entry> = self
+            .qcow
+            .l1_table
+            .iter()
+            .map(|entry| L1Entry::from_u64(entry.l2_offset))
+            .collect();
+
+        let bytes: Vec<u8> = l1_table
+            .iter()
+            .flat_map(|entry| entry.to_u64().to_be_bytes())
+            .collect();
+        let l1_table_offset = self
+            .refcounts
+            .allocate((bytes.len() as u64).div_ceil(cluster_size).max(1))?;
+        self.file.seek(SeekFrom::Start(l1_table_offset))?;
+        self.file.write_all(&bytes)?;
+
+        for (l1_index, l1_entry) in l1_table.iter().enumerate() {
+            if l1_entry.l2_offset == 0 {
+                continue;
+            }
+
+            self.refcounts.increment(l1_entry.l2_offset)?;
+
+            let l1_index = l1_index as u64;
+            self.load_l2_table(l1_index)?;
+            let host_clusters: Vec<u64> = self.l2_tables[&l1_index]
+                .iter()
+                .flat_map(|entry| self.host_clusters(entry))
+                .collect();
+            for host_offset in host_clusters {
+                self.refcounts.increment(host_offset)?;
+            }
+
+            // rewrite the table so the copied flags of its now shared clusters are cleared
+            self.dirty_l2_tables.insert(l1_index);
+        }
+
+        // the table is rewritten elsewhere on sync, as it may no longer fit in place
+        self.release_snapshot_table()?;
+
+        snapshot.l1_table_offset = l1_table_offset;
+        snapshot.l1_table = l1_table;
+        self.qcow.snapshots.push(snapshot);
+        self.snapshots_dirty = true;
+
+        self.sync()
+    }
+
+    /// Free the clusters of the snapshot table, before a new one is written on the next sync
+    pub(crate) fn release_snapshot_table(&mut self) -> Result<(), Error> {
+        if self.qcow.header.snapshots_offset == 0 {
+            return Ok(());
+        }
+
+        let cluster_size = self.cluster_size();
+        let table_len: u64 = self
+            .qcow
+            .snapshots
+            .iter()
+            .map(|snapshot| snapshot.to_bytes().len() as u64)
+            .sum();
+        for cluster in 0..table_len.div_ceil(cluster_size) {
+            self.refcounts
+                .decrement(self.qcow.header.snapshots_offset + (cluster * cluster_size))?;
+        }
+
+        Ok(())
+    }
+}
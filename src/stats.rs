@@ -0,0 +1,208 @@
+use crate::*;
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io::{self, Read, Seek};
+
+/// Statistics about the allocation, compression and fragmentation of a qcow, as returned by
+/// [`Qcow2::statistics`]
+///
+/// Cluster counts cover the guest clusters of the active image, not including anything supplied
+/// by a backing file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImageStatistics {
+    /// Number of clusters in the guest virtual hard disk
+    pub guest_clusters: u64,
+
+    /// Number of guest clusters stored uncompressed within the image
+    pub allocated_clusters: u64,
+
+    /// Number of guest clusters stored compressed within the image
+    pub compressed_clusters: u64,
+
+    /// Number of guest clusters which read as zeroes, whether or not a host cluster is
+    /// preallocated for them
+    pub zero_clusters: u64,
+
+    /// Number of guest clusters not allocated within the image, which read from the backing
+    /// file (or as zeroes if there is no backing file)
+    pub unallocated_clusters: u64,
+
+    /// Number of bytes of the image file used by compressed clusters, from the start of their
+    /// compressed data to the end of their last sector
+    pub compressed_bytes: u64,
+
+    /// Number of bytes the compressed clusters hold once decompressed
+    pub uncompressed_bytes: u64,
+
+    /// Number of distinct L2 tables referenced by the active image or any snapshot
+    pub l2_tables: u64,
+
+    /// Number of runs of uncompressed guest clusters stored back to back within the image
+    /// file, walking the guest in order. Guest clusters which aren't stored uncompressed are
+    /// skipped, so an image whose data is laid out in guest order has a single fragment.
+    pub fragments: u64,
+
+    /// Number of host clusters (of either guest data or L2 tables) referenced by each snapshot
+    /// and by nothing else, in the same order as [`Qcow2::snapshots`]. This is roughly the
+    /// number of clusters freed by deleting the snapshot, not counting its L1 table.
+    pub snapshot_unique_clusters: Vec<u64>,
+}
+
+impl ImageStatistics {
+    /// Get the ratio of the decompressed size of compressed clusters to the space they use
+    /// within the image file, or `None` if there are no compressed clusters
+    pub fn compression_ratio(&self) -> Option<f64> {
+        (self.compressed_bytes != 0)
+            .then(|| self.uncompressed_bytes as f64 / self.compressed_bytes as f64)
+    }
+}
+
+/// What references a host cluster when working out which clusters are unique to a snapshot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Referrer {
+    /// Only referenced by the given snapshot, or the active image if `None`
+    Only(Option<usize>),
+
+    /// Referenced by more than one L1 table
+    Shared,
+}
+
+impl Qcow2 {
+    /// Gather statistics about the image by walking the L1 and L2 tables of the active image
+    /// and every snapshot, such as how many clusters are allocated or compressed, how well
+    /// compressed clusters compress and how fragmented the image file is.
+    ///
+    /// Every L2 table is read from `reader`, which must be the source file of the qcow. Backing
+    /// files are not consulted.
+    ///
+    /// ## Example
+    ///
+    /// ```rust
+    /// use std::io::{Cursor, Write};
+    /// use qcow::CreateOptions;
+    ///
+    /// let mut file = Cursor::new(Vec::new());
+    /// let mut qcow = qcow::create(&mut file, &CreateOptions::new(16 << 20))?;
+    ///
+    /// let mut writer = qcow.writer(&mut file)?;
+    /// writer.write_all(&[0x55; 0x20000])?;
+    /// writer.flush()?;
+    /// drop(writer);
+    ///
+    /// let stats = qcow.statistics(&mut file)?;
+    /// assert_eq!(stats.allocated_clusters, 2);
+    /// assert_eq!(stats.unallocated_clusters, stats.guest_clusters - 2);
+    /// assert_eq!(stats.fragments, 1);
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn statistics(&self, reader: &mut (impl Read + Seek)) -> Result<ImageStatistics, Error> {
+        let cluster_bits = self.header.cluster_bits;
+        let cluster_size = self.cluster_size();
+        let guest_clusters = self.header.size.div_ceil(cluster_size);
+        let mut stats = ImageStatistics {
+            guest_clusters,
+            snapshot_unique_clusters: vec![0; self.snapshots.len()],
+            ..ImageStatistics::default()
+        };
+
+        let mut referrers = HashMap::new();
+        let mut refer = |host_offset: u64, referrer: Option<usize>| {
+            referrers
+                .entry(host_offset)
+                .and_modify(|existing| {
+                    if *existing != Referrer::Only(referrer) {
+                        *existing = Referrer::Shared;
+                    }
+                })
+                .or_insert(Referrer::Only(referrer));
+        };
+
+        // L2 tables are often shared between the active image and snapshots, so each is only
+        // read once
+        let mut l2_tables = HashMap::new();
+        let mut last_host_offset = None;
+        let l1_tables = std::iter::once((None, &self.l1_table)).chain(
+            self.snapshots
+                .iter()
+                .enumerate()
+                .map(|(index, snapshot)| (Some(index), &snapshot.l1_table)),
+        );
+        for (snapshot, l1_table) in l1_tables {
+            for (l1_index, l1_entry) in l1_table.iter().enumerate() {
+                if l1_entry.l2_offset == 0 {
+                    continue;
+                }
+
+                refer(l1_entry.l2_offset, snapshot);
+                let entries = match l2_tables.entry(l1_entry.l2_offset) {
+                    Entry::Occupied(entries) => entries.into_mut(),
+                    Entry::Vacant(vacant) => {
+                        let entries = l1_entry.read_l2(reader, cluster_bits).ok_or_else(|| {
+                            io::Error::new(
+                                io::ErrorKind::UnexpectedEof,
+                                "L2 table could not be read",
+                            )
+                        })?;
+                        vacant.insert(entries)
+                    }
+                };
+
+                for entry in entries.iter() {
+                    for host_offset in entry.host_clusters(cluster_bits) {
+                        refer(host_offset, snapshot);
+                    }
+                }
+
+                if snapshot.is_some() {
+                    continue;
+                }
+
+                let first_cluster = l1_index as u64 * self.l2_entries();
+                for (l2_index, entry) in entries.iter().enumerate() {
+                    if first_cluster + l2_index as u64 >= guest_clusters {
+                        break;
+                    }
+
+                    match &entry.cluster_descriptor {
+                        ClusterDescriptor::Compressed(cluster) => {
+                            let range = cluster.host_range();
+                            stats.compressed_clusters += 1;
+                            stats.compressed_bytes += range.end - range.start;
+                            stats.uncompressed_bytes += cluster_size;
+                        }
+                        ClusterDescriptor::Standard(cluster) if cluster.all_zeroes => {
+                            stats.zero_clusters += 1;
+                        }
+                        ClusterDescriptor::Standard(cluster)
+                            if cluster.host_cluster_offset != 0 =>
+                        {
+                            let host_offset = cluster.host_cluster_offset;
+                            stats.allocated_clusters += 1;
+                            if last_host_offset.map(|last| last + cluster_size) != Some(host_offset)
+                            {
+                                stats.fragments += 1;
+                            }
+                            last_host_offset = Some(host_offset);
+                        }
+                        ClusterDescriptor::Standard(_) => (),
+                    }
+                }
+            }
+        }
+
+        stats.l2_tables = l2_tables.len() as u64;
+        stats.unallocated_clusters = guest_clusters
+            - stats.allocated_clusters
+            - stats.compressed_clusters
+            - stats.zero_clusters;
+
+        for referrer in referrers.into_values() {
+            if let Referrer::Only(Some(index)) = referrer {
+                stats.snapshot_unique_clusters[index] += 1;
+            }
+        }
+
+        Ok(stats)
+    }
+}
@@ -0,0 +1,148 @@
+use crate::*;
+
+use std::io::{self, Read, Seek};
+use std::ops::Range;
+
+/// How the lookup tables of a qcow describe a single byte of the guest and where it is stored
+/// within the image file, as returned by [`Qcow2::guest_to_host`].
+#[derive(Debug, Clone)]
+pub struct Translation {
+    /// Offset of the byte within the guest
+    pub guest_offset: u64,
+
+    /// Index of the L1 entry for the byte within the active L1 table
+    pub l1_index: u64,
+
+    /// The L1 entry pointing to the L2 table for the byte
+    pub l1_entry: L1Entry,
+
+    /// Index of the L2 entry for the byte within its L2 table
+    pub l2_index: u64,
+
+    /// Offset of the L2 entry within the image file, or `None` if no L2 table is allocated
+    pub l2_entry_offset: Option<u64>,
+
+    /// The L2 entry describing the cluster containing the byte, including its decoded
+    /// [`ClusterDescriptor`]
+    pub l2_entry: L2Entry,
+
+    /// Where the byte is stored within the image file
+    pub location: HostLocation,
+}
+
+/// Where a byte of the guest is stored within the image file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostLocation {
+    /// Stored uncompressed at the given offset within the image file
+    Data(u64),
+
+    /// Stored within a compressed cluster
+    Compressed {
+        /// Range of bytes within the image file which may contain the compressed data of the
+        /// cluster. The compressed data may end before the end of the final sector.
+        host_range: Range<u64>,
+
+        /// Offset of the byte within the cluster once decompressed
+        offset_in_cluster: u64,
+    },
+
+    /// Reads as zeroes. If the cluster has a host cluster preallocated for it, this is the
+    /// offset the byte would be stored at, which may still contain stale data.
+    Zero(Option<u64>),
+
+    /// Not allocated within this image, so reads from the backing file (or as zeroes if there
+    /// is no backing file)
+    Unallocated,
+}
+
+impl Qcow2 {
+    /// Look up how the active L1 and L2 tables describe the byte at `guest_offset`, and where it
+    /// is stored within the image file. Only the L2 entry for the byte is read from `reader`.
+    ///
+    /// Backing files are not consulted, so bytes supplied by a backing file are
+    /// [`HostLocation::Unallocated`]. See [`Reader::allocation_map`] for looking up the layer of
+    /// the backing chain supplying each part of the guest.
+    ///
+    /// ## Example
+    ///
+    /// ```rust
+    /// use std::io::{Cursor, Seek, SeekFrom, Write};
+    /// use qcow::{CreateOptions, HostLocation};
+    ///
+    /// let mut file = Cursor::new(Vec::new());
+    /// let mut qcow = qcow::create(&mut file, &CreateOptions::new(16 << 20))?;
+    ///
+    /// let mut writer = qcow.writer(&mut file)?;
+    /// writer.seek(SeekFrom::Start(0x12345))?;
+    /// writer.write_all(b"needle")?;
+    /// writer.flush()?;
+    /// drop(writer);
+    ///
+    /// let translation = qcow.guest_to_host(&mut file, 0x12345)?;
+    /// let start = match translation.location {
+    ///     HostLocation::Data(host_offset) => host_offset as usize,
+    ///     location => panic!("unexpected location {:?}", location),
+    /// };
+    /// assert_eq!(&file.get_ref()[start..start + 6], b"needle");
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn guest_to_host(
+        &self,
+        reader: &mut (impl Read + Seek),
+        guest_offset: u64,
+    ) -> Result<Translation, Error> {
+        if guest_offset >= self.header.size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "offset is past the end of the virtual disk",
+            )
+            .into());
+        }
+
+        let cluster_bits = self.header.cluster_bits;
+        let guest_cluster = guest_offset >> cluster_bits;
+        let offset_in_cluster = guest_offset & (self.cluster_size() - 1);
+        let l1_index = guest_cluster / self.l2_entries();
+        let l2_index = guest_cluster % self.l2_entries();
+
+        let l1_entry = self
+            .l1_table
+            .get(l1_index as usize)
+            .cloned()
+            .unwrap_or_else(|| L1Entry::from_u64(0));
+        let l2_entry = l1_entry.read_l2_entry(reader, l2_index, cluster_bits)?;
+        let l2_entry_offset = match l1_entry.l2_offset {
+            0 => None,
+            l2_offset => Some(l2_offset + (l2_index * 8)),
+        };
+
+        let location = match &l2_entry.cluster_descriptor {
+            ClusterDescriptor::Compressed(cluster) => HostLocation::Compressed {
+                host_range: cluster.host_range(),
+                offset_in_cluster,
+            },
+            ClusterDescriptor::Standard(cluster) => {
+                let host_offset = match cluster.host_cluster_offset {
+                    0 => None,
+                    host_cluster_offset => Some(host_cluster_offset + offset_in_cluster),
+                };
+
+                match host_offset {
+                    _ if cluster.all_zeroes => HostLocation::Zero(host_offset),
+                    Some(host_offset) => HostLocation::Data(host_offset),
+                    None => HostLocation::Unallocated,
+                }
+            }
+        };
+
+        Ok(Translation {
+            guest_offset,
+            l1_index,
+            l1_entry,
+            l2_index,
+            l2_entry_offset,
+            l2_entry,
+            location,
+        })
+    }
+}
@@ -1,6 +1,11 @@
 use binread::derive_binread;
-use crate::EncryptionMethod;
 use crate::header::{read_string, FileString};
+use crate::*;
+
+use flate2::read::DeflateDecoder;
+use std::convert::TryInto;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::rc::Rc;
 
 /// Header for qcow version 1 format
 #[derive_binread]
@@ -50,3 +55,226 @@ pub struct Qcow1Header {
     /// Offset of L1 table used to lookup L2 table offsets
     pub l1_table_offset: u64,
 }
+
+/// A reader for reading from the guest virtual drive of a version 1 qcow. Should be constructed
+/// using [`Qcow1::reader`].
+///
+/// Unallocated clusters are read from the backing file if one is present, or as zeroes
+/// otherwise. Encrypted images are not supported.
+pub struct Qcow1Reader<'qcow, 'reader, R>
+where
+    R: Read + Seek,
+{
+    qcow: &'qcow Qcow1,
+
+    backing_reader: Option<Box<BackingReader>>,
+
+    /// resolver used for locating the contents of the backing file
+    resolver: Rc<dyn BackingResolver>,
+
+    /// inner reader used for reading/seeking in the host file (the qcow itself)
+    reader: &'reader mut R,
+
+    /// current position of the reader within the guest
+    pos: u64,
+
+    /// L1 index and contents of the most recently used L2 table
+    l2_table_cache: Option<(u64, Vec<u64>)>,
+
+    /// guest cluster index and contents of the most recently decompressed cluster
+    compressed_cache: Option<(u64, Box<[u8]>)>,
+}
+
+impl Qcow1 {
+    /// Create a reader for reading from the guest virtual drive
+    ///
+    /// **Note:** if `reader` is not identical to the source file unexpected things will happen.
+    pub fn reader<'qcow, 'reader, R>(
+        &'qcow self,
+        reader: &'reader mut R,
+    ) -> Qcow1Reader<'qcow, 'reader, R>
+    where
+        R: Read + Seek,
+    {
+        Qcow1Reader {
+            qcow: self,
+            backing_reader: None,
+            resolver: Rc::new(FileResolver),
+            reader,
+            pos: 0,
+            l2_table_cache: None,
+            compressed_cache: None,
+        }
+    }
+}
+
+/// Bit of an L2 entry marking the cluster as compressed
+const COMPRESSED: u64 = 1 << 63;
+
+impl<'qcow, 'reader, R> Qcow1Reader<'qcow, 'reader, R>
+where
+    R: Read + Seek,
+{
+    /// Returns the current read position within the guest virtual hard disk
+    pub fn guest_pos(&self) -> u64 {
+        self.pos
+    }
+
+    /// Returns the size of the guest virtual hard disk
+    pub fn guest_size(&self) -> u64 {
+        self.qcow.header.size
+    }
+
+    /// Get the size of a cluster within the qcow
+    pub fn cluster_size(&self) -> u64 {
+        self.qcow.cluster_size()
+    }
+
+    /// Use `resolver` to locate the contents of the backing file (and any backing files further
+    /// down the chain) instead of opening the path stored in the header.
+    ///
+    /// See [`BackingResolver`] for an example.
+    pub fn with_backing_resolver(mut self, resolver: impl BackingResolver + 'static) -> Self {
+        self.resolver = Rc::new(resolver);
+        self.backing_reader = None;
+        self
+    }
+
+    /// Returns a reference to a reader for the backing file, if such a backing file exists.
+    ///
+    /// As version 1 images can't declare the format of their backing file, it is detected from
+    /// the contents of the backing file.
+    pub fn get_backing_reader(&mut self) -> Result<Option<&mut BackingReader>, Error> {
+        if self.backing_reader.is_none() {
+            if let Some(backing_file) = &self.qcow.header.backing_file {
+                let source = self.resolver.resolve(backing_file).map_err(Error::FileNotFound)?;
+                let reader = BackingReader::from_source(source, None, Rc::clone(&self.resolver))?;
+                self.backing_reader = Some(Box::new(reader));
+            }
+        }
+
+        Ok(self.backing_reader.as_deref_mut())
+    }
+
+    /// Get the L2 entry describing the given guest cluster, 0 if the cluster is unallocated
+    fn l2_entry(&mut self, guest_cluster: u64) -> io::Result<u64> {
+        let l2_bits = self.qcow.header.l2_bits as u32;
+        let l1_index = guest_cluster >> l2_bits;
+        let l2_index = (guest_cluster & ((1 << l2_bits) - 1)) as usize;
+
+        if !matches!(&self.l2_table_cache, Some((index, _)) if *index == l1_index) {
+            let l2_offset = read_u64(self.reader, self.qcow.header.l1_table_offset + l1_index * 8)?;
+            let mut table = vec![0; 1 << l2_bits];
+            if l2_offset != 0 {
+                let mut bytes = vec![0; table.len() * 8];
+                self.reader.seek(SeekFrom::Start(l2_offset))?;
+                self.reader.read_exact(&mut bytes)?;
+
+                for (entry, bytes) in table.iter_mut().zip(bytes.chunks_exact(8)) {
+                    *entry = u64::from_be_bytes(bytes.try_into().unwrap());
+                }
+            }
+
+            self.l2_table_cache = Some((l1_index, table));
+        }
+
+        Ok(self.l2_table_cache.as_ref().unwrap().1[l2_index])
+    }
+
+    /// Read the decompressed contents of a compressed cluster into the cache
+    fn load_compressed(&mut self, guest_cluster: u64, entry: u64) -> io::Result<&[u8]> {
+        if !matches!(&self.compressed_cache, Some((index, _)) if *index == guest_cluster) {
+            let cluster_bits = self.qcow.header.cluster_bits as u32;
+            let offset_bits = 63 - cluster_bits;
+            let host_offset = entry & ((1 << offset_bits) - 1);
+            let compressed_len = (entry >> offset_bits) & (self.cluster_size() - 1);
+
+            let mut cluster = vec![0; self.cluster_size() as usize];
+            self.reader.seek(SeekFrom::Start(host_offset))?;
+            let mut decoder = DeflateDecoder::new((&mut *self.reader).take(compressed_len));
+            io::copy(
+                &mut (&mut decoder).take(cluster.len() as u64),
+                &mut io::Cursor::new(&mut cluster[..]),
+            )?;
+
+            self.compressed_cache = Some((guest_cluster, cluster.into_boxed_slice()));
+        }
+
+        Ok(&self.compressed_cache.as_ref().unwrap().1)
+    }
+}
+
+fn read_u64(reader: &mut (impl Read + Seek), offset: u64) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.read_exact(&mut bytes)?;
+
+    Ok(u64::from_be_bytes(bytes))
+}
+
+impl<'qcow, 'reader, R> Read for Qcow1Reader<'qcow, 'reader, R>
+where
+    R: Read + Seek,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.guest_size() {
+            return Ok(0);
+        }
+
+        if self.qcow.header.crypt_method != EncryptionMethod::None {
+            return Err(Error::UnsupportedFeature("encryption").into());
+        }
+
+        let cluster_size = self.cluster_size();
+        let guest_cluster = self.pos / cluster_size;
+        let pos_in_cluster = self.pos % cluster_size;
+        let read_len = u64::min(cluster_size - pos_in_cluster, buf.len() as u64);
+        let read_len = u64::min(read_len, self.guest_size() - self.pos) as usize;
+        let buf = &mut buf[..read_len];
+
+        let entry = self.l2_entry(guest_cluster)?;
+        if entry == 0 {
+            let pos = self.pos;
+            match self.get_backing_reader().map_err(io::Error::from)? {
+                Some(backing) => {
+                    backing.seek(SeekFrom::Start(pos))?;
+                    backing.read_exact(buf)?;
+                }
+                None => buf.fill(0),
+            }
+        } else if entry & COMPRESSED != 0 {
+            let cluster = self.load_compressed(guest_cluster, entry)?;
+            let pos_in_cluster = pos_in_cluster as usize;
+            buf.copy_from_slice(&cluster[pos_in_cluster..pos_in_cluster + read_len]);
+        } else {
+            self.reader.seek(SeekFrom::Start(entry + pos_in_cluster))?;
+            self.reader.read_exact(buf)?;
+        }
+
+        self.pos += read_len as u64;
+
+        Ok(read_len)
+    }
+}
+
+impl<'qcow, 'reader, R> Seek for Qcow1Reader<'qcow, 'reader, R>
+where
+    R: Read + Seek,
+{
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::Current(offset) => offset_pos(self.pos, offset),
+            SeekFrom::End(offset) => offset_pos(self.guest_size(), offset),
+        };
+
+        self.pos = new_pos.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek out of range of 64-bit position",
+            )
+        })?;
+
+        Ok(self.pos)
+    }
+}
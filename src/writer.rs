@@ -0,0 +1,738 @@
+use crate::levels::{ClusterDescriptor, L2Entry};
+use crate::refcount::Refcounts;
+use crate::*;
+
+use std::collections::{BTreeSet, HashMap};
+use std::convert::TryInto;
+use std::io::{self, Read, Seek, Write};
+use std::rc::Rc;
+
+/// A writer for modifying the guest virtual drive of a qcow2 image in place. Should be
+/// constructed using [`Qcow2::writer`].
+///
+/// Guest data is written to the image immediately, while the metadata describing it (L1 and L2
+/// tables, refcounts and the header) is written when the writer is flushed or dropped. Clusters
+/// shared with snapshots are copied before being modified, and partially written clusters which
+/// are not yet allocated are filled in from the backing file.
+///
+/// ## Example
+///
+/// ```rust,no_run
+/// use std::io::{Seek, SeekFrom, Write};
+/// use std::fs::OpenOptions;
+///
+/// # const PATH: &str = "/home/jamcleod/.panda/bionic-server-cloudimg-amd64-noaslr-nokaslr.qcow2";
+/// let mut qcow = qcow::open(PATH)?.unwrap_qcow2();
+/// let mut file = OpenOptions::new().read(true).write(true).open(PATH)?;
+/// let mut writer = qcow.writer(&mut file)?;
+///
+/// // overwrite the boot signature of the virtual drive
+/// writer.seek(SeekFrom::Start(0x1fe))?;
+/// writer.write_all(&[0x55, 0xaa])?;
+/// writer.flush()?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub struct Writer<'qcow, 'file, F>
+where
+    F: Read + Write + Seek,
+{
+    pub(crate) qcow: &'qcow mut Qcow2,
+
+    /// inner file used for reading/writing the host file (the qcow itself)
+    pub(crate) file: &'file mut F,
+
+    pub(crate) refcounts: Refcounts,
+
+    /// host offset following the most recently written compressed cluster, or 0 if it ended on
+    /// a cluster boundary
+    pub(crate) compressed_offset: u64,
+
+    /// whether clusters should be compressed when written
+    pub(crate) compress: bool,
+
+    /// cache of L2 tables which have been read or modified, keyed by L1 index
+    pub(crate) l2_tables: HashMap<u64, Vec<L2Entry>>,
+    pub(crate) dirty_l2_tables: BTreeSet<u64>,
+    l1_dirty: bool,
+    pub(crate) header_dirty: bool,
+
+    /// whether the snapshot table needs to be written to a newly allocated location
+    pub(crate) snapshots_dirty: bool,
+
+    pub(crate) backing_reader: Option<Box<BackingReader>>,
+
+    /// resolver used for locating the contents of the backing file
+    pub(crate) resolver: Rc<dyn BackingResolver>,
+
+    /// current position of the writer within the guest
+    pub(crate) pos: u64,
+}
+
+impl Qcow2 {
+    /// Create a writer for modifying the guest virtual drive. `file` must be the source file of
+    /// the qcow, opened for both reading and writing.
+    ///
+    /// Any auto-clear feature bits are cleared, as this crate does not keep the data they
+    /// describe (such as bitmaps) up to date.
+    ///
+    /// See [`Writer`] for an example.
+    pub fn writer<'qcow, 'file, F>(
+        &'qcow mut self,
+        file: &'file mut F,
+    ) -> Result<Writer<'qcow, 'file, F>, Error>
+    where
+        F: Read + Write + Seek,
+    {
+        self.check_writable()?;
+
+        let mut header_dirty = false;
+        if let Some(v3_header) = &mut self.header.v3_header {
+            if v3_header.autoclear_features.to_u64() != 0 {
+                v3_header.autoclear_features = AutoClearFeatures::new();
+                header_dirty = true;
+            }
+        }
+
+        let file_len = file.seek(SeekFrom::End(0))?;
+        let refcounts = Refcounts::load(&self.header, file, file_len)?;
+
+        Ok(Writer {
+            qcow: self,
+            file,
+            refcounts,
+            compressed_offset: 0,
+            compress: false,
+            l2_tables: HashMap::new(),
+            dirty_l2_tables: BTreeSet::new(),
+            l1_dirty: false,
+            header_dirty,
+            snapshots_dirty: false,
+            backing_reader: None,
+            resolver: Rc::new(FileResolver),
+            pos: 0,
+        })
+    }
+
+    /// Ensure the image doesn't use any features which can't be preserved when modifying it
+    pub(crate) fn check_writable(&self) -> Result<(), Error> {
+        if self.header.crypt_method != EncryptionMethod::None {
+            return Err(Error::UnsupportedFeature("encryption"));
+        }
+
+        if let Some(v3_header) = &self.header.v3_header {
+            let features = &v3_header.incompatible_features;
+            if features.dirty() {
+                return Err(Error::UnsupportedFeature("inconsistent refcounts (dirty bit)"));
+            }
+
+            if features.corrupt() {
+                return Err(Error::UnsupportedFeature("corrupt metadata (corrupt bit)"));
+            }
+
+            if features.external_data_file() {
+                return Err(Error::UnsupportedFeature("external data files"));
+            }
+
+            if features.extended_l2() {
+                return Err(Error::UnsupportedFeature("extended L2 entries"));
+            }
+
+            if features.unknown_bits() != 0 {
+                return Err(Error::UnsupportedFeature("unknown incompatible features"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<'qcow, 'file, F> Writer<'qcow, 'file, F>
+where
+    F: Read + Write + Seek,
+{
+    /// Returns the current write position within the guest virtual hard disk
+    pub fn guest_pos(&self) -> u64 {
+        self.pos
+    }
+
+    /// Returns the size of the guest virtual hard disk
+    pub fn guest_size(&self) -> u64 {
+        self.qcow.header.size
+    }
+
+    /// Get the size of a cluster within the qcow
+    pub fn cluster_size(&self) -> u64 {
+        self.qcow.cluster_size()
+    }
+
+    /// Use `resolver` to locate the contents of the backing file, which is read from when
+    /// partially writing to clusters not yet allocated in this image.
+    ///
+    /// See [`BackingResolver`] for more info.
+    pub fn with_backing_resolver(mut self, resolver: impl BackingResolver + 'static) -> Self {
+        self.resolver = Rc::new(resolver);
+        self.backing_reader = None;
+        self
+    }
+
+    /// Deallocate every cluster of the guest within this image, so that the entire guest reads
+    /// from the backing file (or as zeroes if there is no backing file).
+    ///
+    /// Clusters still referenced by snapshots are left in place.
+    pub fn make_empty(&mut self) -> Result<(), Error> {
+        for l1_index in 0..self.qcow.l1_table.len() as u64 {
+            let l2_offset = self.qcow.l1_table[l1_index as usize].l2_offset;
+            if l2_offset == 0 {
+                continue;
+            }
+
+            self.load_l2_table(l1_index)?;
+            let table = self.l2_tables.remove(&l1_index).unwrap_or_default();
+            self.dirty_l2_tables.remove(&l1_index);
+
+            // snapshots hold their own references to the data clusters, so these are released
+            // regardless of whether the L2 table itself is still in use
+            self.refcounts.decrement(l2_offset)?;
+            for entry in &table {
+                self.release(entry)?;
+            }
+
+            let l1_entry = &mut self.qcow.l1_table[l1_index as usize];
+            l1_entry.l2_offset = 0;
+            l1_entry.is_used = false;
+            self.l1_dirty = true;
+        }
+
+        Ok(())
+    }
+
+    /// Write all modified metadata out to the image.
+    ///
+    /// Everything is written in an order which keeps the image consistent if interrupted part
+    /// way through: refcount increments first, then the L2 tables, the L1 table and the header,
+    /// and finally any refcount decrements, so an interruption can only leak clusters.
+    pub fn sync(&mut self) -> Result<(), Error> {
+        let cluster_bits = self.qcow.header.cluster_bits;
+
+        // the snapshot table is written to newly allocated clusters, so needs to be in place
+        // before the refcounts are
+        if self.snapshots_dirty {
+            self.write_snapshot_table()?;
+        }
+
+        if let Some((offset, clusters)) = self.refcounts.flush(self.file)? {
+            self.qcow.header.refcount_table_offset = offset;
+            self.qcow.header.refcount_table_clusters = clusters;
+            self.header_dirty = true;
+        }
+
+        for l1_index in std::mem::take(&mut self.dirty_l2_tables) {
+            let l2_offset = self.qcow.l1_table[l1_index as usize].l2_offset;
+            let table = self.l2_tables.get_mut(&l1_index).unwrap();
+            let refcounts = &self.refcounts;
+
+            let mut bytes = Vec::with_capacity(table.len() * 8);
+            for entry in table.iter_mut() {
+                entry.is_used = entry
+                    .standard_host_offset()
+                    .is_some_and(|offset| refcounts.get(offset) == 1);
+                bytes.extend_from_slice(&entry.to_u64(cluster_bits).to_be_bytes());
+            }
+
+            self.file.seek(SeekFrom::Start(l2_offset))?;
+            self.file.write_all(&bytes)?;
+        }
+
+        for l1_entry in self.qcow.l1_table.iter_mut() {
+            let is_used = l1_entry.l2_offset != 0 && self.refcounts.get(l1_entry.l2_offset) == 1;
+            if l1_entry.is_used != is_used {
+                l1_entry.is_used = is_used;
+                self.l1_dirty = true;
+            }
+        }
+
+        if self.l1_dirty {
+            let bytes: Vec<u8> = self
+                .qcow
+                .l1_table
+                .iter()
+                .flat_map(|entry| entry.to_u64().to_be_bytes())
+                .collect();
+
+            self.file.seek(SeekFrom::Start(self.qcow.header.l1_table_offset))?;
+            self.file.write_all(&bytes)?;
+            self.l1_dirty = false;
+        }
+
+        if self.header_dirty {
+            write_header(&mut self.qcow.header, self.file)?;
+            self.header_dirty = false;
+        }
+
+        self.refcounts.flush_frees(self.file)?;
+        self.file.flush()?;
+
+        Ok(())
+    }
+
+    /// Write the snapshot table to newly allocated clusters, updating the header to point to it.
+    /// The clusters of the previous table must already have been released.
+    fn write_snapshot_table(&mut self) -> Result<(), Error> {
+        let bytes: Vec<u8> = self
+            .qcow
+            .snapshots
+            .iter()
+            .flat_map(Snapshot::to_bytes)
+            .collect();
+
+        let offset = if bytes.is_empty() {
+            0
+        } else {
+            let offset = self
+                .refcounts
+                .allocate((bytes.len() as u64).div_ceil(self.cluster_size()))?;
+            self.file.seek(SeekFrom::Start(offset))?;
+            self.file.write_all(&bytes)?;
+
+            offset
+        };
+
+        self.qcow.header.nb_snapshots = self.qcow.snapshots.len() as u32;
+        self.qcow.header.snapshots_offset = offset;
+        self.header_dirty = true;
+        self.snapshots_dirty = false;
+
+        Ok(())
+    }
+
+    /// Grow the active L1 table to hold at least `l1_size` entries, moving it to newly allocated
+    /// clusters if it does not fit in its current ones
+    pub(crate) fn grow_l1_table(&mut self, l1_size: u64) -> Result<(), Error> {
+        let old_size = self.qcow.l1_table.len() as u64;
+        if l1_size <= old_size {
+            return Ok(());
+        }
+
+        let cluster_size = self.cluster_size();
+        let old_clusters = (old_size * 8).div_ceil(cluster_size);
+        let new_clusters = (l1_size * 8).div_ceil(cluster_size);
+        if new_clusters > old_clusters {
+            let new_offset = self.refcounts.allocate(new_clusters)?;
+            let old_offset = self.qcow.header.l1_table_offset;
+            for cluster in 0..old_clusters {
+                self.refcounts.decrement(old_offset + (cluster * cluster_size))?;
+            }
+
+            // the new clusters are written in full so the unused tail of the table is zeroed
+            self.file.seek(SeekFrom::Start(new_offset))?;
+            self.file.write_all(&vec![0; (new_clusters * cluster_size) as usize])?;
+            self.qcow.header.l1_table_offset = new_offset;
+        }
+
+        self.qcow.l1_table.resize(l1_size as usize, L1Entry::from_u64(0));
+        self.qcow.header.l1_size = l1_size as u32;
+        self.l1_dirty = true;
+        self.header_dirty = true;
+
+        Ok(())
+    }
+
+    /// Shrink the active L1 table to `l1_size` entries, freeing the L2 tables of the removed
+    /// entries along with any clusters they reference, and any clusters of the table itself which
+    /// are no longer needed.
+    pub(crate) fn truncate_l1_table(&mut self, l1_size: u64) -> Result<(), Error> {
+        let old_size = self.qcow.l1_table.len() as u64;
+        if l1_size >= old_size {
+            return Ok(());
+        }
+
+        for l1_index in l1_size..old_size {
+            let l2_offset = self.qcow.l1_table[l1_index as usize].l2_offset;
+            if l2_offset == 0 {
+                continue;
+            }
+
+            self.load_l2_table(l1_index)?;
+            let table = self.l2_tables.remove(&l1_index).unwrap_or_default();
+            self.dirty_l2_tables.remove(&l1_index);
+
+            self.refcounts.decrement(l2_offset)?;
+            for entry in &table {
+                self.release(entry)?;
+            }
+        }
+
+        // the removed entries are cleared so they aren't mistaken for L2 tables still in use
+        let l1_table_offset = self.qcow.header.l1_table_offset;
+        self.file.seek(SeekFrom::Start(l1_table_offset + (l1_size * 8)))?;
+        self.file.write_all(&vec![0; ((old_size - l1_size) * 8) as usize])?;
+
+        let cluster_size = self.cluster_size();
+        let old_clusters = (old_size * 8).div_ceil(cluster_size);
+        let new_clusters = (l1_size * 8).div_ceil(cluster_size).max(1);
+        for cluster in new_clusters..old_clusters {
+            self.refcounts.decrement(l1_table_offset + (cluster * cluster_size))?;
+        }
+
+        self.qcow.l1_table.truncate(l1_size as usize);
+        self.qcow.header.l1_size = l1_size as u32;
+        self.l1_dirty = true;
+        self.header_dirty = true;
+
+        Ok(())
+    }
+
+    /// Returns a reference to a reader for the backing file, if such a backing file exists.
+    pub(crate) fn get_backing_reader(&mut self) -> Result<Option<&mut BackingReader>, Error> {
+        if self.backing_reader.is_none() {
+            self.backing_reader =
+                BackingReader::open(&self.qcow.header, Rc::clone(&self.resolver))?.map(Box::new);
+        }
+
+        Ok(self.backing_reader.as_deref_mut())
+    }
+
+    fn l2_entries(&self) -> u64 {
+        self.qcow.l2_entries()
+    }
+
+    /// Read the L2 table for the given L1 index into the cache if it isn't already present
+    pub(crate) fn load_l2_table(&mut self, l1_index: u64) -> io::Result<()> {
+        if self.l2_tables.contains_key(&l1_index) {
+            return Ok(());
+        }
+
+        let l2_offset = self.qcow.l1_table[l1_index as usize].l2_offset;
+        let cluster_bits = self.qcow.header.cluster_bits;
+        let table = if l2_offset == 0 {
+            vec![L2Entry::unallocated(); self.l2_entries() as usize]
+        } else {
+            let mut bytes = vec![0; (self.l2_entries() * 8) as usize];
+            self.file.seek(SeekFrom::Start(l2_offset))?;
+            self.file.read_exact(&mut bytes)?;
+
+            bytes
+                .chunks_exact(8)
+                .map(|entry| {
+                    L2Entry::from_u64(u64::from_be_bytes(entry.try_into().unwrap()), cluster_bits)
+                })
+                .collect()
+        };
+
+        self.l2_tables.insert(l1_index, table);
+
+        Ok(())
+    }
+
+    /// Get the L2 entry for the given guest cluster
+    pub(crate) fn l2_entry(&mut self, guest_cluster: u64) -> io::Result<L2Entry> {
+        let l1_index = guest_cluster / self.l2_entries();
+        if l1_index >= self.qcow.l1_table.len() as u64 {
+            return Ok(L2Entry::unallocated());
+        }
+
+        self.load_l2_table(l1_index)?;
+
+        Ok(self.l2_tables[&l1_index][(guest_cluster % self.l2_entries()) as usize].clone())
+    }
+
+    /// Get the L2 table for the given L1 index so that it can be modified, allocating it or
+    /// copying it if it is shared with a snapshot.
+    pub(crate) fn l2_table_mut(&mut self, l1_index: u64) -> Result<&mut Vec<L2Entry>, Error> {
+        if l1_index >= self.qcow.l1_table.len() as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "write position past end of L1 table",
+            )
+            .into());
+        }
+
+        self.load_l2_table(l1_index)?;
+
+        let l2_offset = self.qcow.l1_table[l1_index as usize].l2_offset;
+        if l2_offset == 0 || self.refcounts.get(l2_offset) > 1 {
+            let new_offset = self.refcounts.allocate(1)?;
+
+            // references to data clusters are counted per L1 table, so the copy takes over the
+            // references held by the original table
+            if l2_offset != 0 {
+                self.refcounts.decrement(l2_offset)?;
+            }
+
+            self.qcow.l1_table[l1_index as usize].l2_offset = new_offset;
+            self.l1_dirty = true;
+        }
+
+        self.dirty_l2_tables.insert(l1_index);
+
+        Ok(self.l2_tables.get_mut(&l1_index).unwrap())
+    }
+
+    /// Set the L2 entry for the given guest cluster, without modifying refcounts
+    pub(crate) fn set_l2_entry(&mut self, guest_cluster: u64, entry: L2Entry) -> Result<(), Error> {
+        let l2_entries = self.l2_entries();
+        let table = self.l2_table_mut(guest_cluster / l2_entries)?;
+        table[(guest_cluster % l2_entries) as usize] = entry;
+
+        Ok(())
+    }
+
+    /// Remove a reference from every host cluster used by the given L2 entry
+    pub(crate) fn release(&mut self, entry: &L2Entry) -> Result<(), Error> {
+        for host_offset in self.host_clusters(entry) {
+            let freed = self.refcounts.decrement(host_offset)? == 0;
+
+            // don't continue packing compressed data into a cluster which may be reused
+            let compressed_cluster = self.compressed_offset & !(self.cluster_size() - 1);
+            if freed && host_offset == compressed_cluster {
+                self.compressed_offset = 0;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Deallocate the given guest cluster, so that it reads from the backing file (or as zeroes
+    /// if there is no backing file)
+    pub(crate) fn deallocate_cluster(&mut self, guest_cluster: u64) -> Result<(), Error> {
+        self.replace_l2_entry(guest_cluster, L2Entry::unallocated())
+    }
+
+    /// Mark the given guest cluster as reading as zeroes, without allocating any data for it.
+    /// Requires a version 3 image.
+    pub(crate) fn zero_cluster(&mut self, guest_cluster: u64) -> Result<(), Error> {
+        self.replace_l2_entry(guest_cluster, L2Entry::zero())
+    }
+
+    /// Replace the L2 entry for the given guest cluster with one which has no host cluster,
+    /// releasing the clusters used by the previous entry
+    fn replace_l2_entry(&mut self, guest_cluster: u64, entry: L2Entry) -> Result<(), Error> {
+        let cluster_bits = self.qcow.header.cluster_bits;
+        let old_entry = self.l2_entry(guest_cluster)?;
+        if old_entry.to_u64(cluster_bits) == entry.to_u64(cluster_bits) {
+            return Ok(());
+        }
+
+        self.set_l2_entry(guest_cluster, entry)?;
+        self.release(&old_entry)
+    }
+
+    /// Get the host offset of every cluster used by the given L2 entry
+    pub(crate) fn host_clusters(&self, entry: &L2Entry) -> Vec<u64> {
+        entry.host_clusters(self.qcow.header.cluster_bits)
+    }
+
+    /// Returns true if any entry of any L2 table, either active or belonging to a snapshot,
+    /// matches `predicate`
+    pub(crate) fn any_l2_entry(
+        &mut self,
+        predicate: impl Fn(&L2Entry) -> bool,
+    ) -> Result<bool, Error> {
+        let cluster_bits = self.qcow.header.cluster_bits;
+
+        // modified tables may not have been written yet, so check the cached copies first
+        if self.l2_tables.values().flatten().any(&predicate) {
+            return Ok(true);
+        }
+
+        let cached_tables = &self.l2_tables;
+        let active_tables = self
+            .qcow
+            .l1_table
+            .iter()
+            .enumerate()
+            .filter(|(l1_index, _)| !cached_tables.contains_key(&(*l1_index as u64)))
+            .map(|(_, l1_entry)| l1_entry);
+        let snapshot_tables = self
+            .qcow
+            .snapshots
+            .iter()
+            .flat_map(|snapshot| snapshot.l1_table.iter());
+
+        for l1_entry in active_tables.chain(snapshot_tables) {
+            if l1_entry.l2_offset == 0 {
+                continue;
+            }
+
+            let table = l1_entry.read_l2(self.file, cluster_bits).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::UnexpectedEof, "L2 table could not be read")
+            })?;
+
+            if table.iter().any(&predicate) {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Read the current contents of the given guest cluster, including from the backing file
+    pub(crate) fn read_cluster(&mut self, guest_cluster: u64) -> Result<Vec<u8>, Error> {
+        let cluster_size = self.cluster_size();
+        let mut cluster = vec![0; cluster_size as usize];
+
+        let entry = self.l2_entry(guest_cluster)?;
+        if entry.is_unallocated() {
+            if let Some(backing) = self.get_backing_reader()? {
+                backing.seek(SeekFrom::Start(guest_cluster * cluster_size))?;
+                backing.read_exact(&mut cluster)?;
+            }
+        } else {
+            let compression_type = self.qcow.header.compression_type();
+            entry.read_contents(self.file, &mut cluster, compression_type)?;
+        }
+
+        Ok(cluster)
+    }
+
+    /// Write the full contents of the given guest cluster
+    pub(crate) fn write_cluster(&mut self, guest_cluster: u64, data: &[u8]) -> Result<(), Error> {
+        let l2_entries = self.l2_entries();
+        let l2_index = (guest_cluster % l2_entries) as usize;
+        let entry = self.l2_table_mut(guest_cluster / l2_entries)?[l2_index].clone();
+
+        let host_offset = match entry.standard_host_offset() {
+            Some(offset) if self.refcounts.get(offset) == 1 => offset,
+            _ => {
+                let offset = self.refcounts.allocate(1)?;
+                self.release(&entry)?;
+
+                offset
+            }
+        };
+
+        self.file.seek(SeekFrom::Start(host_offset))?;
+        self.file.write_all(data)?;
+
+        self.set_l2_entry(guest_cluster, L2Entry::standard(host_offset))
+    }
+
+    /// Write the full contents of the given guest cluster, compressing it if enabled
+    pub(crate) fn store_cluster(&mut self, guest_cluster: u64, data: &[u8]) -> Result<(), Error> {
+        if self.compress {
+            self.write_compressed_cluster(guest_cluster, data)
+        } else {
+            self.write_cluster(guest_cluster, data)
+        }
+    }
+
+    pub(crate) fn write_at_pos(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let cluster_size = self.cluster_size();
+        let guest_cluster = self.pos >> self.qcow.header.cluster_bits;
+        let pos_in_cluster = self.pos % cluster_size;
+        let write_len = u64::min(
+            u64::min(cluster_size - pos_in_cluster, buf.len() as u64),
+            self.guest_size() - self.pos,
+        ) as usize;
+
+        if write_len as u64 == cluster_size {
+            self.store_cluster(guest_cluster, &buf[..write_len])?;
+        } else {
+            let entry = self.l2_entry(guest_cluster)?;
+            let in_place = match &entry.cluster_descriptor {
+                ClusterDescriptor::Standard(cluster) if !cluster.all_zeroes => entry
+                    .standard_host_offset()
+                    .filter(|&offset| self.refcounts.get(offset) == 1),
+                _ => None,
+            };
+
+            match in_place {
+                Some(host_offset) => {
+                    self.file.seek(SeekFrom::Start(host_offset + pos_in_cluster))?;
+                    self.file.write_all(&buf[..write_len])?;
+                }
+                None => {
+                    let mut cluster = self.read_cluster(guest_cluster)?;
+                    let pos_in_cluster = pos_in_cluster as usize;
+                    cluster[pos_in_cluster..pos_in_cluster + write_len]
+                        .copy_from_slice(&buf[..write_len]);
+
+                    self.store_cluster(guest_cluster, &cluster)?;
+                }
+            }
+        }
+
+        self.pos += write_len as u64;
+
+        Ok(write_len)
+    }
+}
+
+/// Write the header, extensions and backing file name to the start of the image
+pub(crate) fn write_header(
+    header: &mut QcowHeader,
+    file: &mut (impl Write + Seek),
+) -> Result<(), Error> {
+    let bytes = header.to_bytes();
+    if bytes.len() as u64 > header.cluster_size() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "header, header extensions and backing file name do not fit in the first cluster",
+        )
+        .into());
+    }
+
+    if let Some(v3_header) = &mut header.v3_header {
+        v3_header.header_len = V3_HEADER_LEN;
+    }
+
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&bytes)?;
+
+    Ok(())
+}
+
+impl<'qcow, 'file, F> Write for Writer<'qcow, 'file, F>
+where
+    F: Read + Write + Seek,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        if self.pos >= self.guest_size() {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "write position past end of virtual disk",
+            ));
+        }
+
+        self.write_at_pos(buf).map_err(io::Error::from)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sync().map_err(io::Error::from)
+    }
+}
+
+impl<'qcow, 'file, F> Seek for Writer<'qcow, 'file, F>
+where
+    F: Read + Write + Seek,
+{
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::Current(offset) => offset_pos(self.pos, offset),
+            SeekFrom::End(offset) => offset_pos(self.guest_size(), offset),
+        };
+
+        self.pos = new_pos.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek out of range of 64-bit position",
+            )
+        })?;
+
+        Ok(self.pos)
+    }
+}
+
+impl<'qcow, 'file, F> Drop for Writer<'qcow, 'file, F>
+where
+    F: Read + Write + Seek,
+{
+    fn drop(&mut self) {
+        let _ = self.sync();
+    }
+}
@@ -0,0 +1,1627 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs;
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use qcow::{
+    AmendOptions, BackingFormat, ClusterOwner, CommitAction, CompareOptions, CompressionType,
+    CreateOptions, DiffOptions, DynamicQcow, ExtentKind, HostLocation, HostRegionKind,
+    ImportOptions, MeasureOptions, Preallocation, Qcow2, ReadSeek, ReencodeOptions, ResizeMode,
+    Snapshot,
+};
+
+fn read_guest(image: &mut Cursor<Vec<u8>>) -> Vec<u8> {
+    image.set_position(0);
+    let qcow = qcow::load(image).unwrap().unwrap_qcow2();
+    let mut reader = qcow.reader(image);
+    let mut guest = Vec::new();
+    reader.read_to_end(&mut guest).unwrap();
+
+    guest
+}
+
+fn raw_disk() -> Vec<u8> {
+    let mut disk = vec![0; 1_000_000];
+    disk[..5].copy_from_slice(b"hello");
+    for (i, byte) in disk[300_000..400_000].iter_mut().enumerate() {
+        *byte = (i % 251) as u8;
+    }
+    disk[999_990..].copy_from_slice(b"0123456789");
+
+    disk
+}
+
+/// Resolve every backing file to a copy of `bytes`
+fn memory_resolver(bytes: Vec<u8>) -> impl Fn(&str) -> std::io::Result<Box<dyn ReadSeek>> {
+    move |_: &str| Ok(Box::new(Cursor::new(bytes.clone())) as Box<dyn ReadSeek>)
+}
+
+/// Resolve backing files by name to copies of in-memory images
+fn named_resolver(
+    images: Vec<(&'static str, Vec<u8>)>,
+) -> impl Fn(&str) -> std::io::Result<Box<dyn ReadSeek>> {
+    move |path: &str| {
+        let (_, bytes) = images
+            .iter()
+            .find(|(name, _)| *name == path)
+            .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotFound))?;
+
+        Ok(Box::new(Cursor::new(bytes.clone())) as Box<dyn ReadSeek>)
+    }
+}
+
+/// Create an empty directory for a test's image files, unique to the test and process
+fn scratch_dir(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("qcow-{}-{}", test, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    dir
+}
+
+#[test]
+fn write_and_read_back() {
+    let mut image = Cursor::new(Vec::new());
+    let options = CreateOptions {
+        cluster_bits: 12,
+        ..CreateOptions::new(1 << 20)
+    };
+    let mut qcow = qcow::create(&mut image, &options).unwrap();
+
+    let mut writer = qcow.writer(&mut image).unwrap();
+    writer.seek(SeekFrom::Start(4000)).unwrap();
+    writer.write_all(&[0xaa; 10_000]).unwrap();
+    writer.flush().unwrap();
+    drop(writer);
+
+    let guest = read_guest(&mut image);
+    assert_eq!(guest.len(), 1 << 20);
+    assert!(guest[..4000].iter().all(|&byte| byte == 0));
+    assert!(guest[4000..14_000].iter().all(|&byte| byte == 0xaa));
+    assert!(guest[14_000..].iter().all(|&byte| byte == 0));
+}
+
+#[test]
+fn unknown_incompatible_features() {
+    let mut image = Cursor::new(Vec::new());
+    let options = CreateOptions {
+        cluster_bits: 12,
+        ..CreateOptions::new(1 << 20)
+    };
+    qcow::create(&mut image, &options).unwrap();
+
+    // set a bit of the incompatible features bitmask which has no defined meaning
+    image.get_mut()[72..80].copy_from_slice(&(1u64 << 40).to_be_bytes());
+    image.set_position(0);
+    let mut qcow = qcow::load(&mut image).unwrap().unwrap_qcow2();
+    assert!(matches!(
+        qcow.writer(&mut image),
+        Err(qcow::Error::UnsupportedFeature(_))
+    ));
+}
+
+#[test]
+fn raw_backing_file() {
+    // a backing file shorter than the overlay reads as zeroes past its end
+    let base = raw_disk()[..600_000].to_vec();
+    let mut expected = raw_disk();
+    expected[600_000..].fill(0);
+    expected[5000..9000].fill(0xaa);
+
+    let mut image = Cursor::new(Vec::new());
+    let options = CreateOptions {
+        cluster_bits: 12,
+        backing_file: Some("base.raw".to_owned()),
+        backing_format: Some(BackingFormat::Raw),
+        ..CreateOptions::new(1_000_000)
+    };
+    let mut qcow = qcow::create(&mut image, &options).unwrap();
+    let mut writer = qcow
+        .writer(&mut image)
+        .unwrap()
+        .with_backing_resolver(memory_resolver(base.clone()));
+    writer.seek(SeekFrom::Start(5000)).unwrap();
+    writer.write_all(&[0xaa; 4000]).unwrap();
+    writer.flush().unwrap();
+    drop(writer);
+
+    let mut reader = qcow
+        .reader(&mut image)
+        .with_backing_resolver(memory_resolver(base.clone()));
+    assert_eq!(
+        reader.get_backing_reader().unwrap().unwrap().format(),
+        BackingFormat::Raw
+    );
+    let mut guest = Vec::new();
+    reader.read_to_end(&mut guest).unwrap();
+    assert_eq!(guest, expected);
+
+    // the same raw backing file declared as a qcow2 image is refused
+    let mut image = Cursor::new(Vec::new());
+    let options = CreateOptions {
+        backing_format: Some(BackingFormat::Qcow2),
+        ..options
+    };
+    let qcow = qcow::create(&mut image, &options).unwrap();
+    let mut reader = qcow
+        .reader(&mut image)
+        .with_backing_resolver(memory_resolver(base));
+    match reader.get_backing_reader() {
+        Err(qcow::Error::BackingFormatMismatch { declared, detected }) => {
+            assert_eq!(declared, BackingFormat::Qcow2);
+            assert_eq!(detected, BackingFormat::Raw);
+        }
+        _ => panic!("backing format mismatch not detected"),
+    }
+    assert!(reader.read_to_end(&mut Vec::new()).is_err());
+}
+
+#[test]
+fn backing_resolver() {
+    let options = ImportOptions {
+        cluster_bits: 12,
+        ..Default::default()
+    };
+    let mut base_image = Cursor::new(Vec::new());
+    Qcow2::import_raw(&mut Cursor::new(raw_disk()), &mut base_image, &options).unwrap();
+
+    let mut mid_image = Cursor::new(Vec::new());
+    let mid_options = CreateOptions {
+        cluster_bits: 12,
+        backing_file: Some("base.qcow2".to_owned()),
+        backing_format: Some(BackingFormat::Qcow2),
+        ..CreateOptions::new(1_000_000)
+    };
+    let mut mid = qcow::create(&mut mid_image, &mid_options).unwrap();
+    let mut writer = mid
+        .writer(&mut mid_image)
+        .unwrap()
+        .with_backing_resolver(memory_resolver(base_image.get_ref().clone()));
+    writer.seek(SeekFrom::Start(0x1000)).unwrap();
+    writer.write_all(&[0xaa; 0x1000]).unwrap();
+    writer.flush().unwrap();
+    drop(writer);
+
+    let mut top_image = Cursor::new(Vec::new());
+    let top_options = CreateOptions {
+        backing_file: Some("mid.qcow2".to_owned()),
+        ..mid_options
+    };
+    let top = qcow::create(&mut top_image, &top_options).unwrap();
+
+    // backing files only exist in memory, so can only be found through the resolver
+    let images = HashMap::from([
+        ("base.qcow2", base_image.into_inner()),
+        ("mid.qcow2", mid_image.into_inner()),
+    ]);
+    let requested = Rc::new(RefCell::new(Vec::new()));
+    let resolver = {
+        let requested = Rc::clone(&requested);
+        move |path: &str| {
+            requested.borrow_mut().push(path.to_owned());
+            let bytes = images
+                .get(path)
+                .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotFound))?;
+
+            Ok(Box::new(Cursor::new(bytes.clone())) as Box<dyn ReadSeek>)
+        }
+    };
+
+    let mut reader = top.reader(&mut top_image).with_backing_resolver(resolver);
+    let mut guest = Vec::new();
+    reader.read_to_end(&mut guest).unwrap();
+
+    let mut expected = raw_disk();
+    expected[0x1000..0x2000].fill(0xaa);
+    assert_eq!(guest, expected);
+    assert_eq!(*requested.borrow(), ["mid.qcow2", "base.qcow2"]);
+}
+
+#[test]
+fn missing_backing_file() {
+    let mut image = Cursor::new(Vec::new());
+    let options = CreateOptions {
+        cluster_bits: 12,
+        backing_file: Some("missing.qcow2".to_owned()),
+        ..CreateOptions::new(1_000_000)
+    };
+    let qcow = qcow::create(&mut image, &options).unwrap();
+
+    let requested = Rc::new(RefCell::new(0));
+    let resolver = {
+        let requested = Rc::clone(&requested);
+        move |_: &str| -> std::io::Result<Box<dyn ReadSeek>> {
+            *requested.borrow_mut() += 1;
+            Err(std::io::Error::from(std::io::ErrorKind::NotFound))
+        }
+    };
+    let mut reader = qcow.reader(&mut image).with_backing_resolver(resolver);
+    assert!(matches!(
+        reader.get_backing_reader(),
+        Err(qcow::Error::FileNotFound(_))
+    ));
+
+    // the backing file is only looked for once, however much of the guest is read
+    for offset in [0, 0x1000, 0x20000] {
+        reader.seek(SeekFrom::Start(offset)).unwrap();
+        assert!(reader.read(&mut [0; 0x10]).is_err());
+    }
+    match reader.get_backing_reader() {
+        Err(qcow::Error::BackingFileUnavailable(reason)) => assert!(reason.contains("not found")),
+        _ => panic!("backing file failure not remembered"),
+    }
+    assert_eq!(*requested.borrow(), 1);
+}
+
+#[test]
+fn backing_chain() {
+    let dir = scratch_dir("backing_chain");
+    let base_path = dir.join("base.qcow2");
+    let mid_path = dir.join("mid.qcow2");
+    let top_path = dir.join("top.qcow2");
+
+    let mut base_image = Cursor::new(Vec::new());
+    let options = ImportOptions {
+        cluster_bits: 12,
+        ..Default::default()
+    };
+    Qcow2::import_raw(&mut Cursor::new(raw_disk()), &mut base_image, &options).unwrap();
+    fs::write(&base_path, base_image.into_inner()).unwrap();
+
+    // each overlay writes 4 KiB over the layer below it, using its own cluster size
+    let layers = [
+        (&mid_path, &base_path, 0x1000, 1 << 16),
+        (&top_path, &mid_path, 0x3000, 1 << 12),
+    ];
+    for (path, backing_path, offset, cluster_size) in layers {
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .unwrap();
+        let options = CreateOptions {
+            cluster_bits: u64::trailing_zeros(cluster_size),
+            backing_file: Some(backing_path.to_str().unwrap().to_owned()),
+            backing_format: Some(BackingFormat::Qcow2),
+            ..CreateOptions::new(1_000_000)
+        };
+        let mut qcow = qcow::create(&mut file, &options).unwrap();
+        let mut writer = qcow.writer(&mut file).unwrap();
+        writer.seek(SeekFrom::Start(offset)).unwrap();
+        writer.write_all(&[0xaa; 0x1000]).unwrap();
+        writer.flush().unwrap();
+    }
+
+    let mut top_file = fs::File::open(&top_path).unwrap();
+    let top = qcow::load(&mut top_file).unwrap().unwrap_qcow2();
+
+    let chain = top.backing_chain();
+    let paths: Vec<_> = chain.iter().map(|layer| layer.path.clone()).collect();
+    assert_eq!(
+        paths,
+        [
+            None,
+            Some(mid_path.to_str().unwrap().to_owned()),
+            Some(base_path.to_str().unwrap().to_owned()),
+        ]
+    );
+    let cluster_sizes: Vec<_> = chain.iter().map(|layer| layer.cluster_size).collect();
+    assert_eq!(cluster_sizes, [Some(1 << 12), Some(1 << 16), Some(1 << 12)]);
+    for layer in &chain {
+        assert!(layer.present);
+        assert!(layer.error.is_none());
+        assert_eq!(layer.format, Some(BackingFormat::Qcow2));
+        assert_eq!(layer.virtual_size, Some(1_000_000));
+    }
+
+    // a resolver which can't find the base ends the chain there
+    let mid_bytes = fs::read(&mid_path).unwrap();
+    let resolver = move |path: &str| {
+        if path.ends_with("mid.qcow2") {
+            Ok(Box::new(Cursor::new(mid_bytes.clone())) as Box<dyn ReadSeek>)
+        } else {
+            Err(std::io::Error::from(std::io::ErrorKind::NotFound))
+        }
+    };
+    let chain = top.backing_chain_with_resolver(&resolver);
+    assert_eq!(chain.len(), 3);
+    assert!(chain[1].present);
+    assert!(!chain[2].present);
+    assert_eq!(chain[2].format, None);
+    assert_eq!(chain[2].declared_format, Some(BackingFormat::Qcow2));
+
+    // as does a base which is found but can't be parsed
+    let truncated_header = b"QFI\xfb\0\0\0\x03".to_vec();
+    let chain = top.backing_chain_with_resolver(&memory_resolver(truncated_header));
+    assert_eq!(chain.len(), 2);
+    assert!(chain[1].present);
+    assert!(chain[1].error.is_some());
+    assert_eq!(chain[1].virtual_size, None);
+
+    let mut reader = top.reader(&mut top_file);
+    assert_eq!(reader.supplying_layer(0x3000).unwrap(), Some(0));
+    // the middle layer's write copies up the whole of its larger first cluster
+    assert_eq!(reader.supplying_layer(0x1800).unwrap(), Some(1));
+    assert_eq!(reader.supplying_layer(0).unwrap(), Some(1));
+    assert_eq!(reader.supplying_layer(0x10000).unwrap(), None);
+    assert_eq!(reader.supplying_layer(300_000).unwrap(), Some(2));
+    assert_eq!(reader.supplying_layer(500_000).unwrap(), None);
+    assert_eq!(reader.supplying_layer(1_000_000).unwrap(), None);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn commit() {
+    let mut base_qcow2 = Cursor::new(Vec::new());
+    let options = CreateOptions {
+        cluster_bits: 12,
+        ..CreateOptions::new(1_000_000)
+    };
+    Qcow2::import_raw(
+        &mut Cursor::new(raw_disk()),
+        &mut base_qcow2,
+        &Default::default(),
+    )
+    .unwrap();
+
+    let mut modified_disk = raw_disk();
+    modified_disk[5000..9000].fill(0xaa);
+    modified_disk[999_000..].fill(0x55);
+
+    let dir = scratch_dir("commit");
+    let bases = [
+        ("base.qcow2", BackingFormat::Qcow2, base_qcow2.into_inner()),
+        ("base.raw", BackingFormat::Raw, raw_disk()),
+    ];
+    for (base_name, backing_format, base_bytes) in bases {
+        for action in [CommitAction::Keep, CommitAction::Empty, CommitAction::Drop] {
+            let base_path = dir.join(base_name);
+            fs::write(&base_path, &base_bytes).unwrap();
+
+            let overlay_path = dir.join("overlay.qcow2");
+            let mut overlay_file = fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&overlay_path)
+                .unwrap();
+            let overlay_options = CreateOptions {
+                backing_file: Some(base_path.to_str().unwrap().to_owned()),
+                backing_format: Some(backing_format.clone()),
+                ..options.clone()
+            };
+            let mut overlay = qcow::create(&mut overlay_file, &overlay_options).unwrap();
+            let mut writer = overlay.writer(&mut overlay_file).unwrap();
+            writer.seek(SeekFrom::Start(5000)).unwrap();
+            writer.write_all(&[0xaa; 4000]).unwrap();
+            writer.seek(SeekFrom::Start(999_000)).unwrap();
+            writer.write_all(&[0x55; 1000]).unwrap();
+            writer.flush().unwrap();
+            drop(writer);
+            drop(overlay_file);
+
+            // every cluster touched is committed in full, clipped to the end of the guest
+            let committed = qcow::commit(&overlay_path, action).unwrap();
+            assert_eq!(committed, 0x2000 + (1_000_000 - 0xf3000));
+
+            let mut base_image = Cursor::new(fs::read(&base_path).unwrap());
+            let base_guest = match backing_format {
+                BackingFormat::Qcow2 => read_guest(&mut base_image),
+                _ => base_image.into_inner(),
+            };
+            assert_eq!(base_guest, modified_disk);
+
+            if action == CommitAction::Drop {
+                assert!(!overlay_path.exists());
+                continue;
+            }
+
+            let mut overlay_image = Cursor::new(fs::read(&overlay_path).unwrap());
+            let overlay = qcow::load(&mut overlay_image).unwrap().unwrap_qcow2();
+            let stats = overlay.statistics(&mut overlay_image).unwrap();
+            match action {
+                CommitAction::Keep => assert_eq!(stats.allocated_clusters, 4),
+                _ => assert_eq!(stats.allocated_clusters, 0),
+            }
+            assert_eq!(read_guest(&mut overlay_image), modified_disk);
+        }
+    }
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+/// Create an overlay of `base.qcow2` with some data written over part of the first 4 KiB cluster
+fn rebase_overlay(base: &[u8]) -> Cursor<Vec<u8>> {
+    let mut image = Cursor::new(Vec::new());
+    let options = CreateOptions {
+        cluster_bits: 12,
+        backing_file: Some("base.qcow2".to_owned()),
+        backing_format: Some(BackingFormat::Qcow2),
+        ..CreateOptions::new(1_000_000)
+    };
+    let mut qcow = qcow::create(&mut image, &options).unwrap();
+    let mut writer = qcow
+        .writer(&mut image)
+        .unwrap()
+        .with_backing_resolver(named_resolver(vec![("base.qcow2", base.to_vec())]));
+    writer.seek(SeekFrom::Start(0x800)).unwrap();
+    writer.write_all(&[0xaa; 0x100]).unwrap();
+    writer.flush().unwrap();
+    drop(writer);
+
+    image.set_position(0);
+    image
+}
+
+#[test]
+fn rebase() {
+    let options = ImportOptions {
+        cluster_bits: 12,
+        ..Default::default()
+    };
+    let mut old_base = Cursor::new(Vec::new());
+    Qcow2::import_raw(&mut Cursor::new(raw_disk()), &mut old_base, &options).unwrap();
+
+    // the new base differs in one cluster of data and in a previously zeroed cluster
+    let mut new_disk = raw_disk();
+    new_disk[300_000..300_010].fill(0xbb);
+    new_disk[600_000..600_010].fill(0xcc);
+    let mut new_base = Cursor::new(Vec::new());
+    Qcow2::import_raw(&mut Cursor::new(new_disk), &mut new_base, &options).unwrap();
+
+    let resolver = || {
+        named_resolver(vec![
+            ("base.qcow2", old_base.get_ref().clone()),
+            ("new-base.qcow2", new_base.get_ref().clone()),
+        ])
+    };
+    let mut expected = raw_disk();
+    expected[0x800..0x900].fill(0xaa);
+
+    let mut image = rebase_overlay(old_base.get_ref());
+    let mut qcow = qcow::load(&mut image).unwrap().unwrap_qcow2();
+    let mut writer = qcow
+        .writer(&mut image)
+        .unwrap()
+        .with_backing_resolver(resolver());
+    let copied = writer.rebase(Some("new-base.qcow2"), None).unwrap();
+    assert_eq!(copied, 2 * 0x1000);
+    writer.flush().unwrap();
+    drop(writer);
+
+    image.set_position(0);
+    let qcow = qcow::load(&mut image).unwrap().unwrap_qcow2();
+    assert_eq!(qcow.header.backing_file.as_deref(), Some("new-base.qcow2"));
+    assert_eq!(
+        qcow.header.backing_file_format(),
+        Some(BackingFormat::Qcow2)
+    );
+    let mut reader = qcow.reader(&mut image).with_backing_resolver(resolver());
+    let mut guest = Vec::new();
+    reader.read_to_end(&mut guest).unwrap();
+    assert_eq!(guest, expected);
+
+    // an unsafe rebase only changes the backing file in the header, leaving the rest untouched
+    let mut image = rebase_overlay(old_base.get_ref());
+    let before = image.get_ref().clone();
+    let mut qcow = qcow::load(&mut image).unwrap().unwrap_qcow2();
+    let old_offsets = (
+        qcow.header.size,
+        qcow.header.l1_table_offset,
+        qcow.header.refcount_table_offset,
+    );
+    let mut writer = qcow.writer(&mut image).unwrap();
+    writer
+        .rebase_unsafe(Some("new-base.qcow2"), Some(BackingFormat::Raw))
+        .unwrap();
+    writer.flush().unwrap();
+    drop(writer);
+
+    assert_eq!(image.get_ref().len(), before.len());
+    assert_eq!(image.get_ref()[0x1000..], before[0x1000..]);
+    image.set_position(0);
+    let qcow = qcow::load(&mut image).unwrap().unwrap_qcow2();
+    assert_eq!(qcow.header.backing_file.as_deref(), Some("new-base.qcow2"));
+    assert_eq!(qcow.header.backing_file_format(), Some(BackingFormat::Raw));
+    let offsets = (
+        qcow.header.size,
+        qcow.header.l1_table_offset,
+        qcow.header.refcount_table_offset,
+    );
+    assert_eq!(offsets, old_offsets);
+}
+
+#[test]
+fn rebase_longer_backing_file() {
+    let mut base = Cursor::new(Vec::new());
+    let options = ImportOptions {
+        cluster_bits: 12,
+        ..Default::default()
+    };
+    Qcow2::import_raw(&mut Cursor::new(raw_disk()), &mut base, &options).unwrap();
+    let mut expected = raw_disk();
+    expected[0x800..0x900].fill(0xaa);
+
+    // the backing file name is stored after the header extensions, so the backing file format
+    // extension is rewritten in front of the longer name, or removed entirely
+    let long_name = format!("{}/base.qcow2", "longer-directory-name".repeat(10));
+    for (name, format) in [
+        (long_name.as_str(), Some(BackingFormat::Qcow2)),
+        ("b.qcow2", None),
+    ] {
+        let mut image = rebase_overlay(base.get_ref());
+        let mut qcow = qcow::load(&mut image).unwrap().unwrap_qcow2();
+        let mut writer = qcow.writer(&mut image).unwrap();
+        writer.rebase_unsafe(Some(name), format.clone()).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+
+        image.set_position(0);
+        let qcow = qcow::load(&mut image).unwrap().unwrap_qcow2();
+        assert_eq!(qcow.header.backing_file.as_deref(), Some(name));
+        assert_eq!(qcow.header.backing_file_format(), format);
+
+        let mut guest = Vec::new();
+        qcow.reader(&mut image)
+            .with_backing_resolver(memory_resolver(base.get_ref().clone()))
+            .read_to_end(&mut guest)
+            .unwrap();
+        assert_eq!(guest, expected);
+    }
+}
+
+#[test]
+fn import_and_convert() {
+    let disk = raw_disk();
+
+    for compression in [
+        None,
+        Some(CompressionType::Zlib),
+        Some(CompressionType::Zstd),
+    ] {
+        let options = ImportOptions {
+            cluster_bits: 12,
+            compression,
+            ..Default::default()
+        };
+
+        let mut image = Cursor::new(Vec::new());
+        Qcow2::import_raw(&mut Cursor::new(&disk), &mut image, &options).unwrap();
+        assert!(image.get_ref().len() < disk.len());
+        assert_eq!(read_guest(&mut image), disk);
+
+        image.set_position(0);
+        let qcow = qcow::load(&mut image).unwrap();
+        let mut raw = Cursor::new(Vec::new());
+        qcow.convert_to_raw(&mut image, &mut raw).unwrap();
+        assert_eq!(raw.into_inner(), disk);
+    }
+}
+
+#[test]
+fn reencode() {
+    let disk = raw_disk();
+    let options = ImportOptions {
+        cluster_bits: 9,
+        ..Default::default()
+    };
+    let mut image = Cursor::new(Vec::new());
+    let qcow = Qcow2::import_raw(&mut Cursor::new(&disk), &mut image, &options).unwrap();
+
+    for (cluster_bits, version, compression) in [
+        (16, 3, None),
+        (12, 2, Some(CompressionType::Zlib)),
+        (12, 3, Some(CompressionType::Zstd)),
+    ] {
+        let options = ReencodeOptions {
+            cluster_bits,
+            version,
+            compression,
+            ..ReencodeOptions::matching(&qcow)
+        };
+
+        let mut output = Cursor::new(Vec::new());
+        let reencoded = qcow.reencode(&mut image, &mut output, &options).unwrap();
+        assert_eq!(reencoded.header.cluster_bits, cluster_bits);
+        assert_eq!(reencoded.header.version, version);
+        assert_eq!(read_guest(&mut output), disk);
+    }
+}
+
+/// Append `data` to `bytes` as a new cluster, returning its offset
+fn append_cluster(bytes: &mut Vec<u8>, data: &[u8]) -> u64 {
+    let offset = (bytes.len() as u64).div_ceil(0x1000) * 0x1000;
+    bytes.resize(offset as usize, 0);
+    bytes.extend_from_slice(data);
+    bytes.resize(bytes.len().div_ceil(0x1000) * 0x1000, 0);
+
+    offset
+}
+
+/// Create a 1 MB image with 4 KiB clusters and an internal snapshot named "boot", with 0xaa
+/// in the first cluster of the snapshot, 0x1800 bytes of 0x55 as VM state, and an extra data
+/// field unknown to the crate. The active image has 0xbb in its first cluster.
+fn snapshot_image() -> Cursor<Vec<u8>> {
+    let mut image = Cursor::new(Vec::new());
+    let options = CreateOptions {
+        cluster_bits: 12,
+        ..CreateOptions::new(4 << 20)
+    };
+    let mut qcow = qcow::create(&mut image, &options).unwrap();
+
+    // the VM state is stored past the end of the guest, at the start of the next L1 entry
+    let mut writer = qcow.writer(&mut image).unwrap();
+    writer.write_all(&[0xaa; 0x1000]).unwrap();
+    writer.seek(SeekFrom::Start(2 << 20)).unwrap();
+    writer.write_all(&[0x55; 0x1800]).unwrap();
+    writer.flush().unwrap();
+    drop(writer);
+
+    // the snapshot takes over the current tables, and the active image gets a new L2 table
+    let l1_table_offset = qcow.header.l1_table_offset as usize;
+    let l2_offsets: Vec<u64> = qcow.l1_table.iter().map(|entry| entry.l2_offset).collect();
+    let mut bytes = image.into_inner();
+    let snapshot_l1: Vec<u8> = l2_offsets.iter().flat_map(|o| o.to_be_bytes()).collect();
+    let snapshot_l1_offset = append_cluster(&mut bytes, &snapshot_l1);
+
+    let data_offset = append_cluster(&mut bytes, &[0xbb; 0x1000]);
+    let mut l2_table = bytes[l2_offsets[0] as usize..][..0x1000].to_vec();
+    l2_table[..8].copy_from_slice(&(data_offset | 1 << 63).to_be_bytes());
+    let l2_offset = append_cluster(&mut bytes, &l2_table);
+    bytes[l1_table_offset..][..8].copy_from_slice(&(l2_offset | 1 << 63).to_be_bytes());
+    bytes[l1_table_offset + 8..][..8].fill(0);
+
+    let mut entry = Vec::new();
+    entry.extend_from_slice(&snapshot_l1_offset.to_be_bytes());
+    entry.extend_from_slice(&2u32.to_be_bytes());
+    entry.extend_from_slice(&1u16.to_be_bytes());
+    entry.extend_from_slice(&4u16.to_be_bytes());
+    entry.extend_from_slice(&[0; 16]);
+    entry.extend_from_slice(&0x1800u32.to_be_bytes());
+    entry.extend_from_slice(&32u32.to_be_bytes());
+    entry.extend_from_slice(&0x1800u64.to_be_bytes());
+    entry.extend_from_slice(&(1u64 << 20).to_be_bytes());
+    entry.extend_from_slice(&1234i64.to_be_bytes());
+    entry.extend_from_slice(&0x1122_3344_5566_7788u64.to_be_bytes());
+    entry.extend_from_slice(b"1boot");
+    let snapshots_offset = append_cluster(&mut bytes, &entry);
+
+    bytes[24..32].copy_from_slice(&(1u64 << 20).to_be_bytes());
+    bytes[60..64].copy_from_slice(&1u32.to_be_bytes());
+    bytes[64..72].copy_from_slice(&snapshots_offset.to_be_bytes());
+
+    Cursor::new(bytes)
+}
+
+/// Read the cluster of the guest at `offset` as it was when `snapshot` was taken
+fn read_snapshot_cluster(
+    image: &mut Cursor<Vec<u8>>,
+    qcow: &Qcow2,
+    snapshot: &Snapshot,
+    offset: u64,
+) -> Vec<u8> {
+    let cluster_bits = qcow.header.cluster_bits;
+    let l2_bits = cluster_bits - 3;
+    let l1_entry = &snapshot.l1_table[(offset >> (cluster_bits + l2_bits)) as usize];
+    let l2_index = (offset >> cluster_bits) % (1 << l2_bits);
+    let entry = l1_entry
+        .read_l2_entry(image, l2_index, cluster_bits)
+        .unwrap();
+
+    let mut cluster = vec![0; qcow.cluster_size() as usize];
+    entry
+        .read_contents(image, &mut cluster, qcow.header.compression_type())
+        .unwrap();
+
+    cluster
+}
+
+#[test]
+fn reencode_snapshots() {
+    let mut image = snapshot_image();
+    let qcow = qcow::load(&mut image).unwrap().unwrap_qcow2();
+    assert_eq!(qcow.snapshots.len(), 1);
+
+    for (cluster_bits, version, compression) in
+        [(16, 3, None), (12, 2, Some(CompressionType::Zlib))]
+    {
+        let options = ReencodeOptions {
+            cluster_bits,
+            version,
+            compression,
+            ..ReencodeOptions::matching(&qcow)
+        };
+
+        let mut output = Cursor::new(Vec::new());
+        let reencoded = qcow.reencode(&mut image, &mut output, &options).unwrap();
+        let mut guest = read_guest(&mut output);
+        assert!(guest.drain(..0x1000).all(|byte| byte == 0xbb));
+        assert!(guest.iter().all(|&byte| byte == 0));
+
+        let snapshot = &reencoded.snapshots[0];
+        assert_eq!(reencoded.snapshots.len(), 1);
+        assert_eq!(snapshot.name, "boot");
+        assert_eq!(snapshot.unique_id, "1");
+        assert_eq!(snapshot.vm_state_len(), 0x1800);
+        assert_eq!(snapshot.extra_data.virtual_disk_size, Some(1 << 20));
+        assert_eq!(snapshot.extra_data.instruction_count, Some(1234));
+
+        // the extra data field unknown to the crate is copied as-is
+        let bytes = output.get_ref();
+        let snapshots_offset = u64::from_be_bytes(bytes[64..72].try_into().unwrap());
+        let extra_data = &bytes[snapshots_offset as usize + 40..][..32];
+        assert_eq!(extra_data[24..], 0x1122_3344_5566_7788u64.to_be_bytes());
+
+        let first = read_snapshot_cluster(&mut output, &reencoded, snapshot, 0);
+        assert!(first[..0x1000].iter().all(|&byte| byte == 0xaa));
+
+        // the VM state moves to the start of the first L1 entry past the end of the guest
+        let l1_entry_span = 1u64 << (2 * cluster_bits - 3);
+        let vm_state_offset = (1u64 << 20).div_ceil(l1_entry_span) * l1_entry_span;
+        let mut vm_state = Vec::new();
+        for cluster in 0..0x2000u64.div_ceil(reencoded.cluster_size()) {
+            let offset = vm_state_offset + cluster * reencoded.cluster_size();
+            vm_state.extend(read_snapshot_cluster(
+                &mut output,
+                &reencoded,
+                snapshot,
+                offset,
+            ));
+        }
+        assert!(vm_state[..0x1800].iter().all(|&byte| byte == 0x55));
+        assert!(vm_state[0x1800..].iter().all(|&byte| byte == 0));
+    }
+}
+
+#[test]
+fn compact() {
+    let disk = raw_disk();
+    let options = ImportOptions {
+        cluster_bits: 12,
+        ..Default::default()
+    };
+    let mut image = Cursor::new(Vec::new());
+    let mut qcow = Qcow2::import_raw(&mut Cursor::new(&disk), &mut image, &options).unwrap();
+
+    // rewriting clusters in compressed form leaves holes where they used to be
+    let mut writer = qcow.writer(&mut image).unwrap();
+    writer.set_compression(Some(CompressionType::Zlib)).unwrap();
+    writer.seek(SeekFrom::Start(300_000)).unwrap();
+    writer.write_all(&disk[300_000..400_000]).unwrap();
+    writer.flush().unwrap();
+    drop(writer);
+
+    let dir = scratch_dir("compact");
+    let path = dir.join("image.qcow2");
+    fs::write(&path, image.get_ref()).unwrap();
+
+    let mut compacted = Cursor::new(Vec::new());
+    let compaction = qcow.compact(&mut image, &mut compacted).unwrap();
+    assert!(compaction.reclaimed() > 0);
+    assert_eq!(compacted.get_ref().len() as u64, compaction.new_len);
+    assert_eq!(read_guest(&mut compacted), disk);
+
+    // compacting on the filesystem replaces the image, leaving nothing else behind
+    assert_eq!(qcow::compact(&path).unwrap(), compaction);
+    assert_eq!(fs::read(&path).unwrap(), *compacted.get_ref());
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn resize() {
+    let mut image = Cursor::new(Vec::new());
+    let options = CreateOptions {
+        cluster_bits: 12,
+        ..CreateOptions::new(1 << 20)
+    };
+    let mut qcow = qcow::create(&mut image, &options).unwrap();
+
+    let mut writer = qcow.writer(&mut image).unwrap();
+    writer.seek(SeekFrom::Start(500_000)).unwrap();
+    writer.write_all(&[0xaa; 10_000]).unwrap();
+
+    assert!(matches!(
+        writer.resize(400_000, ResizeMode::Safe),
+        Err(qcow::Error::ShrinkDiscardsData(_))
+    ));
+    writer.resize(505_000, ResizeMode::Force).unwrap();
+    writer.resize(64 << 20, ResizeMode::Safe).unwrap();
+    writer.flush().unwrap();
+    drop(writer);
+
+    let guest = read_guest(&mut image);
+    assert_eq!(guest.len(), 64 << 20);
+    assert!(guest[..500_000].iter().all(|&byte| byte == 0));
+    assert!(guest[500_000..505_000].iter().all(|&byte| byte == 0xaa));
+    assert!(guest[505_000..].iter().all(|&byte| byte == 0));
+}
+
+#[test]
+fn resize_over_backing_file() {
+    // the backing file extends past the old end of the overlay, which must not show through
+    let base = raw_disk();
+    for version in [2, 3] {
+        let mut image = Cursor::new(Vec::new());
+        let options = CreateOptions {
+            cluster_bits: 12,
+            version,
+            backing_file: Some("base.raw".to_owned()),
+            backing_format: Some(BackingFormat::Raw),
+            ..CreateOptions::new(250_000)
+        };
+        let mut qcow = qcow::create(&mut image, &options).unwrap();
+
+        let mut writer = qcow
+            .writer(&mut image)
+            .unwrap()
+            .with_backing_resolver(memory_resolver(base.clone()));
+        writer.resize(2_000_000, ResizeMode::Safe).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+
+        let mut reader = qcow
+            .reader(&mut image)
+            .with_backing_resolver(memory_resolver(base.clone()));
+        let mut guest = Vec::new();
+        reader.read_to_end(&mut guest).unwrap();
+        assert_eq!(guest.len(), 2_000_000);
+        assert_eq!(guest[..250_000], base[..250_000]);
+        assert!(guest[250_000..].iter().all(|&byte| byte == 0));
+
+        // version 3 images hide the backing file with zero clusters rather than data, past the
+        // cluster containing the old end which holds data from the backing file
+        let expected_kind = if version == 3 {
+            ExtentKind::Zero
+        } else {
+            ExtentKind::Data
+        };
+        let hidden: Vec<_> = reader
+            .allocation_map()
+            .map(Result::unwrap)
+            .filter(|extent| extent.end() > 0x3e000 && extent.start < 1_000_000)
+            .collect();
+        assert!(hidden.iter().all(|extent| extent.layer == 0));
+        assert!(hidden
+            .iter()
+            .filter(|extent| extent.start >= 0x3e000)
+            .all(|extent| extent.kind == expected_kind));
+    }
+}
+
+#[test]
+fn amend() {
+    let raw = raw_disk();
+    let mut image = Cursor::new(Vec::new());
+    let options = ImportOptions {
+        cluster_bits: 12,
+        version: 2,
+        ..Default::default()
+    };
+    let mut qcow = Qcow2::import_raw(&mut Cursor::new(&raw), &mut image, &options).unwrap();
+
+    let mut writer = qcow.writer(&mut image).unwrap();
+    writer
+        .amend(&AmendOptions {
+            version: Some(3),
+            lazy_refcounts: Some(true),
+            refcount_order: Some(1),
+        })
+        .unwrap();
+    drop(writer);
+    assert_eq!(qcow.header.version, 3);
+    assert_eq!(qcow.header.refcount_order(), 1);
+    assert_eq!(read_guest(&mut image), raw);
+
+    let downgrade = AmendOptions {
+        version: Some(2),
+        ..AmendOptions::default()
+    };
+    let mut writer = qcow.writer(&mut image).unwrap();
+    assert!(writer.amend(&downgrade).is_err());
+    writer
+        .amend(&AmendOptions {
+            refcount_order: Some(4),
+            ..downgrade
+        })
+        .unwrap();
+    drop(writer);
+    assert_eq!(qcow.header.version, 2);
+    assert!(qcow.header.v3_header.is_none());
+    assert_eq!(read_guest(&mut image), raw);
+}
+
+#[test]
+fn discard_and_write_zeroes() {
+    for version in [2, 3] {
+        let mut image = Cursor::new(Vec::new());
+        let options = CreateOptions {
+            cluster_bits: 12,
+            version,
+            ..CreateOptions::new(1 << 20)
+        };
+        let mut qcow = qcow::create(&mut image, &options).unwrap();
+
+        let mut writer = qcow.writer(&mut image).unwrap();
+        writer.write_all(&[0xaa; 1 << 20]).unwrap();
+        writer.discard(0x800, 0x2000).unwrap();
+        writer.write_zeroes(0x10800, 0x2000).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+
+        let guest = read_guest(&mut image);
+        assert!(guest[..0x1000].iter().all(|&byte| byte == 0xaa));
+        assert!(guest[0x1000..0x2000].iter().all(|&byte| byte == 0));
+        assert!(guest[0x2000..0x10800].iter().all(|&byte| byte == 0xaa));
+        assert!(guest[0x10800..0x12800].iter().all(|&byte| byte == 0));
+        assert!(guest[0x12800..].iter().all(|&byte| byte == 0xaa));
+    }
+}
+
+#[test]
+fn allocation_map() {
+    let mut image = Cursor::new(Vec::new());
+    let options = CreateOptions {
+        cluster_bits: 12,
+        ..CreateOptions::new(1 << 20)
+    };
+    let mut qcow = qcow::create(&mut image, &options).unwrap();
+
+    let mut writer = qcow.writer(&mut image).unwrap();
+    writer.seek(SeekFrom::Start(0x3000)).unwrap();
+    writer.write_all(&[0xaa; 0x2000]).unwrap();
+    writer.seek(SeekFrom::Start(0x8000)).unwrap();
+    writer.write_all(&[0xbb; 0x1000]).unwrap();
+    writer.write_zeroes(0x8000, 0x1000).unwrap();
+    writer.flush().unwrap();
+    drop(writer);
+
+    let mut reader = qcow.reader(&mut image);
+    let extents: Vec<_> = reader
+        .allocation_map()
+        .map(|extent| {
+            let extent = extent.unwrap();
+            (extent.start, extent.len, extent.kind, extent.layer)
+        })
+        .collect();
+
+    assert_eq!(
+        extents,
+        [
+            (0, 0x3000, ExtentKind::Unallocated, 0),
+            (0x3000, 0x2000, ExtentKind::Data, 0),
+            (0x5000, 0x3000, ExtentKind::Unallocated, 0),
+            (0x8000, 0x1000, ExtentKind::Zero, 0),
+            (0x9000, 0xf7000, ExtentKind::Unallocated, 0),
+        ]
+    );
+
+    let data = reader.allocation_map().nth(1).unwrap().unwrap();
+    let host_offset = data.host_offset.unwrap() as usize;
+    assert!(image.get_ref()[host_offset..host_offset + 0x2000]
+        .iter()
+        .all(|&byte| byte == 0xaa));
+}
+
+#[test]
+fn seek_data_and_hole() {
+    let mut image = Cursor::new(Vec::new());
+    let mut qcow = qcow::create(&mut image, &CreateOptions::new(1 << 40)).unwrap();
+
+    let mut writer = qcow.writer(&mut image).unwrap();
+    writer.seek(SeekFrom::Start(0x80_0000_0000)).unwrap();
+    writer.write_all(&[0xaa; 0x20000]).unwrap();
+    writer.flush().unwrap();
+    drop(writer);
+
+    let mut reader = qcow.reader(&mut image);
+    assert_eq!(reader.seek_hole().unwrap(), Some(0));
+    assert_eq!(reader.seek_data().unwrap(), Some(0x80_0000_0000));
+    assert_eq!(reader.seek_data().unwrap(), Some(0x80_0000_0000));
+    assert_eq!(reader.seek_hole().unwrap(), Some(0x80_0002_0000));
+    assert_eq!(reader.seek_data().unwrap(), None);
+    assert_eq!(reader.guest_pos(), 0x80_0002_0000);
+
+    reader.seek(SeekFrom::Start(0x80_0001_0000)).unwrap();
+    assert_eq!(reader.seek_data().unwrap(), Some(0x80_0001_0000));
+    reader.seek(SeekFrom::End(-1)).unwrap();
+    assert_eq!(reader.seek_hole().unwrap(), Some((1 << 40) - 1));
+    reader.seek(SeekFrom::End(0)).unwrap();
+    assert_eq!(reader.seek_hole().unwrap(), None);
+}
+
+#[test]
+fn guest_to_host() {
+    let mut image = Cursor::new(Vec::new());
+    let options = CreateOptions {
+        cluster_bits: 12,
+        ..CreateOptions::new(1 << 24)
+    };
+    let mut qcow = qcow::create(&mut image, &options).unwrap();
+
+    let mut writer = qcow.writer(&mut image).unwrap();
+    writer.seek(SeekFrom::Start(0x201000)).unwrap();
+    writer.write_all(&[0xaa; 0x2000]).unwrap();
+    writer.write_zeroes(0x202000, 0x1000).unwrap();
+    writer.flush().unwrap();
+    drop(writer);
+
+    let data = qcow.guest_to_host(&mut image, 0x201234).unwrap();
+    assert_eq!((data.l1_index, data.l2_index), (1, 1));
+    let l2_entry_offset = data.l2_entry_offset.unwrap();
+    assert_eq!(l2_entry_offset, data.l1_entry.l2_offset + 8);
+    match data.location {
+        HostLocation::Data(host_offset) => assert_eq!(host_offset & 0xfff, 0x234),
+        location => panic!("unexpected location {:?}", location),
+    }
+
+    let zero = qcow.guest_to_host(&mut image, 0x202000).unwrap();
+    assert!(matches!(zero.location, HostLocation::Zero(_)));
+
+    let unallocated = qcow.guest_to_host(&mut image, 0x1000).unwrap();
+    assert_eq!(unallocated.location, HostLocation::Unallocated);
+    assert_eq!(unallocated.l2_entry_offset, None);
+
+    assert!(qcow.guest_to_host(&mut image, 1 << 24).is_err());
+}
+
+#[test]
+fn host_cluster_map() {
+    let mut image = Cursor::new(Vec::new());
+    let options = CreateOptions {
+        cluster_bits: 12,
+        ..CreateOptions::new(1 << 24)
+    };
+    let mut qcow = qcow::create(&mut image, &options).unwrap();
+
+    let mut writer = qcow.writer(&mut image).unwrap();
+    writer.seek(SeekFrom::Start(0x201000)).unwrap();
+    writer.write_all(&[0xaa; 0x2000]).unwrap();
+    writer.flush().unwrap();
+    drop(writer);
+
+    let map = qcow.host_cluster_map(&mut image).unwrap();
+    assert_eq!(map.owners(0), [ClusterOwner::Header]);
+    assert_eq!(
+        map.owners(qcow.header.l1_table_offset),
+        [ClusterOwner::L1Table]
+    );
+
+    let data = qcow.guest_to_host(&mut image, 0x202000).unwrap();
+    let l2_offset = data.l1_entry.l2_offset;
+    assert_eq!(
+        map.owners(l2_offset),
+        [ClusterOwner::L2Table {
+            snapshot: None,
+            l1_index: 1
+        }]
+    );
+    match data.location {
+        HostLocation::Data(host_offset) => assert_eq!(
+            map.owners(host_offset),
+            [ClusterOwner::GuestData {
+                snapshot: None,
+                guest_offset: 0x202000,
+                compressed: false
+            }]
+        ),
+        location => panic!("unexpected location {:?}", location),
+    }
+
+    // every cluster of the image is used by something
+    let file_len = image.get_ref().len() as u64;
+    assert!((0..file_len)
+        .step_by(0x1000)
+        .all(|offset| !map.owners(offset).is_empty()));
+}
+
+#[test]
+fn orphaned_clusters() {
+    let mut image = Cursor::new(Vec::new());
+    let options = CreateOptions {
+        cluster_bits: 12,
+        ..CreateOptions::new(1 << 24)
+    };
+    let mut qcow = qcow::create(&mut image, &options).unwrap();
+
+    let mut writer = qcow.writer(&mut image).unwrap();
+    writer.write_all(&[0xaa; 0x10000]).unwrap();
+    writer.discard(0, 0x8000).unwrap();
+    writer.flush().unwrap();
+    drop(writer);
+
+    // discarded clusters keep their old data until they are reused
+    let orphans = qcow.orphaned_clusters(&mut image).unwrap();
+    assert_eq!(orphans.len(), 8);
+    for orphan in &orphans {
+        assert!(!orphan.is_leaked());
+        assert!(!orphan.looks_like_table);
+        let start = orphan.host_offset as usize;
+        assert!(image.get_ref()[start..start + 0x1000]
+            .iter()
+            .all(|&byte| byte == 0xaa));
+    }
+}
+
+#[test]
+fn host_layout() {
+    let mut image = Cursor::new(Vec::new());
+    let options = CreateOptions {
+        cluster_bits: 12,
+        ..CreateOptions::new(1 << 24)
+    };
+    let mut qcow = qcow::create(&mut image, &options).unwrap();
+
+    let mut writer = qcow.writer(&mut image).unwrap();
+    writer.seek(SeekFrom::Start(0x201000)).unwrap();
+    writer.write_all(&[0xaa; 0x2000]).unwrap();
+    writer.flush().unwrap();
+    drop(writer);
+
+    let layout = qcow.host_layout(&mut image).unwrap();
+    assert_eq!(layout[0].kind, HostRegionKind::Header);
+    assert_eq!(layout[0].range.start, 0);
+
+    // regions are sorted and don't overlap
+    assert!(layout
+        .windows(2)
+        .all(|pair| pair[0].range.end <= pair[1].range.start));
+
+    let bytes_of = |kind| -> u64 {
+        layout
+            .iter()
+            .filter(|region| region.kind == kind)
+            .map(|region| region.range.end - region.range.start)
+            .sum()
+    };
+    assert_eq!(bytes_of(HostRegionKind::Data), 0x2000);
+    assert_eq!(bytes_of(HostRegionKind::L2Table), 0x1000);
+    assert_eq!(
+        bytes_of(HostRegionKind::L1Table),
+        qcow.l1_table.len() as u64 * 8
+    );
+
+    let data = qcow.guest_to_host(&mut image, 0x202000).unwrap();
+    match data.location {
+        HostLocation::Data(host_offset) => assert!(layout.iter().any(|region| {
+            region.kind == HostRegionKind::Data && region.range.contains(&host_offset)
+        })),
+        location => panic!("unexpected location {:?}", location),
+    }
+
+    // nothing past the end of the file
+    let file_len = image.get_ref().len() as u64;
+    assert!(layout.last().unwrap().range.end <= file_len);
+}
+
+/// Create an image with 64 KiB clusters and one data cluster in each of two L2 tables, then
+/// truncate it at the start of the second L2 table. Returns the image and the offsets of the
+/// two L2 tables.
+fn truncated_image() -> (Cursor<Vec<u8>>, Qcow2, [u64; 2]) {
+    let mut image = Cursor::new(Vec::new());
+    let mut qcow = qcow::create(&mut image, &CreateOptions::new(1 << 30)).unwrap();
+
+    let mut writer = qcow.writer(&mut image).unwrap();
+    writer.write_all(&[0xaa; 0x10000]).unwrap();
+    writer.seek(SeekFrom::Start(1 << 29)).unwrap();
+    writer.write_all(&[0xbb; 0x10000]).unwrap();
+    writer.flush().unwrap();
+    drop(writer);
+
+    let l2_offsets = [qcow.l1_table[0].l2_offset, qcow.l1_table[1].l2_offset];
+    assert!(l2_offsets[0] < l2_offsets[1]);
+    image.get_mut().truncate(l2_offsets[1] as usize);
+
+    (image, qcow, l2_offsets)
+}
+
+#[test]
+fn host_layout_truncated() {
+    let (mut image, qcow, l2_offsets) = truncated_image();
+    let layout = qcow.host_layout(&mut image).unwrap();
+
+    // both L2 tables are where the L1 table says, but only the data of the first is known
+    for l2_offset in l2_offsets {
+        assert!(layout.iter().any(|region| {
+            region.kind == HostRegionKind::L2Table && region.range.contains(&l2_offset)
+        }));
+    }
+    let data: Vec<_> = layout
+        .iter()
+        .filter(|region| region.kind == HostRegionKind::Data)
+        .collect();
+    assert_eq!(data.len(), 1);
+    assert_eq!(data[0].range.end - data[0].range.start, 0x10000);
+    assert!(data[0].range.end <= l2_offsets[1]);
+
+    assert!(layout
+        .iter()
+        .any(|region| region.kind == HostRegionKind::RefcountBlock));
+    assert!(layout.last().unwrap().range.end > image.get_ref().len() as u64);
+}
+
+#[test]
+fn host_cluster_map_truncated() {
+    let (mut image, qcow, l2_offsets) = truncated_image();
+    let map = qcow.host_cluster_map(&mut image).unwrap();
+
+    // both L2 tables own their clusters, but only the data of the first is known
+    for (l1_index, &l2_offset) in l2_offsets.iter().enumerate() {
+        assert_eq!(
+            map.owners(l2_offset),
+            [ClusterOwner::L2Table {
+                snapshot: None,
+                l1_index: l1_index as u64
+            }]
+        );
+    }
+    let guest_data: Vec<_> = map
+        .iter()
+        .flat_map(|(_, owners)| owners)
+        .filter(|owner| matches!(owner, ClusterOwner::GuestData { .. }))
+        .collect();
+    assert_eq!(
+        guest_data,
+        [&ClusterOwner::GuestData {
+            snapshot: None,
+            guest_offset: 0,
+            compressed: false
+        }]
+    );
+}
+
+#[test]
+fn orphaned_clusters_truncated() {
+    let (mut image, qcow, _) = truncated_image();
+    let orphans = qcow.orphaned_clusters(&mut image).unwrap();
+    assert!(orphans.is_empty());
+}
+
+#[test]
+fn recover_deleted_snapshot() {
+    let mut image = snapshot_image();
+    let qcow = qcow::load(&mut image).unwrap().unwrap_qcow2();
+    let l2_offsets: Vec<u64> = qcow.snapshots[0]
+        .l1_table
+        .iter()
+        .map(|entry| entry.l2_offset)
+        .collect();
+
+    // dropping the snapshot from the header orphans its L1 table, its L2 tables and its data
+    let mut bytes = image.into_inner();
+    bytes[60..72].fill(0);
+    let mut image = Cursor::new(bytes);
+    let qcow = qcow::load(&mut image).unwrap().unwrap_qcow2();
+    assert!(qcow.snapshots.is_empty());
+
+    let orphans = qcow.orphaned_clusters(&mut image).unwrap();
+    for &l2_offset in &l2_offsets {
+        let orphan = orphans
+            .iter()
+            .find(|orphan| orphan.host_offset == l2_offset)
+            .unwrap();
+        assert!(orphan.looks_like_table);
+        assert!(orphan.is_leaked());
+    }
+
+    let mut recovered = |guest_offset: u64| {
+        let orphan = orphans
+            .iter()
+            .find(|orphan| {
+                orphan
+                    .former_owners
+                    .iter()
+                    .any(|owner| owner.guest_offset == Some(guest_offset))
+            })
+            .unwrap();
+        assert_eq!(orphan.former_owners.len(), 1);
+
+        let owner = &orphan.former_owners[0];
+        let l1_index = guest_offset >> 21;
+        assert_eq!(owner.l1_index, Some(l1_index));
+        assert_eq!(owner.l2_table_offset, l2_offsets[l1_index as usize]);
+        assert_eq!(owner.l2_index, (guest_offset >> 12) % 512);
+
+        let mut cluster = vec![0; 0x1000];
+        qcow.read_former_contents(&mut image, owner, &mut cluster)
+            .unwrap();
+        cluster
+    };
+
+    // the first cluster of the snapshot and its VM state
+    assert_eq!(recovered(0), [0xaa; 0x1000]);
+    assert_eq!(recovered(2 << 20), [0x55; 0x1000]);
+    assert_eq!(recovered((2 << 20) + 0x1000)[..0x800], [0x55; 0x800]);
+}
+
+#[test]
+fn statistics() {
+    let mut image = Cursor::new(Vec::new());
+    let options = CreateOptions {
+        cluster_bits: 12,
+        ..CreateOptions::new(1 << 24)
+    };
+    let mut qcow = qcow::create(&mut image, &options).unwrap();
+
+    // written out of guest order, so the data is split into two fragments
+    let mut writer = qcow.writer(&mut image).unwrap();
+    writer.seek(SeekFrom::Start(0x4000)).unwrap();
+    writer.write_all(&[0xaa; 0x2000]).unwrap();
+    writer.seek(SeekFrom::Start(0)).unwrap();
+    writer.write_all(&[0xbb; 0x2000]).unwrap();
+    writer.write_zeroes(0x1000, 0x1000).unwrap();
+
+    writer.set_compression(Some(CompressionType::Zlib)).unwrap();
+    writer.seek(SeekFrom::Start(0x10000)).unwrap();
+    writer.write_all(&[0xcc; 0x4000]).unwrap();
+    writer.flush().unwrap();
+    drop(writer);
+
+    let stats = qcow.statistics(&mut image).unwrap();
+    assert_eq!(stats.guest_clusters, 0x1000);
+    assert_eq!(stats.allocated_clusters, 3);
+    assert_eq!(stats.zero_clusters, 1);
+    assert_eq!(stats.compressed_clusters, 4);
+    assert_eq!(stats.unallocated_clusters, 0x1000 - 8);
+    assert_eq!(stats.uncompressed_bytes, 0x4000);
+    assert!(stats.compression_ratio().unwrap() > 1.0);
+    assert_eq!(stats.l2_tables, 1);
+    assert_eq!(stats.fragments, 2);
+    assert!(stats.snapshot_unique_clusters.is_empty());
+}
+
+#[test]
+fn compare() {
+    let mut image = Cursor::new(Vec::new());
+    let options = CreateOptions {
+        cluster_bits: 12,
+        ..CreateOptions::new(1 << 24)
+    };
+    let mut qcow = qcow::create(&mut image, &options).unwrap();
+    let mut writer = qcow.writer(&mut image).unwrap();
+    writer.write_all(&[0xaa; 0x3000]).unwrap();
+    writer.flush().unwrap();
+    drop(writer);
+
+    // the same data in a differently laid out image, plus two changes and a larger guest
+    let mut other_image = Cursor::new(Vec::new());
+    let other_options = CreateOptions {
+        cluster_bits: 16,
+        ..CreateOptions::new(2 << 24)
+    };
+    let mut other = qcow::create(&mut other_image, &other_options).unwrap();
+    let mut writer = other.writer(&mut other_image).unwrap();
+    writer.write_all(&[0xaa; 0x3000]).unwrap();
+    writer.seek(SeekFrom::Start(0x1010)).unwrap();
+    writer.write_all(&[0xbb; 0x10]).unwrap();
+    writer.seek(SeekFrom::Start(0x800000)).unwrap();
+    writer.write_all(&[0xcc; 0x20]).unwrap();
+    writer.flush().unwrap();
+    drop(writer);
+
+    let qcow = DynamicQcow::Qcow2(qcow);
+    let other = DynamicQcow::Qcow2(other);
+    let mut compare = |options| {
+        qcow.compare(&mut image, &other, &mut other_image, &options)
+            .unwrap()
+            .differences
+    };
+
+    assert_eq!(
+        compare(CompareOptions::default()),
+        [0x1010..0x1020, 0x800000..0x800020, 1 << 24..2 << 24]
+    );
+    let first = compare(CompareOptions {
+        first_only: true,
+        ..CompareOptions::default()
+    });
+    assert_eq!(first.len(), 1);
+    assert_eq!(first[0], 0x1010..0x1020);
+    assert_eq!(
+        compare(CompareOptions {
+            zero_extend: true,
+            ..CompareOptions::default()
+        }),
+        [0x1010..0x1020, 0x800000..0x800020]
+    );
+}
+
+#[test]
+fn export_diff() {
+    let disk = raw_disk();
+    let options = ImportOptions {
+        cluster_bits: 12,
+        ..Default::default()
+    };
+    let mut base_image = Cursor::new(Vec::new());
+    Qcow2::import_raw(&mut Cursor::new(&disk), &mut base_image, &options).unwrap();
+
+    // change a few bytes, zero part of the data and grow the disk
+    let mut modified_disk = disk.clone();
+    modified_disk[1] = b'a';
+    modified_disk[320_000..330_000].fill(0);
+    modified_disk.resize(1_100_000, 0);
+    modified_disk[1_050_000..1_050_010].copy_from_slice(b"9876543210");
+    let mut modified_image = Cursor::new(Vec::new());
+    Qcow2::import_raw(
+        &mut Cursor::new(&modified_disk),
+        &mut modified_image,
+        &options,
+    )
+    .unwrap();
+
+    base_image.set_position(0);
+    let base = qcow::load(&mut base_image).unwrap();
+    modified_image.set_position(0);
+    let modified = qcow::load(&mut modified_image).unwrap();
+
+    let mut overlay_image = Cursor::new(Vec::new());
+    let overlay = modified
+        .export_diff(
+            &mut modified_image,
+            &base,
+            &mut base_image,
+            "base.qcow2",
+            &mut overlay_image,
+            &DiffOptions {
+                cluster_bits: 12,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    assert_eq!(overlay.header.backing_file.as_deref(), Some("base.qcow2"));
+
+    // only the clusters containing changes are stored in the overlay
+    let stats = overlay.statistics(&mut overlay_image).unwrap();
+    assert_eq!(stats.allocated_clusters, 4);
+    assert_eq!(stats.zero_clusters, 1);
+
+    let base_bytes = base_image.into_inner();
+    let mut reader = overlay
+        .reader(&mut overlay_image)
+        .with_backing_resolver(move |_: &str| {
+            Ok(Box::new(Cursor::new(base_bytes.clone())) as Box<dyn ReadSeek>)
+        });
+    let mut guest = Vec::new();
+    reader.read_to_end(&mut guest).unwrap();
+    assert_eq!(guest, modified_disk);
+}
+
+#[test]
+fn export_diff_qcow1_base() {
+    // an empty 1 MB version 1 image with 4 KiB clusters and a single L1 entry
+    let mut base_bytes = b"QFI\xfb\0\0\0\x01".to_vec();
+    base_bytes.extend_from_slice(&[0; 16]);
+    base_bytes.extend_from_slice(&1_000_000u64.to_be_bytes());
+    base_bytes.extend_from_slice(&[12, 9, 0, 0, 0, 0, 0, 0]);
+    base_bytes.extend_from_slice(&56u64.to_be_bytes());
+    base_bytes.extend_from_slice(&[0; 8]);
+    let mut base_image = Cursor::new(base_bytes);
+    let base = qcow::load(&mut base_image).unwrap();
+    assert!(matches!(base, DynamicQcow::Qcow1(_)));
+
+    let mut image = Cursor::new(Vec::new());
+    let qcow = qcow::create(&mut image, &CreateOptions::new(1_000_000)).unwrap();
+
+    // overlays can't be read on top of version 1 images, so none is written
+    let mut overlay_image = Cursor::new(Vec::new());
+    let result = DynamicQcow::Qcow2(qcow).export_diff(
+        &mut image,
+        &base,
+        &mut base_image,
+        "base.qcow",
+        &mut overlay_image,
+        &DiffOptions::default(),
+    );
+    assert!(matches!(
+        result,
+        Err(qcow::Error::UnsupportedBackingFormat(BackingFormat::Qcow))
+    ));
+    assert!(overlay_image.get_ref().is_empty());
+}
+
+#[test]
+fn measure() {
+    for compression in [None, Some(CompressionType::Zlib)] {
+        let mut image = Cursor::new(Vec::new());
+        let options = ImportOptions {
+            cluster_bits: 12,
+            version: 3,
+            compression,
+        };
+        Qcow2::import_raw(&mut Cursor::new(raw_disk()), &mut image, &options).unwrap();
+
+        image.set_position(0);
+        let qcow = qcow::load(&mut image).unwrap();
+        let options = MeasureOptions {
+            cluster_bits: 12,
+            compression,
+            ..Default::default()
+        };
+        let measurement = qcow.measure(&mut image, &options).unwrap();
+        assert_eq!(measurement.required, image.get_ref().len() as u64);
+        assert!(measurement.fully_allocated > measurement.required);
+    }
+
+    let mut image = Cursor::new(Vec::new());
+    let options = CreateOptions {
+        cluster_bits: 9,
+        refcount_order: 6,
+        ..CreateOptions::new(64 << 20)
+    };
+    qcow::create(&mut image, &options).unwrap();
+
+    let options = MeasureOptions {
+        cluster_bits: 9,
+        refcount_order: 6,
+        ..Default::default()
+    };
+    let measurement = qcow::measure_size(64 << 20, &options).unwrap();
+    assert_eq!(measurement.required, image.get_ref().len() as u64);
+
+    let options = MeasureOptions {
+        preallocation: Preallocation::Metadata,
+        compression: Some(CompressionType::Zlib),
+        ..Default::default()
+    };
+    assert!(qcow::measure_size(64 << 20, &options).is_err());
+}
+
+#[test]
+fn preallocation() {
+    for preallocation in [Preallocation::Metadata, Preallocation::Full] {
+        for zero_preallocated in [false, true] {
+            let mut image = Cursor::new(Vec::new());
+            let options = CreateOptions {
+                cluster_bits: 12,
+                preallocation,
+                zero_preallocated,
+                ..CreateOptions::new(3 << 20)
+            };
+            let mut qcow = qcow::create(&mut image, &options).unwrap();
+
+            let options = MeasureOptions {
+                cluster_bits: 12,
+                preallocation,
+                ..Default::default()
+            };
+            let measurement = qcow::measure_size(3 << 20, &options).unwrap();
+            let len = image.get_ref().len() as u64;
+            assert_eq!(measurement.required, len);
+
+            let stats = qcow.statistics(&mut image).unwrap();
+            match zero_preallocated {
+                true => assert_eq!(stats.zero_clusters, stats.guest_clusters),
+                false => assert_eq!(stats.allocated_clusters, stats.guest_clusters),
+            }
+
+            // writes land in the preallocated clusters without growing the image
+            let mut writer = qcow.writer(&mut image).unwrap();
+            writer.seek(SeekFrom::Start(0x1ff800)).unwrap();
+            writer.write_all(&[0x55; 0x1000]).unwrap();
+            writer.flush().unwrap();
+            drop(writer);
+            assert_eq!(image.get_ref().len() as u64, len);
+
+            let mut expected = vec![0; 3 << 20];
+            expected[0x1ff800..0x200800].fill(0x55);
+            assert_eq!(read_guest(&mut image), expected);
+        }
+    }
+
+    let options = CreateOptions {
+        version: 2,
+        preallocation: Preallocation::Metadata,
+        zero_preallocated: true,
+        ..CreateOptions::new(3 << 20)
+    };
+    assert!(qcow::create(&mut Cursor::new(Vec::new()), &options).is_err());
+}